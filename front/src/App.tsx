@@ -8,6 +8,8 @@ import CalendarScreen from "./CalendarScreen";
 import CourseEdit from "./course/CourseEdit";
 import CourseListScreen from "./CourseListScreen";
 import LoginScreen from "./LoginScreen";
+import LoginTotpScreen from "./LoginTotpScreen";
+import ResetPasswordScreen from "./ResetPasswordScreen";
 import * as routes from "./routes";
 import Settings from "./Settings";
 import {useRootStore} from "./StoreProvider";
@@ -46,7 +48,8 @@ function SetupUnauthorizedHandler() {
 
     useEffect(() => {
         api.onDisconnectedHandler = () => {
-            if (history.location.pathname !== routes.LOGIN) {
+            const pathname = history.location.pathname;
+            if (!pathname.startsWith(routes.LOGIN) && pathname !== routes.RESET_PASSWORD) {
                 toasts.showToast('Votre session a expiré, veuillez vous reconnecter', 'info', undefined, 5000);
                 history.push(routes.LOGIN);
             }
@@ -68,6 +71,12 @@ export default function App() {
                 <Container maxWidth="sm">
                     <Main>
                         <Switch>
+                            <Route path={routes.RESET_PASSWORD}>
+                                <ResetPasswordScreen/>
+                            </Route>
+                            <Route path={routes.LOGIN_TOTP}>
+                                <LoginTotpScreen/>
+                            </Route>
                             <Route path={routes.LOGIN}>
                                 <LoginScreen/>
                             </Route>
@@ -60,6 +60,10 @@ export default function LoginScreen() {
                         history.push(routes.TIMELINE);
                         break;
                     }
+                    case 'totp_required': {
+                        history.push(routes.LOGIN_TOTP);
+                        break;
+                    }
                     case 'database': {
                         showToast('Erreur interne. Veuillez réessayer plus tard.')
                         break;
@@ -78,7 +82,7 @@ export default function LoginScreen() {
     }, []);
 
     const passwordForgotten = useCallback(() => {
-        alert("Envoie un message à dev@edgar.bzh et explique ton problème ;)")
+        history.push(routes.RESET_PASSWORD);
     }, []);
 
     const noAccount = useCallback(() => {
@@ -0,0 +1,78 @@
+import TextField from "@mui/material/TextField";
+import React, {FormEvent, useCallback, useRef, useState} from "react";
+import {useHistory} from "react-router-dom";
+import {WithBottomButton} from "./BottomButton";
+import {useRootStore} from "./StoreProvider";
+import * as routes from "./routes";
+import {styled} from "@mui/material/styles";
+
+const Form = styled('form')`
+    height: 100%;
+    display: flex;
+    flex-direction: column;
+    align-items: center;
+    justify-content: center;
+
+    & > * {
+        width: 80%;
+    }
+`;
+
+/**
+ * Second step of the login for accounts with 2FA, reached through a redirection once the password (or the OpenID
+ * Connect provider) has been checked.
+ */
+export default function LoginTotpScreen() {
+    const rootStore = useRootStore();
+    const history = useHistory();
+
+    const formRef = useRef<HTMLFormElement | null>(null);
+    const [loggingIn, setLoggingIn] = useState(false);
+
+    const doLogin = useCallback((e?: FormEvent) => {
+        e?.preventDefault();
+        const formData = formRef.current ? new FormData(formRef.current) : null;
+        if (formData) {
+            setLoggingIn(true);
+            rootStore.api.loginTotp(formData).then(result => {
+                setLoggingIn(false);
+                const showToast = (message) => rootStore.toasts.showToast(message, 'error', 'login');
+                switch (result) {
+                    case true: {
+                        rootStore.fetchAll();
+                        history.push(routes.TIMELINE);
+                        break;
+                    }
+                    case 'no_pending_login': {
+                        showToast('La connexion a expiré, veuillez recommencer');
+                        history.push(routes.LOGIN);
+                        break;
+                    }
+                    case 'invalid_code': {
+                        showToast('Code invalide')
+                        break;
+                    }
+                    case 'rate_limited': {
+                        showToast('Trop de tentatives, veuillez réessayer plus tard')
+                        break;
+                    }
+                    case 'database': {
+                        showToast('Erreur interne. Veuillez réessayer plus tard.')
+                        break;
+                    }
+                    case 'invalid_response': {
+                        showToast('Le serveur à renvoyé une réponse invalide. Essayez de recharger la page ?')
+                        break;
+                    }
+                }
+            });
+        }
+    }, []);
+
+    return (
+        <Form method="post" onSubmit={doLogin} ref={formRef}>
+            <TextField label={"Code de l'application ou code de secours"} disabled={loggingIn} variant="outlined" type="text" name="code" autoComplete="one-time-code" autoFocus placeholder="123456" />
+            <WithBottomButton instance="login-totp" disabled={loggingIn} label={"Valider"} onClick={doLogin}/>
+        </Form>
+    );
+}
@@ -0,0 +1,126 @@
+import TextField from "@mui/material/TextField";
+import Typography from "@mui/material/Typography";
+import React, {FormEvent, useCallback, useRef, useState} from "react";
+import {useHistory, useLocation} from "react-router-dom";
+import {WithBottomButton} from "./BottomButton";
+import {useRootStore} from "./StoreProvider";
+import * as routes from "./routes";
+import {styled} from "@mui/material/styles";
+
+const Form = styled('form')`
+    height: 100%;
+    display: flex;
+    flex-direction: column;
+    align-items: center;
+    justify-content: center;
+
+    & > * {
+        width: 80%;
+    }
+
+    & > *:not(:last-child) {
+        margin-bottom: 16px;
+    }
+`;
+
+/**
+ * Asks for a reset link, or sets the new password once the link from the email is opened, which holds the reset token
+ * in its query string.
+ */
+export default function ResetPasswordScreen() {
+    const rootStore = useRootStore();
+    const history = useHistory();
+    const token = new URLSearchParams(useLocation().search).get('token');
+
+    const formRef = useRef<HTMLFormElement | null>(null);
+    const [sending, setSending] = useState(false);
+    const [sent, setSent] = useState(false);
+
+    const submit = useCallback((e?: FormEvent) => {
+        e?.preventDefault();
+        const formData = formRef.current ? new FormData(formRef.current) : null;
+        if (!formData) return;
+
+        const showToast = (message) => rootStore.toasts.showToast(message, 'error', 'reset-password');
+        setSending(true);
+
+        if (token) {
+            const password = formData.get('password') as string;
+            if (password !== formData.get('confirmation')) {
+                setSending(false);
+                showToast('Les mots de passe ne correspondent pas');
+                return;
+            }
+
+            rootStore.api.resetPassword(token, password).then(result => {
+                setSending(false);
+                switch (result) {
+                    case true: {
+                        rootStore.toasts.showToast('Mot de passe modifié, vous pouvez vous connecter', 'success', 'reset-password');
+                        history.push(routes.LOGIN);
+                        break;
+                    }
+                    case 'weak_password': {
+                        showToast('Le mot de passe doit faire au moins 8 caractères')
+                        break;
+                    }
+                    case 'invalid_token': {
+                        showToast('Le lien a expiré ou a déjà été utilisé')
+                        break;
+                    }
+                    case 'database': {
+                        showToast('Erreur interne. Veuillez réessayer plus tard.')
+                        break;
+                    }
+                    case 'invalid_response': {
+                        showToast('Le serveur à renvoyé une réponse invalide. Essayez de recharger la page ?')
+                        break;
+                    }
+                }
+            });
+        } else {
+            rootStore.api.requestPasswordReset(formData.get('email') as string).then(result => {
+                setSending(false);
+                switch (result) {
+                    case true: {
+                        setSent(true);
+                        break;
+                    }
+                    case 'email_disabled': {
+                        showToast("L'envoi d'emails n'est pas configuré sur ce serveur")
+                        break;
+                    }
+                    case 'rate_limited': {
+                        showToast('Trop de demandes, veuillez réessayer plus tard')
+                        break;
+                    }
+                    case 'invalid_response': {
+                        showToast('Le serveur à renvoyé une réponse invalide. Essayez de recharger la page ?')
+                        break;
+                    }
+                }
+            });
+        }
+    }, [token]);
+
+    if (token) {
+        return (
+            <Form method="post" onSubmit={submit} ref={formRef}>
+                <TextField label={"Nouveau mot de passe"} disabled={sending} variant="outlined" type="password" name="password" autoComplete="new-password" />
+                <TextField label={"Confirmation"} disabled={sending} variant="outlined" type="password" name="confirmation" autoComplete="new-password" />
+                <WithBottomButton instance="reset-password" disabled={sending} label={"Changer le mot de passe"} onClick={submit}/>
+            </Form>
+        );
+    }
+
+    return (
+        <Form method="post" onSubmit={submit} ref={formRef}>
+            {sent ? (
+                <Typography variant="body1">Si un compte correspond à cette adresse, un lien pour choisir un nouveau mot de passe vient de lui être envoyé.</Typography>
+            ) : (
+                <TextField label={"Adresse e-mail"} disabled={sending} variant="outlined" type="text" name="email" placeholder="john@doe.net" />
+            )}
+            <WithBottomButton instance="reset-password" disabled={sending || sent} label={"Envoyer un lien"} onClick={submit}/>
+        </Form>
+    );
+}
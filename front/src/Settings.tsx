@@ -4,10 +4,9 @@ import Paper from "@mui/material/Paper";
 import Typography from "@mui/material/Typography";
 import ExitToApp from "@mui/icons-material/ExitToApp";
 import FileCopyIcon from "@mui/icons-material/FileCopy";
-import Skeleton from "@mui/material/Skeleton";
 import copy from "copy-to-clipboard";
 import {observer} from "mobx-react-lite";
-import React, {useCallback, useMemo} from "react";
+import React, {useCallback, useState} from "react";
 import {Link} from "react-router-dom";
 import ArchiveDialogs from "./ArchiveDialogs";
 import * as routes from "./routes";
@@ -66,9 +65,20 @@ export default observer(function Settings() {
     const store = useRootStore();
     const accountId = store.accountInfo.id;
 
-    const icalUrl = useMemo(() => (
-        accountId ? (`${location.protocol}//${location.host}` + "/ical/" + accountId) : undefined
-    ), [accountId]);
+    // The link holds a secret that can't be read back, so it is only known once created
+    const [icalUrl, setIcalUrl] = useState<string | null>(null);
+
+    const createIcalUrl = useCallback(() => {
+        store.api.createIcalLink().then(setIcalUrl);
+    }, [store]);
+
+    const [pushEnabled, setPushEnabled] = useState(false);
+
+    const enablePush = useCallback(() => {
+        store.api.subscribeToPush()
+            .then(setPushEnabled)
+            .catch(() => store.toasts.showToast("Impossible d'activer les notifications", 'error'));
+    }, [store]);
 
     const doCopy = useCallback(() => {
         copy(icalUrl!!);
@@ -79,19 +89,35 @@ export default observer(function Settings() {
         <Root>
             <SettingsSection>
                 <Typography variant="h6" component="h2">Importer dans un calendrier</Typography>
-                <CopyLinkContainer>
-                    <LinkButton disabled={!icalUrl} variant="outlined" onClick={doCopy}>
-                        {icalUrl ? (
-                            icalUrl
-                        ): (
-                            <Skeleton width="100%"/>
-                        )}
-                    </LinkButton>
-                    <IconButton disabled={!icalUrl} onClick={doCopy}>
-                        <FileCopyIcon/>
-                    </IconButton>
-                </CopyLinkContainer>
+                {icalUrl ? (
+                    <CopyLinkContainer>
+                        <LinkButton variant="outlined" onClick={doCopy}>
+                            {icalUrl}
+                        </LinkButton>
+                        <IconButton onClick={doCopy}>
+                            <FileCopyIcon/>
+                        </IconButton>
+                    </CopyLinkContainer>
+                ) : (
+                    <>
+                        <Typography variant="body1" color="textSecondary">Le lien est secret : en créer un nouveau désactive le précédent.</Typography>
+                        <ArchiveButtonContainer>
+                            <Button disabled={!accountId} variant="outlined" onClick={createIcalUrl}>Créer un lien</Button>
+                        </ArchiveButtonContainer>
+                    </>
+                )}
             </SettingsSection>
+            {'PushManager' in window && (
+                <SettingsSection>
+                    <Typography variant="h6" component="h2">Notifications</Typography>
+                    <Typography variant="body1" color="textSecondary">Un rappel des révisions du jour est envoyé sur cet appareil à l'heure du récapitulatif par email.</Typography>
+                    <ArchiveButtonContainer>
+                        <Button disabled={!accountId || pushEnabled} variant="outlined" onClick={enablePush}>
+                            {pushEnabled ? "Activées" : "Activer"}
+                        </Button>
+                    </ArchiveButtonContainer>
+                </SettingsSection>
+            )}
             <SettingsSection>
                 <Typography variant="h6" component="h2">Archive</Typography>
                 <Typography variant="body1" color="textSecondary">Votre archive rassemble les cours manuellement marqués comme archivés. Elle permet de mettre de côté des cours arrivés à leur échéance sans pour autant les supprimer définitivement.</Typography>
@@ -3,3 +3,7 @@ import ReactDOM from 'react-dom';
 import App from "./App";
 
 ReactDOM.render(<App />, document.querySelector('#app'))
+
+if ('serviceWorker' in navigator) {
+    navigator.serviceWorker.register(new URL('service-worker.ts', import.meta.url), { type: 'module' });
+}
@@ -1,5 +1,7 @@
 export const LOGIN = '/login';
+export const LOGIN_TOTP = '/login/totp';
 export const LOGOUT = '/logout';
+export const RESET_PASSWORD = '/reset-password';
 export const TIMELINE = '/';
 export const CALENDAR = '/calendar';
 export const TAB_TIMELINE = [TIMELINE, CALENDAR] as const;
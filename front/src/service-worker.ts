@@ -0,0 +1,35 @@
+/// <reference lib="webworker" />
+
+// Displays the revision reminders pushed by the server, see `Notification` in src/push.rs
+
+declare const self: ServiceWorkerGlobalScope;
+
+self.addEventListener('push', event => {
+    const notification = event.data?.json();
+    if (!notification) return;
+
+    event.waitUntil(self.registration.showNotification(notification.title, {
+        body: notification.body,
+        tag: notification.tag,
+        actions: notification.actions,
+        data: { markUrl: notification.mark_url },
+    }));
+});
+
+self.addEventListener('notificationclick', event => {
+    event.notification.close();
+
+    const markUrl: string | undefined = event.notification.data?.markUrl;
+
+    // Actions are markings, a click anywhere else opens the app
+    if (event.action && markUrl) {
+        event.waitUntil(fetch(markUrl, {
+            method: 'PUT',
+            credentials: 'include',
+            headers: { 'X-Requested-With': 'service-worker' },
+            body: event.action,
+        }));
+    } else {
+        event.waitUntil(self.clients.openWindow('/'));
+    }
+});
@@ -2,23 +2,34 @@ import {Color} from "@mui/material";
 import {makeAutoObservable, runInAction} from "mobx";
 import Day from "./Day";
 
+function base64UrlDecode(data: string): Uint8Array {
+    const base64 = data.replace(/-/g, '+').replace(/_/g, '/');
+    return Uint8Array.from(atob(base64), c => c.charCodeAt(0));
+}
+
 class Api {
     private readonly login_url: string;
+    private readonly login_totp_url: string;
     private readonly account: string;
     private readonly courses: string;
     private readonly courses_id: string;
     private readonly timeline: string;
     private readonly timeline_after: string;
+    private readonly push_key: string;
+    private readonly push_subscriptions: string;
 
     public onDisconnectedHandler: (() => void) | null = null;
 
     constructor(baseUrl: string) {
         this.login_url = baseUrl + 'login';
+        this.login_totp_url = baseUrl + 'login/totp';
         this.account = baseUrl + 'api/account';
         this.courses = baseUrl + 'api/courses';
         this.courses_id = baseUrl + 'api/courses/';
         this.timeline = baseUrl + 'api/timeline';
         this.timeline_after = baseUrl + 'api/timeline?after=';
+        this.push_key = baseUrl + 'api/push/key';
+        this.push_subscriptions = baseUrl + 'api/push/subscriptions';
     }
 
     private fetch(input: RequestInfo, init?: RequestInit | undefined): Promise<any | null> {
@@ -34,13 +45,14 @@ class Api {
         });
     }
 
-    async login(form: FormData): Promise<true | 'invalid_response' | 'database' | 'invalid_credentials'> {
+    /** Returns `'totp_required'` if the account has 2FA enabled, the login is then finished by {@link loginTotp} */
+    async login(form: FormData): Promise<true | 'totp_required' | 'invalid_response' | 'database' | 'invalid_credentials'> {
         return await fetch(this.login_url, {
             method: 'POST',
             body: form,
         }).then(async res => {
             if (res.redirected) {
-                return true;
+                return new URL(res.url).pathname === this.login_totp_url ? 'totp_required' : true;
             } else {
                 const errorKind = (await res.json())?.error_kind as 'database' | 'invalid_credentials';
                 if (errorKind) return errorKind;
@@ -49,10 +61,95 @@ class Api {
         });
     }
 
+    async loginTotp(form: FormData): Promise<true | 'invalid_response' | 'database' | 'invalid_code' | 'no_pending_login' | 'rate_limited'> {
+        return await fetch(this.login_totp_url, {
+            method: 'POST',
+            body: form,
+        }).then(async res => {
+            if (res.redirected) {
+                return true;
+            } else if (res.status === 429) {
+                return 'rate_limited';
+            } else {
+                const errorKind = (await res.json())?.error_kind as 'database' | 'invalid_code' | 'no_pending_login';
+                if (errorKind) return errorKind;
+                else return 'invalid_response';
+            }
+        });
+    }
+
+    /** Emails a reset link, the response is the same whether the address belongs to an account or not */
+    async requestPasswordReset(email: string): Promise<true | 'invalid_response' | 'email_disabled' | 'rate_limited'> {
+        return await fetch(this.account + '/password/reset', {
+            method: 'POST',
+            headers: {
+                'Content-Type': 'application/json',
+            },
+            body: JSON.stringify({email}),
+        }).then(async res => {
+            if (res.status === 200) {
+                return true;
+            } else if (res.status === 429) {
+                return 'rate_limited';
+            } else {
+                const errorKind = (await res.json())?.error_kind as 'email_disabled';
+                if (errorKind) return errorKind;
+                else return 'invalid_response';
+            }
+        });
+    }
+
+    async resetPassword(token: string, newPassword: string): Promise<true | 'invalid_response' | 'database' | 'invalid_token' | 'weak_password'> {
+        return await fetch(this.account + '/password/reset/confirm', {
+            method: 'POST',
+            headers: {
+                'Content-Type': 'application/json',
+            },
+            body: JSON.stringify({token, new_password: newPassword}),
+        }).then(async res => {
+            if (res.status === 200) {
+                return true;
+            } else {
+                const errorKind = (await res.json())?.error_kind as 'database' | 'invalid_token' | 'weak_password';
+                if (errorKind) return errorKind;
+                else return 'invalid_response';
+            }
+        });
+    }
+
     async fetchAccountInfo(): Promise<Record<string, any> | null> {
         return await this.fetch(this.account);
     }
 
+    /** Subscribes this browser to the revision reminders, asking for the permission if needed */
+    async subscribeToPush(): Promise<boolean> {
+        const key = await this.fetch(this.push_key);
+        if (!key) return false;
+
+        const registration = await navigator.serviceWorker.ready;
+        const subscription = await registration.pushManager.subscribe({
+            userVisibleOnly: true,
+            applicationServerKey: base64UrlDecode(key.public_key),
+        });
+
+        const res = await this.fetch(this.push_subscriptions, {
+            method: 'POST',
+            headers: {
+                'Content-Type': 'application/json',
+            },
+            body: JSON.stringify(subscription),
+        });
+        return res !== null;
+    }
+
+    /** Creates a new secret link to the iCal feed, revoking the previous one */
+    async createIcalLink(): Promise<string | null> {
+        const link = await this.fetch(this.account + '/ical', {
+            method: 'POST',
+        });
+        return link?.url ?? null;
+    }
+
     async fetchCourses(archived = false): Promise<Record<string, any>[] | null> {
         return await this.fetch(this.courses + '?archived=' + archived);
     }
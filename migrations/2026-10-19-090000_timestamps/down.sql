@@ -0,0 +1,35 @@
+drop view timeline;
+create view timeline as select * from (
+    select
+        events.course as course,
+        courses.owner as course_owner,
+        courses.name as course_name,
+        courses.description as course_description,
+        events.j,
+        lag(events.j) over (partition by events.course order by date, j) as previous_j,
+        events.marking,
+        lag(events.marking) over (partition by events.course order by date, j) as previous_marking,
+        events.date,
+        events.cache_key
+    from events
+    inner join courses on events.course = courses.id
+    where not archived
+    order by date, j
+) _;
+
+drop trigger set_updated_xid on events;
+drop trigger set_updated_xid on courses;
+drop function set_updated_xid;
+
+drop trigger set_updated_at on events;
+drop trigger set_updated_at on courses;
+
+alter table events
+    drop column created_at,
+    drop column updated_at,
+    drop column updated_xid;
+
+alter table courses
+    drop column created_at,
+    drop column updated_at,
+    drop column updated_xid;
@@ -0,0 +1,50 @@
+alter table courses
+    add column created_at timestamp not null default now(),
+    add column updated_at timestamp not null default now();
+
+alter table events
+    add column created_at timestamp not null default now(),
+    add column updated_at timestamp not null default now();
+
+select diesel_manage_updated_at('courses');
+select diesel_manage_updated_at('events');
+
+-- Id of the last transaction that wrote each row, which unlike timestamps can be compared with the
+-- snapshot of a sync transaction to find the changes it couldn't see yet
+alter table courses add column updated_xid bigint not null default txid_current();
+alter table events add column updated_xid bigint not null default txid_current();
+
+create function set_updated_xid() returns trigger language plpgsql as $$
+    begin
+        new.updated_xid := txid_current();
+        return new;
+    end;
+$$;
+
+create trigger set_updated_xid
+    before update on courses
+    for each row execute procedure set_updated_xid();
+
+create trigger set_updated_xid
+    before update on events
+    for each row execute procedure set_updated_xid();
+
+create or replace view timeline as select * from (
+    select
+        events.course as course,
+        courses.owner as course_owner,
+        courses.name as course_name,
+        courses.description as course_description,
+        events.j,
+        lag(events.j) over (partition by events.course order by date, j) as previous_j,
+        events.marking,
+        lag(events.marking) over (partition by events.course order by date, j) as previous_marking,
+        events.date,
+        events.cache_key,
+        events.created_at,
+        events.updated_at
+    from events
+    inner join courses on events.course = courses.id
+    where not archived
+    order by date, j
+) _;
@@ -0,0 +1,5 @@
+drop trigger events_tombstone_trigger on events;
+drop trigger courses_tombstone_trigger on courses;
+drop function record_tombstone;
+
+drop table tombstones;
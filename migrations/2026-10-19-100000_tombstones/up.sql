@@ -0,0 +1,35 @@
+-- No foreign key on `owner`: rows are written while their account may itself be getting deleted
+create table tombstones (
+    id bigserial not null,
+    owner uuid not null,
+    course uuid not null,
+    j bigint,
+
+    deleted_at timestamp not null default now(),
+    -- Transaction that deleted the row, see `courses.updated_xid`
+    deleted_xid bigint not null default txid_current(),
+
+    primary key (id)
+);
+
+create index on tombstones(owner, deleted_xid);
+create index on tombstones(deleted_at);
+
+create function record_tombstone() returns trigger language plpgsql as $$
+    begin
+        if tg_table_name = 'courses' then
+            insert into tombstones (owner, course, j) values (old.owner, old.id, null);
+        else
+            insert into tombstones (owner, course, j) values (old.owner, old.course, old.j);
+        end if;
+        return old;
+    end;
+$$;
+
+create trigger courses_tombstone_trigger
+    after delete on courses
+    for each row execute procedure record_tombstone();
+
+create trigger events_tombstone_trigger
+    after delete on events
+    for each row execute procedure record_tombstone();
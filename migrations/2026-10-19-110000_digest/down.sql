@@ -0,0 +1,7 @@
+drop table digest_tokens;
+
+alter table accounts
+    drop column digest_enabled,
+    drop column digest_time,
+    drop column digest_utc_offset,
+    drop column digest_last_sent;
@@ -0,0 +1,17 @@
+alter table accounts
+    add column digest_enabled boolean not null default false,
+    add column digest_time time not null default '07:00',
+    add column digest_utc_offset integer not null default 0,
+    add column digest_last_sent date;
+
+-- Tokens of the links of the digest emails, one per email
+create table digest_tokens (
+    -- SHA-256 of the token, in hex
+    token_hash varchar(64) not null,
+    account uuid not null references accounts (id) on delete cascade,
+    expires_at timestamp not null,
+
+    primary key (token_hash)
+);
+
+create index on digest_tokens(expires_at);
@@ -0,0 +1,4 @@
+alter table accounts drop column push_last_sent;
+
+drop table push_subscriptions;
+drop table server_secrets;
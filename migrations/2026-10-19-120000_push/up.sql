@@ -0,0 +1,20 @@
+create table server_secrets (
+    name varchar not null,
+    value varchar not null,
+
+    primary key (name)
+);
+
+create table push_subscriptions (
+    endpoint varchar not null,
+    account uuid not null references accounts (id) on delete cascade,
+
+    p256dh varchar not null,
+    auth varchar not null,
+
+    created_at timestamp not null default now(),
+
+    primary key (endpoint)
+);
+
+alter table accounts add column push_last_sent date;
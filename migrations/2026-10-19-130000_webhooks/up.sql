@@ -0,0 +1,47 @@
+create table webhooks (
+    id uuid not null default uuid_generate_v4(),
+    account uuid not null references accounts (id) on delete cascade,
+
+    url varchar not null,
+    secret varchar not null,
+    event_types varchar[] not null,
+
+    created_at timestamp not null default now(),
+
+    primary key (id)
+);
+
+-- Events waiting to be delivered, rows are removed once delivered or given up on
+create table webhook_outbox (
+    id bigserial not null,
+    webhook uuid not null references webhooks (id) on delete cascade,
+
+    event_type varchar not null,
+    payload varchar not null,
+
+    attempts int4 not null default 0,
+    next_attempt_at timestamp not null default now(),
+
+    primary key (id)
+);
+
+create index on webhook_outbox(next_attempt_at);
+
+create table webhook_deliveries (
+    id bigserial not null,
+    webhook uuid not null references webhooks (id) on delete cascade,
+    outbox_id bigint not null,
+
+    event_type varchar not null,
+    attempt int4 not null,
+    status_code int4,
+    -- Kind of error, such as `timeout` or `connection_failed`, never the transport error itself
+    -- which could reveal details about the network the server runs in
+    error varchar,
+
+    delivered_at timestamp not null default now(),
+
+    primary key (id)
+);
+
+create index on webhook_deliveries(webhook, delivered_at);
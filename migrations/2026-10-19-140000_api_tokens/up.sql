@@ -0,0 +1,15 @@
+create table api_tokens (
+    id uuid not null default uuid_generate_v4(),
+    account uuid not null references accounts (id) on delete cascade,
+
+    name varchar not null,
+    -- SHA-256 of the token, in hex
+    token_hash varchar(64) not null unique,
+    read_only boolean not null default false,
+
+    created_at timestamp not null default now(),
+    expires_at timestamp,
+    last_used_at timestamp,
+
+    primary key (id)
+);
@@ -0,0 +1,9 @@
+create table password_resets (
+    -- SHA-256 of the token, in hex
+    token_hash varchar(64) not null,
+    account uuid not null references accounts (id) on delete cascade,
+
+    expires_at timestamp not null default now() + interval '1 hour',
+
+    primary key (token_hash)
+);
@@ -0,0 +1,3 @@
+drop table email_verifications;
+
+alter table accounts drop column email_verified;
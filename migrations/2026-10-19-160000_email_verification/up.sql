@@ -0,0 +1,17 @@
+alter table accounts add column email_verified boolean not null default false;
+
+-- Accounts created so far were set up by hand
+update accounts set email_verified = true;
+
+create table email_verifications (
+    -- SHA-256 of the token, in hex
+    token_hash varchar(64) not null,
+    account uuid not null references accounts (id) on delete cascade,
+
+    -- Address being verified, which replaces the current one if it differs
+    email varchar(256) not null,
+
+    expires_at timestamp not null default now() + interval '1 day',
+
+    primary key (token_hash)
+);
@@ -0,0 +1,3 @@
+alter table events drop constraint events_owner_fkey;
+alter table events add constraint events_owner_fkey
+    foreign key (owner) references accounts(id);
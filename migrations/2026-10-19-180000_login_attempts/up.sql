@@ -0,0 +1,17 @@
+-- No foreign key: attempts are also logged for unknown emails
+create table login_attempts (
+    id bigserial not null,
+
+    email varchar(256) not null,
+    ip varchar,
+    -- `success`, `invalid_credentials` or `rate_limited`
+    outcome varchar not null,
+
+    created_at timestamp not null default now(),
+
+    primary key (id)
+);
+
+create index on login_attempts(email, ip, created_at);
+-- For pruning the log
+create index on login_attempts(created_at);
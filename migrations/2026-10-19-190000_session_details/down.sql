@@ -0,0 +1,5 @@
+alter table sessions drop column last_seen_at;
+alter table sessions drop column created_at;
+alter table sessions drop column ip;
+alter table sessions drop column user_agent;
+alter table sessions drop column id;
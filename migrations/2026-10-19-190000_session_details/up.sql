@@ -0,0 +1,7 @@
+-- The token is a secret, sessions are referred to by this id in the API
+alter table sessions add column id uuid unique not null default uuid_generate_v4();
+
+alter table sessions add column user_agent varchar;
+alter table sessions add column ip varchar;
+alter table sessions add column created_at timestamp not null default now();
+alter table sessions add column last_seen_at timestamp not null default now();
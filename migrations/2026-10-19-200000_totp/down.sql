@@ -0,0 +1,12 @@
+create or replace function expiration_gc() returns void as $$
+    begin
+        delete from sessions where (expires < now());
+    end;
+$$ language plpgsql;
+
+drop table pending_logins;
+drop table totp_recovery_codes;
+
+alter table accounts drop column totp_last_step;
+alter table accounts drop column totp_enabled;
+alter table accounts drop column totp_secret;
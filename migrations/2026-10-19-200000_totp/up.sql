@@ -0,0 +1,34 @@
+-- Base32 secret, set at enrolment but only checked at login once `totp_enabled` is set
+alter table accounts add column totp_secret varchar;
+alter table accounts add column totp_enabled boolean not null default false;
+-- Time step of the last accepted code, so that codes can't be replayed
+alter table accounts add column totp_last_step bigint;
+
+create table totp_recovery_codes (
+    account uuid not null references accounts (id) on delete cascade,
+    -- SHA-256 of the code, in hex
+    code_hash varchar(64) not null,
+
+    primary key (account, code_hash)
+);
+
+-- Logins waiting for their second factor
+create table pending_logins (
+    -- SHA-256 of the token, in hex
+    token_hash varchar(64) not null,
+    account uuid not null references accounts (id) on delete cascade,
+
+    user_agent varchar,
+    ip varchar,
+
+    expires_at timestamp not null default now() + interval '5 minutes',
+
+    primary key (token_hash)
+);
+
+create or replace function expiration_gc() returns void as $$
+    begin
+        delete from sessions where (expires < now());
+        delete from pending_logins where (expires_at < now());
+    end;
+$$ language plpgsql;
@@ -0,0 +1,10 @@
+-- Accounts linked to an OpenID Connect provider, `provider` being its key in the configuration
+create table oidc_identities (
+    provider varchar not null,
+    subject varchar not null,
+    account uuid not null references accounts (id) on delete cascade,
+
+    created_at timestamp not null default now(),
+
+    primary key (provider, subject)
+);
@@ -0,0 +1,15 @@
+create table passkeys (
+    id uuid not null default uuid_generate_v4(),
+    account uuid not null references accounts (id) on delete cascade,
+
+    -- URL-safe base64 of the WebAuthn credential ID
+    credential_id varchar not null unique,
+    name varchar not null,
+    -- Credential as serialized by webauthn-rs, including its public key and signature counter
+    passkey varchar not null,
+
+    created_at timestamp not null default now(),
+    last_used_at timestamp,
+
+    primary key (id)
+);
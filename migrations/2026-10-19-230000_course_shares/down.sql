@@ -0,0 +1,2 @@
+alter table courses drop column share;
+drop table course_shares;
@@ -0,0 +1,32 @@
+-- Invitations to a course, addressed by email so that they can be sent before the recipient signs
+-- up. `mode` is either `read_only` (the recipient's course follows the original) or `copy` (the
+-- recipient gets an independent copy once accepting).
+create table course_shares (
+    id uuid not null default uuid_generate_v4(),
+    course uuid not null references courses (id) on delete cascade,
+    -- Matched in lowercase, as email addresses are in practice case-insensitive
+    email varchar not null check (email = lower(email)),
+    mode varchar not null,
+
+    -- Account which accepted the invitation
+    account uuid references accounts (id) on delete cascade,
+
+    created_at timestamp not null default now(),
+    accepted_at timestamp,
+
+    primary key (id),
+    unique (course, email)
+);
+
+-- Read-only share a course was created from, revoking the share detaches the course
+alter table courses add column share uuid references course_shares (id) on delete set null;
+create index on courses (share);
+
+-- Account addresses are stored in lowercase as well, so that invitations can be matched exactly.
+-- Addresses differing only by their case are left as they are, merging their accounts being up to
+-- their owners.
+update accounts set email = lower(email)
+where email <> lower(email) and not exists (
+    select from accounts other where other.id <> accounts.id and lower(other.email) = lower(accounts.email)
+);
+update email_verifications set email = lower(email);
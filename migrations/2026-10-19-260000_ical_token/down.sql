@@ -0,0 +1,9 @@
+drop trigger bump_ical_sequence on events;
+drop trigger bump_ical_sequence on courses;
+drop function bump_ical_sequence();
+
+alter table events drop column sequence;
+alter table courses drop column sequence, drop column tags;
+drop sequence ical_sequence;
+
+alter table accounts drop column ical_token_hash;
@@ -0,0 +1,31 @@
+-- SHA-256 of the secret in the account's feed URL, the feed is disabled until a link is created
+alter table accounts add column ical_token_hash varchar(64) unique;
+
+-- Free-form labels of a course, which the feed can be restricted to
+alter table courses add column tags varchar[] not null default '{}';
+
+-- Source of the SEQUENCE numbers of the feed, shared by every row so that they keep increasing when
+-- the events of a course are re-created by a recurrence change
+create sequence ical_sequence as integer;
+
+alter table courses add column sequence integer not null default nextval('ical_sequence');
+alter table events add column sequence integer not null default nextval('ical_sequence');
+
+create function bump_ical_sequence() returns trigger language plpgsql as $$
+    begin
+        new.sequence := nextval('ical_sequence');
+        return new;
+    end;
+$$;
+
+create trigger bump_ical_sequence
+    before update of name, description on courses
+    for each row
+    when ((old.name, old.description) is distinct from (new.name, new.description))
+    execute procedure bump_ical_sequence();
+
+create trigger bump_ical_sequence
+    before update of date, marking on events
+    for each row
+    when ((old.date, old.marking) is distinct from (new.date, new.marking))
+    execute procedure bump_ical_sequence();
@@ -1,4 +1,5 @@
 use diesel::result::Error as DieselError;
+use rocket::http::Status;
 use rocket::response::Responder;
 use rocket::serde::json::Json;
 use rocket::serde::Serializer;
@@ -19,6 +20,8 @@ impl Default for Success {
 pub enum ApiResult<T = Success> {
     Ok(T),
     DatabaseError(DieselError),
+    /// Request-level failure, reported as `{"error_kind": ...}` like the login form does
+    Error(Status, &'static str),
 }
 
 impl ApiResult {
@@ -43,18 +46,38 @@ impl<T: Serialize> Serialize for ApiResult<T> {
             error: E,
         }
 
+        #[derive(serde::Serialize)]
+        struct ErrorKind {
+            error_kind: &'static str,
+        }
+
         match self {
             ApiResult::Ok(t) => t.serialize(serializer),
             ApiResult::DatabaseError(e) => Error {
                 error: e.to_string(),
             }
             .serialize(serializer),
+            ApiResult::Error(_, error_kind) => ErrorKind {
+                error_kind: *error_kind,
+            }
+            .serialize(serializer),
         }
     }
 }
 
 impl<'r, T: Serialize> Responder<'r, 'static> for ApiResult<T> {
     fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
-        Json(self).respond_to(request)
+        let status = match self {
+            ApiResult::DatabaseError(DieselError::NotFound) => Some(Status::NotFound),
+            ApiResult::Error(status, _) => Some(status),
+            _ => None,
+        };
+
+        let mut response = Json(self).respond_to(request)?;
+        if let Some(status) = status {
+            response.set_status(status);
+        }
+
+        Ok(response)
     }
 }
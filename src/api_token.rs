@@ -0,0 +1,166 @@
+use crate::api_result::ApiResult;
+use crate::{random_token, schema, Account, CookieAccount, DbConn};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Prefix of personal access tokens, to make them recognizable by secret scanners
+const TOKEN_PREFIX: &str = "mdj_";
+
+/// Hex SHA-256 of a token
+///
+/// A fast hash is enough here as tokens are long random strings, unlike passwords.
+pub fn hash_token(token: &str) -> String {
+    let hash = Sha256::digest(token.as_bytes());
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Finds the account a personal access token belongs to, along with its read-only flag
+///
+/// Expired tokens are ignored.
+pub fn authenticate(db: &mut PgConnection, token: &str) -> QueryResult<(Account, bool)> {
+    use schema::accounts::dsl as a_dsl;
+    use schema::api_tokens::dsl as t_dsl;
+
+    let now = Utc::now().naive_utc();
+
+    let (account, token_id, read_only) = a_dsl::accounts
+        .inner_join(t_dsl::api_tokens)
+        .filter(t_dsl::token_hash.eq(hash_token(token)))
+        .filter(t_dsl::expires_at.is_null().or(t_dsl::expires_at.gt(now)))
+        .select((
+            (
+                a_dsl::id,
+                a_dsl::email,
+                a_dsl::password,
+                a_dsl::email_verified,
+            ),
+            t_dsl::id,
+            t_dsl::read_only,
+        ))
+        .first::<(Account, Uuid, bool)>(db)?;
+
+    diesel::update(t_dsl::api_tokens.find(token_id))
+        .set(t_dsl::last_used_at.eq(now))
+        .execute(db)?;
+
+    Ok((account, read_only))
+}
+
+#[derive(Queryable, serde::Serialize)]
+pub struct ApiToken {
+    id: Uuid,
+    name: String,
+    read_only: bool,
+    created_at: NaiveDateTime,
+    expires_at: Option<NaiveDateTime>,
+    last_used_at: Option<NaiveDateTime>,
+}
+
+#[get("/api/account/tokens")]
+pub async fn tokens(db: DbConn, a: CookieAccount) -> ApiResult<Vec<ApiToken>> {
+    let CookieAccount(a) = a;
+
+    let tokens = with_db!(db => {
+        use schema::api_tokens::dsl;
+
+        dsl::api_tokens
+            .filter(dsl::account.eq(a.id))
+            .order_by(dsl::created_at.asc())
+            .select((
+                dsl::id,
+                dsl::name,
+                dsl::read_only,
+                dsl::created_at,
+                dsl::expires_at,
+                dsl::last_used_at,
+            ))
+            .load::<ApiToken>(db)
+    }?);
+
+    ApiResult::Ok(tokens)
+}
+
+#[derive(serde::Deserialize)]
+pub struct NewApiToken {
+    name: String,
+    #[serde(default)]
+    read_only: bool,
+    expires_at: Option<NaiveDateTime>,
+}
+
+/// The token itself is only returned when it is created
+#[derive(serde::Serialize)]
+pub struct CreatedApiToken {
+    #[serde(flatten)]
+    info: ApiToken,
+    token: String,
+}
+
+#[post("/api/account/tokens", data = "<json>")]
+pub async fn tokens_insert(
+    db: DbConn,
+    a: CookieAccount,
+    json: Json<NewApiToken>,
+) -> ApiResult<CreatedApiToken> {
+    let CookieAccount(a) = a;
+    let NewApiToken {
+        name,
+        read_only,
+        expires_at,
+    } = json.into_inner();
+
+    if name.trim().is_empty() {
+        return ApiResult::Error(Status::BadRequest, "invalid_name");
+    }
+
+    if expires_at.map_or(false, |e| e <= Utc::now().naive_utc()) {
+        return ApiResult::Error(Status::BadRequest, "invalid_expiry");
+    }
+
+    let token = format!("{}{}", TOKEN_PREFIX, random_token(40));
+    let token_hash = hash_token(&token);
+
+    let info = with_db!(db => {
+        use schema::api_tokens::dsl;
+
+        diesel::insert_into(dsl::api_tokens)
+            .values((
+                dsl::account.eq(a.id),
+                dsl::name.eq(name),
+                dsl::token_hash.eq(token_hash),
+                dsl::read_only.eq(read_only),
+                dsl::expires_at.eq(expires_at),
+            ))
+            .returning((
+                dsl::id,
+                dsl::name,
+                dsl::read_only,
+                dsl::created_at,
+                dsl::expires_at,
+                dsl::last_used_at,
+            ))
+            .get_result::<ApiToken>(db)
+    }?);
+
+    ApiResult::Ok(CreatedApiToken { info, token })
+}
+
+#[delete("/api/account/tokens/<id>")]
+pub async fn tokens_delete(db: DbConn, a: CookieAccount, id: Uuid) -> ApiResult {
+    let CookieAccount(a) = a;
+
+    with_db!(db => {
+        use schema::api_tokens::dsl;
+
+        diesel::delete(dsl::api_tokens)
+            .filter(dsl::account.eq(a.id).and(dsl::id.eq(id)))
+            .execute(db)
+    }?);
+
+    ApiResult::success()
+}
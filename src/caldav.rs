@@ -0,0 +1,422 @@
+use crate::api_token::{self, hash_token};
+use crate::ical::{feed_account, feed_events, todo, FeedEvent};
+use crate::marking::apply_marking;
+use crate::timetable::{split_property, start_date, unfold};
+use crate::webhook::{self, WebhookEvent};
+use crate::{schema, Config, CourseAndOccurrences, DbConn};
+use chrono::{Duration, NaiveDate};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use icalendar::Calendar;
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Request, State};
+use uuid::Uuid;
+
+/// Original method of a request, as set by the reverse proxy, along with its `Depth` header
+///
+/// Rocket rejects the WebDAV methods (`PROPFIND`, `REPORT`) before routing, so the proxy forwards
+/// them as `POST`s with an `X-Dav-Method` header, see the README.
+pub struct DavMethod {
+    method: String,
+    depth: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DavMethod {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+
+        match headers.get_one("X-Dav-Method") {
+            Some(method) => Outcome::Success(DavMethod {
+                method: method.to_ascii_uppercase(),
+                depth: headers.get_one("Depth").map(String::from),
+            }),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+/// Credentials and precondition of a `PUT`
+///
+/// Writes are authenticated with HTTP Basic credentials whose password is a personal API token
+/// that isn't read-only, the user name being ignored: the token of the calendar URL only grants
+/// reading, as it is also the one of the iCal feed, which is meant to be handed to other apps.
+pub struct DavWrite {
+    token: Option<String>,
+    if_match: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DavWrite {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+
+        let token = headers
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Basic "))
+            .and_then(|credentials| base64::decode(credentials.trim()).ok())
+            .and_then(|credentials| String::from_utf8(credentials).ok())
+            .and_then(|credentials| Some(credentials.split_once(':')?.1.to_string()));
+
+        Outcome::Success(DavWrite {
+            token,
+            if_match: headers.get_one("If-Match").map(String::from),
+        })
+    }
+}
+
+#[derive(Responder)]
+#[response(status = 207, content_type = "xml")]
+pub struct MultiStatus(String);
+
+#[derive(Responder)]
+pub struct DavOptions {
+    body: (),
+    dav: Header<'static>,
+    allow: Header<'static>,
+}
+
+#[derive(Responder)]
+pub enum DavError {
+    /// Asks the client for the credentials of [`DavWrite`]
+    #[response(status = 401)]
+    Unauthorized((), Header<'static>),
+    Status(Status),
+}
+
+impl From<Status> for DavError {
+    fn from(status: Status) -> Self {
+        DavError::Status(status)
+    }
+}
+
+#[derive(Responder)]
+#[response(content_type = "text/calendar")]
+pub struct CalendarObject {
+    body: String,
+    etag: Header<'static>,
+}
+
+/// Path of `public_url`, which prefixes every href
+fn base_path(public_url: &str) -> &str {
+    let host_start = public_url.find("://").map_or(0, |i| i + 3);
+    public_url[host_start..]
+        .find('/')
+        .map_or("/", |i| &public_url[host_start + i..])
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Name of the resource of a revision, `<course>-<j>.ics`
+fn object_name(event: &FeedEvent) -> String {
+    format!("{}-{}.ics", event.course, event.j)
+}
+
+fn parse_object_name(name: &str) -> Option<(Uuid, i64)> {
+    let (course, j) = name.strip_suffix(".ics")?.rsplit_once('-')?;
+    Some((course.parse().ok()?, j.parse().ok()?))
+}
+
+/// Changes whenever the VTODO does, as its `SEQUENCE` is bumped by every change to its content
+fn etag(event: &FeedEvent) -> String {
+    format!("\"{}\"", event.sequence())
+}
+
+fn calendar_data(event: &FeedEvent, public_url: &str) -> String {
+    let mut calendar = Calendar::new();
+    calendar.push(todo(event, public_url, None));
+    calendar.to_string()
+}
+
+fn response(href: &str, props: &str) -> String {
+    format!(
+        "<d:response><d:href>{}</d:href><d:propstat><d:prop>{}</d:prop>\
+         <d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>",
+        escape_xml(href),
+        props,
+    )
+}
+
+fn multistatus(responses: Vec<String>) -> MultiStatus {
+    MultiStatus(format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <d:multistatus xmlns:d=\"DAV:\" xmlns:c=\"urn:ietf:params:xml:ns:caldav\" \
+         xmlns:cs=\"http://calendarserver.org/ns/\">{}</d:multistatus>",
+        responses.concat(),
+    ))
+}
+
+/// Marking of a revision from the completion state of its VTODO, the reverse of
+/// [`crate::ical::todo_status_for_mark`]
+fn marking_for_todo(status: Option<&str>, percent_complete: Option<u8>) -> Option<&'static str> {
+    match (status, percent_complete.unwrap_or(0)) {
+        (Some("COMPLETED"), _) | (_, 100..=u8::MAX) => Some("green"),
+        (_, 75..=99) => Some("yellow"),
+        (_, 50..=74) => Some("orange"),
+        (Some("IN-PROCESS"), _) | (_, 1..=49) => Some("red"),
+        _ => None,
+    }
+}
+
+/// What a client can change in a VTODO
+struct TodoChanges {
+    status: Option<String>,
+    percent_complete: Option<u8>,
+    /// From `DTSTART`, or else from `DUE`, which is the day after the revision
+    date: Option<NaiveDate>,
+}
+
+/// [`TodoChanges`] of the first VTODO of an iCalendar object
+///
+/// Times are taken in UTC, the time zone of the user being unknown here.
+fn todo_changes(ics: &str) -> Option<TodoChanges> {
+    let mut in_todo = false;
+    let mut status = None;
+    let mut percent_complete = None;
+    let (mut start, mut due) = (None, None);
+
+    for line in unfold(ics) {
+        let (name, value) = match split_property(&line) {
+            Some(property) => property,
+            None => continue,
+        };
+
+        match name.to_ascii_uppercase().as_str() {
+            "BEGIN" if value.eq_ignore_ascii_case("VTODO") => in_todo = true,
+            "END" if in_todo && value.eq_ignore_ascii_case("VTODO") => {
+                return Some(TodoChanges {
+                    status,
+                    percent_complete,
+                    date: start.or_else(|| due.map(|due| due - Duration::days(1))),
+                });
+            }
+            "STATUS" if in_todo => status = Some(value.trim().to_ascii_uppercase()),
+            "PERCENT-COMPLETE" if in_todo => percent_complete = value.trim().parse().ok(),
+            "DTSTART" if in_todo => start = start_date(value.trim(), Duration::zero()),
+            "DUE" if in_todo => due = start_date(value.trim(), Duration::zero()),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Moves a revision of `owner` to `date`
+fn reschedule(
+    db: &mut PgConnection,
+    owner: Uuid,
+    course: Uuid,
+    j: i64,
+    date: NaiveDate,
+) -> QueryResult<()> {
+    use schema::events::dsl;
+
+    diesel::update(dsl::events)
+        .filter(
+            dsl::owner
+                .eq(owner)
+                .and(dsl::course.eq(course).and(dsl::j.eq(j))),
+        )
+        .set(dsl::date.eq(date))
+        .execute(db)?;
+
+    let course = CourseAndOccurrences::load(db, owner, course)?;
+    webhook::enqueue(db, owner, WebhookEvent::CourseUpdated, &course)
+}
+
+#[options("/caldav/<_..>")]
+pub fn caldav_options() -> DavOptions {
+    DavOptions {
+        body: (),
+        dav: Header::new("DAV", "1, calendar-access"),
+        allow: Header::new("Allow", "OPTIONS, GET, PUT, PROPFIND, REPORT"),
+    }
+}
+
+/// `PROPFIND` and `REPORT` on the calendar of the account whose [`crate::ical::ical_link`] holds
+/// `token`, which lists its revisions as VTODOs
+///
+/// This is a minimal CalDAV subset: every property a client needs is returned whatever was asked
+/// for, and `REPORT`s return every revision, only restricted to the requested hrefs for a
+/// `calendar-multiget`.
+#[post("/caldav/<token>", data = "<body>")]
+pub async fn caldav_collection(
+    db: DbConn,
+    config: &State<Config>,
+    token: String,
+    method: DavMethod,
+    body: String,
+) -> Result<MultiStatus, Status> {
+    let DavMethod { method, depth } = method;
+    if method != "PROPFIND" && method != "REPORT" {
+        return Err(Status::MethodNotAllowed);
+    }
+
+    let lookup_token = token.clone();
+    let events = with_db!(db => {
+        let account = match feed_account(db, &lookup_token)? {
+            Some(account) => account,
+            None => return Ok(None),
+        };
+
+        feed_events(db, account, None, None, None, None).map(Some)
+    })
+    .map_err(|_| Status::ServiceUnavailable)?
+    .ok_or(Status::NotFound)?;
+
+    let collection = format!("{}caldav/{}/", base_path(&config.public_url), token);
+    let mut responses = Vec::new();
+
+    if method == "PROPFIND" {
+        // Changes along with the list of revisions or any of them
+        let ctag = events.iter().map(|e| object_name(e) + &etag(e));
+        let ctag = hash_token(&ctag.collect::<Vec<_>>().concat());
+
+        responses.push(response(
+            &collection,
+            &format!(
+                "<d:resourcetype><d:collection/><c:calendar/></d:resourcetype>\
+                 <d:displayname>Méthode des J</d:displayname>\
+                 <c:supported-calendar-component-set><c:comp name=\"VTODO\"/>\
+                 </c:supported-calendar-component-set>\
+                 <cs:getctag>{}</cs:getctag>",
+                ctag,
+            ),
+        ));
+    }
+
+    // Depth 0 only asks for the collection itself
+    let depth_zero = method == "PROPFIND" && depth.as_deref() == Some("0");
+    let multiget = body.contains("calendar-multiget");
+
+    for event in events.iter().filter(|_| !depth_zero) {
+        let href = format!("{}{}", collection, object_name(event));
+        if multiget && !body.contains(&object_name(event)) {
+            continue;
+        }
+
+        let mut props = format!(
+            "<d:getetag>{}</d:getetag>\
+             <d:getcontenttype>text/calendar; charset=utf-8; component=VTODO</d:getcontenttype>",
+            escape_xml(&etag(event)),
+        );
+
+        if method == "REPORT" {
+            props.push_str(&format!(
+                "<c:calendar-data>{}</c:calendar-data>",
+                escape_xml(&calendar_data(event, &config.public_url)),
+            ));
+        }
+
+        responses.push(response(&href, &props));
+    }
+
+    Ok(multistatus(responses))
+}
+
+async fn load_event(
+    db: &DbConn,
+    token: String,
+    course: Uuid,
+    j: i64,
+) -> Result<(Uuid, FeedEvent), Status> {
+    with_db!(db => {
+        let account = match feed_account(db, &token)? {
+            Some(account) => account,
+            None => return Ok(None),
+        };
+
+        let events = feed_events(db, account, Some(course), None, None, None)?;
+        Ok(events.into_iter().find(|e| e.j == j).map(|e| (account, e)))
+    })
+    .map_err(|_: diesel::result::Error| Status::ServiceUnavailable)?
+    .ok_or(Status::NotFound)
+}
+
+/// A revision, as a VTODO
+#[get("/caldav/<token>/<name>")]
+pub async fn caldav_object(
+    db: DbConn,
+    config: &State<Config>,
+    token: String,
+    name: &str,
+) -> Result<CalendarObject, Status> {
+    let (course, j) = parse_object_name(name).ok_or(Status::NotFound)?;
+    let (_, event) = load_event(&db, token, course, j).await?;
+
+    Ok(CalendarObject {
+        body: calendar_data(&event, &config.public_url),
+        etag: Header::new("ETag", etag(&event)),
+    })
+}
+
+/// Sets the marking of a revision from the completion state of its VTODO, and moves it to the date
+/// of the VTODO
+///
+/// Other changes are ignored: the client gets the revision back as it is on its next sync.
+#[put("/caldav/<token>/<name>", data = "<body>")]
+pub async fn caldav_object_put(
+    db: DbConn,
+    token: String,
+    name: &str,
+    write: DavWrite,
+    body: String,
+) -> Result<Status, DavError> {
+    let (course, j) = parse_object_name(name).ok_or(Status::Forbidden)?;
+    let changes = todo_changes(&body).ok_or(Status::BadRequest)?;
+    let (account, event) = load_event(&db, token, course, j).await?;
+
+    let writer = match write.token {
+        Some(token) => with_db!(db => {
+            api_token::authenticate(db, &token).optional()
+        })
+        .map_err(|_| Status::ServiceUnavailable)?,
+        None => None,
+    };
+
+    match writer {
+        Some((writer, false)) if writer.id == account => {}
+        Some(_) => return Err(Status::Forbidden.into()),
+        None => {
+            let challenge = Header::new("WWW-Authenticate", "Basic realm=\"Méthode des J\"");
+            return Err(DavError::Unauthorized((), challenge));
+        }
+    }
+
+    if write
+        .if_match
+        .map_or(false, |if_match| if_match != etag(&event))
+    {
+        return Err(Status::PreconditionFailed.into());
+    }
+
+    let marking = marking_for_todo(changes.status.as_deref(), changes.percent_complete);
+    let marking = Some(marking.map(String::from)).filter(|m| *m != event.marking);
+    let date = changes.date.filter(|date| *date != event.date);
+
+    with_db!(db => {
+        db.transaction::<_, diesel::result::Error, _>(|db| {
+            if let Some(marking) = marking {
+                apply_marking(db, account, course, j, marking, None)?;
+            }
+
+            if let Some(date) = date {
+                reschedule(db, account, course, j, date)?;
+            }
+
+            Ok(())
+        })
+    })
+    .map_err(|_| Status::ServiceUnavailable)?;
+
+    Ok(Status::NoContent)
+}
@@ -0,0 +1,78 @@
+use crate::Config;
+use rocket::http::{Method, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+
+/// Origin (`scheme://host[:port]`) of a URL
+fn origin(url: &str) -> Option<&str> {
+    let scheme_end = url.find("://")? + 3;
+    let host_end = url[scheme_end..]
+        .find('/')
+        .map_or(url.len(), |i| scheme_end + i);
+    Some(&url[..host_end])
+}
+
+/// Whether the request comes from a page of this site, according to its `Origin` header or to
+/// its `Referer` one as a fallback
+///
+/// Requests with neither are only let through with an `X-Requested-With` header, which pages of
+/// other sites can't add without a CORS preflight. Browsers send `Origin` along every POST, PUT
+/// and DELETE, so this only concerns other clients using cookies, or privacy extensions stripping
+/// both headers.
+fn is_same_origin(request: &Request<'_>) -> bool {
+    let headers = request.headers();
+    let source = match headers
+        .get_one("Origin")
+        .or_else(|| headers.get_one("Referer"))
+    {
+        Some(source) => source,
+        None => return headers.contains("X-Requested-With"),
+    };
+
+    let source = match origin(source) {
+        Some(source) => source,
+        None => return false,
+    };
+
+    let public_url = request
+        .rocket()
+        .state::<Config>()
+        .map(|c| c.public_url.as_str());
+    if public_url.and_then(origin) == Some(source) {
+        return true;
+    }
+
+    // Also accept the host the request was sent to, for local and alternative deployments
+    match headers.get_one("Host") {
+        Some(host) => source == format!("http://{}", host) || source == format!("https://{}", host),
+        None => false,
+    }
+}
+
+/// Rejects cross-site state-changing requests
+///
+/// Rocket fairings can't fail requests, so this is a guard: [`crate::Account`] applies it to
+/// cookie-authenticated requests, and routes that don't need an account (such as `login`) take it
+/// directly.
+pub struct SameOrigin;
+
+#[derive(Debug)]
+pub struct CrossOrigin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SameOrigin {
+    type Error = CrossOrigin;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let safe = matches!(
+            request.method(),
+            Method::Get | Method::Head | Method::Options
+        );
+
+        if safe || is_same_origin(request) {
+            Outcome::Success(SameOrigin)
+        } else {
+            Outcome::Failure((Status::Forbidden, CrossOrigin))
+        }
+    }
+}
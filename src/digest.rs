@@ -0,0 +1,434 @@
+use crate::api_result::ApiResult;
+use crate::api_token::hash_token;
+use crate::email::SmtpConfig;
+use crate::marking::{apply_marking, MarkingResult};
+use crate::{random_token, schema, schema_ext, Account, Config, DbConn, EventAndCourse};
+use chrono::{Duration, NaiveDate, NaiveTime, Utc};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::response::content::RawHtml;
+use rocket::response::Redirect;
+use rocket::serde::json::Json;
+use rocket::State;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// How far back unmarked revisions are still listed as overdue
+const OVERDUE_DAYS: i64 = 30;
+
+/// How long the links of a digest email stay valid
+const LINK_DAYS: i64 = 14;
+
+/// How many times a day sending the digest of an account is attempted
+const MAX_SEND_FAILURES: u32 = 5;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DigestSettings {
+    enabled: bool,
+    /// Local time at which the digest is sent
+    time: NaiveTime,
+    /// Offset of the user's local time to UTC, in minutes
+    utc_offset: i32,
+}
+
+#[get("/api/account/digest")]
+pub async fn digest_settings(db: DbConn, a: Account) -> ApiResult<DigestSettings> {
+    let settings = with_db!(db => {
+        use schema::accounts::dsl;
+
+        dsl::accounts
+            .find(a.id)
+            .select((dsl::digest_enabled, dsl::digest_time, dsl::digest_utc_offset))
+            .first::<(bool, NaiveTime, i32)>(db)
+    }?);
+
+    let (enabled, time, utc_offset) = settings;
+
+    ApiResult::Ok(DigestSettings {
+        enabled,
+        time,
+        utc_offset,
+    })
+}
+
+#[put("/api/account/digest", data = "<json>")]
+pub async fn digest_settings_update(
+    db: DbConn,
+    a: Account,
+    json: Json<DigestSettings>,
+) -> ApiResult {
+    let settings = json.into_inner();
+
+    if settings.utc_offset.abs() > 18 * 60 {
+        return ApiResult::Error(Status::BadRequest, "invalid_utc_offset");
+    }
+
+    with_db!(db => {
+        use schema::accounts::dsl;
+
+        diesel::update(dsl::accounts.find(a.id))
+            .set((
+                dsl::digest_enabled.eq(settings.enabled),
+                dsl::digest_time.eq(settings.time),
+                dsl::digest_utc_offset.eq(settings.utc_offset),
+            ))
+            .execute(db)
+    }?);
+
+    ApiResult::success()
+}
+
+/// Account a link of a digest email belongs to, if its token is still valid
+fn token_account(db: &mut PgConnection, token: &str) -> QueryResult<Option<Uuid>> {
+    use schema::digest_tokens::dsl;
+
+    dsl::digest_tokens
+        .filter(dsl::token_hash.eq(hash_token(token)))
+        .filter(dsl::expires_at.gt(Utc::now().naive_utc()))
+        .select(dsl::account)
+        .first::<Uuid>(db)
+        .optional()
+}
+
+/// Page asking to confirm the action of a link, which is then `POST`ed to the same URL
+///
+/// Links of emails are opened by scanners and prefetchers, so they must not change anything
+/// themselves.
+fn confirmation_page(question: &str, button: &str) -> RawHtml<String> {
+    RawHtml(format!(
+        "<!DOCTYPE html>\n\
+         <html lang=\"fr\">\n\
+         <meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width\">\n\
+         <title>Méthode des J</title>\n\
+         <form method=\"post\">\n\
+         <p>{}</p>\n\
+         <button type=\"submit\">{}</button>\n\
+         </form>\n\
+         </html>\n",
+        question, button,
+    ))
+}
+
+#[get("/digest/unsubscribe/<_token>")]
+pub fn digest_unsubscribe_confirm(_token: &str) -> RawHtml<String> {
+    confirmation_page(
+        "Ne plus recevoir le récapitulatif quotidien par email ?",
+        "Se désabonner",
+    )
+}
+
+#[post("/digest/unsubscribe/<token>")]
+pub async fn digest_unsubscribe(db: DbConn, token: String) -> Result<&'static str, Status> {
+    let updated = with_db!(db => {
+        use schema::accounts::dsl;
+
+        match token_account(db, &token)? {
+            Some(account) => diesel::update(dsl::accounts.find(account))
+                .set(dsl::digest_enabled.eq(false))
+                .execute(db),
+            None => Ok(0),
+        }
+    })
+    .map_err(|_| Status::InternalServerError)?;
+
+    match updated {
+        0 => Err(Status::NotFound),
+        _ => Ok("Vous ne recevrez plus de récapitulatif par email."),
+    }
+}
+
+/// Markings the links of the emails can set, from the least to the most mastered
+const MARKINGS: [(&str, &str); 4] = [
+    ("red", "rouge"),
+    ("orange", "orange"),
+    ("yellow", "jaune"),
+    ("green", "vert"),
+];
+
+/// Quick-mark link of the digest emails
+#[get("/digest/mark/<_token>/<_course>/<_j>?<marking>")]
+pub fn digest_mark_confirm(
+    _token: &str,
+    _course: Uuid,
+    _j: u32,
+    marking: &str,
+) -> Result<RawHtml<String>, Status> {
+    let (_, color) = MARKINGS
+        .iter()
+        .find(|(m, _)| *m == marking)
+        .ok_or(Status::NotFound)?;
+
+    Ok(confirmation_page(
+        &format!("Marquer cette révision en {} ?", color),
+        "Marquer",
+    ))
+}
+
+#[post("/digest/mark/<token>/<course>/<j>?<marking>")]
+pub async fn digest_mark(
+    db: DbConn,
+    config: &State<Config>,
+    token: String,
+    course: Uuid,
+    j: u32,
+    marking: String,
+) -> Result<Redirect, Status> {
+    if !MARKINGS.iter().any(|(m, _)| *m == marking) {
+        return Err(Status::NotFound);
+    }
+
+    let result = with_db!(db => {
+        db.transaction::<_, diesel::result::Error, _>(|db| {
+            match token_account(db, &token)? {
+                Some(owner) => apply_marking(db, owner, course, j as i64, Some(marking), None),
+                None => Ok(MarkingResult::NotFound),
+            }
+        })
+    })
+    .map_err(|_| Status::InternalServerError)?;
+
+    match result {
+        MarkingResult::NotFound => Err(Status::NotFound),
+        _ => Ok(Redirect::to(config.public_url.clone())),
+    }
+}
+
+#[derive(Queryable)]
+struct DigestAccount {
+    id: Uuid,
+    email: String,
+    time: NaiveTime,
+    utc_offset: i32,
+    last_sent: Option<NaiveDate>,
+}
+
+/// Digest of an account, ready to be sent
+struct Digest {
+    account: Uuid,
+    email: String,
+    today: NaiveDate,
+    /// Token of the links of the email along with its body, `None` if there is nothing to revise,
+    /// in which case no email is sent
+    email_body: Option<(String, String)>,
+}
+
+fn digest_body(
+    public_url: &str,
+    token: &str,
+    events: &[EventAndCourse],
+    today: NaiveDate,
+) -> String {
+    let mut body = String::from("Bonjour,\n");
+
+    let sections = [
+        (
+            "Révisions du jour",
+            events
+                .iter()
+                .filter(|e| e.date == today)
+                .collect::<Vec<_>>(),
+        ),
+        (
+            "Révisions en retard",
+            events
+                .iter()
+                .filter(|e| e.date < today && e.marking.is_none())
+                .collect(),
+        ),
+    ];
+
+    for (title, events) in sections.iter().filter(|(_, events)| !events.is_empty()) {
+        body.push_str(&format!("\n{} :\n", title));
+
+        for event in events {
+            body.push_str(&format!(
+                "  - {} (J{}, {})\n    Marquer en vert : {}digest/mark/{}/{}/{}?marking=green\n",
+                event.course_name,
+                event.j,
+                event.date.format("%d/%m"),
+                public_url,
+                token,
+                event.course,
+                event.j,
+            ));
+        }
+    }
+
+    body.push_str(&format!(
+        "\nSe désabonner : {}digest/unsubscribe/{}\n",
+        public_url, token,
+    ));
+
+    body
+}
+
+/// Prepares the digest of every account whose local sending time has passed today
+///
+/// Each digest gets a token of its own for its links, which is only stored once the email is sent,
+/// see [`digest_sent`].
+fn due_digests(db: &mut PgConnection, public_url: &str) -> QueryResult<Vec<Digest>> {
+    use schema::accounts::dsl as a_dsl;
+    use schema::digest_tokens::dsl as d_dsl;
+    use schema_ext::timeline::dsl as t_dsl;
+
+    let now = Utc::now().naive_utc();
+
+    diesel::delete(d_dsl::digest_tokens.filter(d_dsl::expires_at.le(now))).execute(db)?;
+
+    let accounts = a_dsl::accounts
+        .filter(a_dsl::digest_enabled.eq(true))
+        .select((
+            a_dsl::id,
+            a_dsl::email,
+            a_dsl::digest_time,
+            a_dsl::digest_utc_offset,
+            a_dsl::digest_last_sent,
+        ))
+        .load::<DigestAccount>(db)?;
+
+    let mut digests = Vec::new();
+
+    for account in accounts {
+        let local = now + Duration::minutes(account.utc_offset as i64);
+        let today = local.date();
+
+        if local.time() < account.time || account.last_sent.map_or(false, |d| d >= today) {
+            continue;
+        }
+
+        let events = t_dsl::timeline
+            .filter(t_dsl::course_owner.eq(account.id))
+            .filter(t_dsl::date.between(today - Duration::days(OVERDUE_DAYS), today))
+            .load::<EventAndCourse>(db)?;
+
+        let has_revisions = events
+            .iter()
+            .any(|e| e.date == today || e.marking.is_none());
+
+        let email_body = has_revisions.then(|| {
+            let token = random_token(64);
+            let body = digest_body(public_url, &token, &events, today);
+            (token, body)
+        });
+
+        digests.push(Digest {
+            account: account.id,
+            email: account.email,
+            today,
+            email_body,
+        });
+    }
+
+    Ok(digests)
+}
+
+/// Records that the digest of `today` was sent, making the links of its email valid for
+/// [`LINK_DAYS`]
+fn digest_sent(
+    db: &mut PgConnection,
+    account: Uuid,
+    today: NaiveDate,
+    token: Option<String>,
+) -> QueryResult<()> {
+    use schema::accounts::dsl as a_dsl;
+    use schema::digest_tokens::dsl as d_dsl;
+
+    db.transaction::<_, diesel::result::Error, _>(|db| {
+        if let Some(token) = token {
+            diesel::insert_into(d_dsl::digest_tokens)
+                .values((
+                    d_dsl::token_hash.eq(hash_token(&token)),
+                    d_dsl::account.eq(account),
+                    d_dsl::expires_at.eq(Utc::now().naive_utc() + Duration::days(LINK_DAYS)),
+                ))
+                .execute(db)?;
+        }
+
+        diesel::update(a_dsl::accounts.find(account))
+            .set(a_dsl::digest_last_sent.eq(today))
+            .execute(db)?;
+
+        Ok(())
+    })
+}
+
+/// Sends the due digests, the SMTP server being contacted once the database connection is released
+///
+/// Digests that couldn't be sent are tried again on the next run, up to [`MAX_SEND_FAILURES`]
+/// times a day: `failures` counts the failed attempts of each account, and is reset with the day.
+pub async fn send_due_digests(
+    conn: &DbConn,
+    smtp: &SmtpConfig,
+    public_url: &str,
+    failures: &mut HashMap<Uuid, (NaiveDate, u32)>,
+) -> QueryResult<()> {
+    let public_url = public_url.to_string();
+    let digests = conn.run(move |db| due_digests(db, &public_url)).await?;
+
+    for digest in digests {
+        let (account, today) = (digest.account, digest.today);
+        let failed = failures
+            .get(&account)
+            .filter(|(day, _)| *day == today)
+            .map_or(0, |(_, failed)| *failed);
+
+        let token = match digest.email_body {
+            Some(_) if failed >= MAX_SEND_FAILURES => continue,
+            Some((token, body)) => {
+                let subject = "Méthode des J : vos révisions du jour";
+
+                if let Err(e) = smtp.send_async(digest.email.clone(), subject, body).await {
+                    eprintln!("couldn't send digest to {}: {}", digest.email, e);
+                    failures.insert(account, (today, failed + 1));
+                    continue;
+                }
+
+                Some(token)
+            }
+            None => None,
+        };
+
+        failures.remove(&account);
+        conn.run(move |db| digest_sent(db, account, today, token))
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Checks every minute for digests to send, if SMTP is configured
+pub fn scheduler() -> AdHoc {
+    AdHoc::on_liftoff("digest scheduler", |rocket| {
+        Box::pin(async move {
+            let config = rocket.state::<Config>();
+            let (smtp, public_url) = match config.and_then(|c| Some((c.smtp.clone()?, c))) {
+                Some((smtp, config)) => (smtp, config.public_url.clone()),
+                None => return,
+            };
+
+            let conn = DbConn::get_one(rocket)
+                .await
+                .expect("no database available for sending digests");
+
+            rocket::tokio::spawn(async move {
+                let mut interval =
+                    rocket::tokio::time::interval(std::time::Duration::from_secs(60));
+
+                let mut failures = HashMap::new();
+
+                loop {
+                    interval.tick().await;
+
+                    let sent = send_due_digests(&conn, &smtp, &public_url, &mut failures).await;
+
+                    if let Err(e) = sent {
+                        eprintln!("couldn't send digests: {}", e);
+                    }
+                }
+            });
+        })
+    })
+}
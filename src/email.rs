@@ -0,0 +1,365 @@
+use crate::api_result::ApiResult;
+use crate::api_token::hash_token;
+use crate::csrf::SameOrigin;
+use crate::password::hash_password;
+use crate::rate_limit::{LoginLimiter, RateLimited};
+use crate::session::ClientInfo;
+use crate::{random_token, schema, AnyAccount, Config, CookieAccount, DbConn};
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel::PgConnection;
+use lettre::message::header::ContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use rocket::http::Status;
+use rocket::response::Redirect;
+use rocket::serde::json::Json;
+use rocket::State;
+use std::error::Error;
+use uuid::Uuid;
+
+/// SMTP server every email is sent through, configured under `smtp`
+#[derive(Clone, serde::Deserialize)]
+pub struct SmtpConfig {
+    host: String,
+    port: Option<u16>,
+    username: Option<String>,
+    password: Option<String>,
+    /// Sender mailbox, e.g. `Méthode des J <mdj@example.com>`
+    from: String,
+    /// Set to `false` to talk plain SMTP to a local sink such as MailHog
+    #[serde(default = "SmtpConfig::default_tls")]
+    tls: bool,
+}
+
+impl SmtpConfig {
+    fn default_tls() -> bool {
+        true
+    }
+
+    pub fn transport(&self) -> Result<SmtpTransport, lettre::transport::smtp::Error> {
+        let mut builder = if self.tls {
+            SmtpTransport::relay(&self.host)?
+        } else {
+            SmtpTransport::builder_dangerous(&self.host)
+        };
+
+        if let Some(port) = self.port {
+            builder = builder.port(port);
+        }
+
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        Ok(builder.build())
+    }
+
+    /// Sends a plain text email
+    pub fn send(&self, to: &str, subject: &str, body: String) -> Result<(), Box<dyn Error>> {
+        let email = Message::builder()
+            .from(self.from.parse()?)
+            .to(to.parse()?)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)?;
+
+        self.transport()?.send(&email)?;
+        Ok(())
+    }
+
+    /// [`SmtpConfig::send`] from a blocking thread, so that talking to the server holds neither a
+    /// runtime worker nor a database connection
+    pub async fn send_async(
+        &self,
+        to: String,
+        subject: &'static str,
+        body: String,
+    ) -> Result<(), String> {
+        let smtp = self.clone();
+
+        rocket::tokio::task::spawn_blocking(move || {
+            smtp.send(&to, subject, body).map_err(|e| e.to_string())
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()))
+    }
+}
+
+/// Creates a verification token for `email`, which becomes the verified address of the account
+/// once the link of [`send_verification`] is opened
+pub fn create_verification(
+    db: &mut PgConnection,
+    account: Uuid,
+    email: &str,
+) -> QueryResult<String> {
+    use schema::email_verifications::dsl;
+
+    let token = random_token(64);
+
+    diesel::insert_into(dsl::email_verifications)
+        .values((
+            dsl::token_hash.eq(hash_token(&token)),
+            dsl::account.eq(account),
+            dsl::email.eq(email),
+        ))
+        .execute(db)?;
+
+    Ok(token)
+}
+
+/// Emails the confirmation link of a token from [`create_verification`]
+pub async fn send_verification(smtp: &SmtpConfig, public_url: &str, email: String, token: &str) {
+    let body = format!(
+        "Bonjour,\n\nPour confirmer votre adresse email, ouvrez ce lien : \
+         {}account/email/confirm/{}\n\nSi vous n'êtes pas à l'origine de cette demande, ignorez \
+         cet email.\n",
+        public_url, token,
+    );
+
+    let subject = "Méthode des J : confirmez votre adresse email";
+    if let Err(e) = smtp.send_async(email.clone(), subject, body).await {
+        eprintln!("couldn't send verification email to {}: {}", email, e);
+    }
+}
+
+/// Tells the owner of `email` that it was used to register or in an email change, in place of
+/// the verification email, so that responses don't tell which addresses have an account
+async fn send_taken_notice(smtp: &SmtpConfig, public_url: &str, email: String) {
+    let body = format!(
+        "Bonjour,\n\nQuelqu'un a essayé d'utiliser votre adresse email pour un autre compte \
+         sur {}. Votre compte n'a pas été modifié.\n\nSi c'était vous, connectez-vous à votre \
+         compte existant, ou choisissez un nouveau mot de passe si vous l'avez oublié.\n",
+        public_url,
+    );
+
+    let subject = "Méthode des J : votre adresse email est déjà utilisée";
+    if let Err(e) = smtp.send_async(email.clone(), subject, body).await {
+        eprintln!("couldn't send email notice to {}: {}", email, e);
+    }
+}
+
+/// Form in which addresses are stored and looked up, as they are in practice case-insensitive
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+fn valid_email(email: &str) -> bool {
+    email.len() <= 256 && email.contains('@')
+}
+
+#[derive(serde::Deserialize)]
+pub struct Registration {
+    email: String,
+    password: String,
+}
+
+/// Creates an account with a password, and emails it a verification link
+///
+/// The response is the same whether or not the address already has an account, whose owner is
+/// sent a notice instead. Requests are limited like logins.
+#[post("/api/account", data = "<json>")]
+pub async fn register(
+    db: DbConn,
+    config: &State<Config>,
+    limiter: &State<LoginLimiter>,
+    client: ClientInfo,
+    json: Json<Registration>,
+    _same_origin: SameOrigin,
+) -> Result<ApiResult, RateLimited> {
+    let Registration { email, password } = json.into_inner();
+    let email = normalize_email(&email);
+
+    if !valid_email(&email) {
+        return Ok(ApiResult::Error(Status::BadRequest, "invalid_email"));
+    }
+
+    let smtp = match &config.smtp {
+        Some(smtp) => smtp.clone(),
+        None => {
+            return Ok(ApiResult::Error(
+                Status::ServiceUnavailable,
+                "email_disabled",
+            ))
+        }
+    };
+
+    let mut keys = vec![format!("register:{}", email)];
+    keys.extend(client.ip.map(|ip| format!("register-ip:{}", ip)));
+
+    if let Err(retry_after) = limiter.take(&keys) {
+        return Err(RateLimited::new(retry_after));
+    }
+
+    let hash = match hash_password(&password) {
+        Ok(hash) => hash,
+        Err(err) => return Ok(err),
+    };
+
+    let account_email = email.clone();
+    let token = with_db!(db => {
+        use schema::accounts::dsl;
+
+        db.transaction::<_, DieselError, _>(|db| {
+            let account = diesel::insert_into(dsl::accounts)
+                .values((dsl::email.eq(&account_email), dsl::password.eq(hash)))
+                .on_conflict_do_nothing()
+                .returning(dsl::id)
+                .get_result::<Uuid>(db)
+                .optional()?;
+
+            account
+                .map(|account| create_verification(db, account, &account_email))
+                .transpose()
+        })
+    });
+
+    let token = match token {
+        Ok(token) => token,
+        Err(e) => return Ok(ApiResult::from(e)),
+    };
+
+    let public_url = config.public_url.clone();
+    rocket::tokio::spawn(async move {
+        match token {
+            Some(token) => send_verification(&smtp, &public_url, email, &token).await,
+            None => send_taken_notice(&smtp, &public_url, email).await,
+        }
+    });
+
+    Ok(ApiResult::success())
+}
+
+/// Sends a new verification link for the current address
+#[post("/api/account/email/verification")]
+pub async fn email_verification_send(
+    db: DbConn,
+    a: AnyAccount,
+    config: &State<Config>,
+) -> ApiResult {
+    let AnyAccount(a) = a;
+
+    if a.email_verified {
+        return ApiResult::Error(Status::BadRequest, "already_verified");
+    }
+
+    let smtp = match &config.smtp {
+        Some(smtp) => smtp,
+        None => return ApiResult::Error(Status::ServiceUnavailable, "email_disabled"),
+    };
+
+    let email = a.email.clone();
+    let token = with_db!(db => { create_verification(db, a.id, &email) }?);
+
+    send_verification(smtp, &config.public_url, a.email, &token).await;
+
+    ApiResult::success()
+}
+
+#[derive(serde::Deserialize)]
+pub struct EmailChange {
+    email: String,
+    /// Required if the account has a password
+    password: Option<String>,
+}
+
+/// Starts changing the email address of the account
+///
+/// The current address is kept until the link sent to the new one is opened. If the new address
+/// already belongs to an account, its owner is sent a notice instead and the response is the same.
+#[put("/api/account/email", data = "<json>")]
+pub async fn email_change(
+    db: DbConn,
+    a: CookieAccount,
+    config: &State<Config>,
+    json: Json<EmailChange>,
+) -> ApiResult {
+    let CookieAccount(a) = a;
+    let EmailChange { email, password } = json.into_inner();
+    let email = normalize_email(&email);
+
+    if let Some(hash) = &a.password {
+        if !bcrypt::verify(&password.unwrap_or_default(), hash).unwrap_or_default() {
+            return ApiResult::Error(Status::Unauthorized, "invalid_credentials");
+        }
+    }
+
+    if !valid_email(&email) {
+        return ApiResult::Error(Status::BadRequest, "invalid_email");
+    }
+
+    let smtp = match &config.smtp {
+        Some(smtp) => smtp.clone(),
+        None => return ApiResult::Error(Status::ServiceUnavailable, "email_disabled"),
+    };
+
+    let new_email = email.clone();
+    let token = with_db!(db => {
+        use schema::accounts::dsl;
+
+        let taken = dsl::accounts
+            .filter(dsl::email.eq(&new_email))
+            .select(dsl::id)
+            .first::<Uuid>(db)
+            .optional()?
+            .is_some();
+
+        match taken {
+            true => QueryResult::Ok(None),
+            false => create_verification(db, a.id, &new_email).map(Some),
+        }
+    }?);
+
+    let public_url = config.public_url.clone();
+    rocket::tokio::spawn(async move {
+        match token {
+            Some(token) => send_verification(&smtp, &public_url, email, &token).await,
+            None => send_taken_notice(&smtp, &public_url, email).await,
+        }
+    });
+
+    ApiResult::success()
+}
+
+/// Link of the verification emails, marks the address as verified and makes it the account's one
+#[get("/account/email/confirm/<token>")]
+pub async fn email_confirm(
+    db: DbConn,
+    config: &State<Config>,
+    token: String,
+) -> Result<Redirect, Status> {
+    let confirmed = with_db!(db => {
+        use schema::accounts::dsl as a_dsl;
+        use schema::email_verifications::dsl as v_dsl;
+
+        db.transaction::<_, DieselError, _>(|db| {
+            let verification = diesel::delete(v_dsl::email_verifications)
+                .filter(v_dsl::token_hash.eq(hash_token(&token)))
+                .filter(v_dsl::expires_at.gt(diesel::dsl::now))
+                .returning((v_dsl::account, v_dsl::email))
+                .get_result::<(Uuid, String)>(db)
+                .optional()?;
+
+            let (account, email) = match verification {
+                Some(verification) => verification,
+                None => return Ok(false),
+            };
+
+            diesel::update(a_dsl::accounts.find(account))
+                .set((a_dsl::email.eq(email), a_dsl::email_verified.eq(true)))
+                .execute(db)?;
+
+            Ok(true)
+        })
+    })
+    .map_err(|e| match e {
+        // The address was claimed by another account since the link was sent
+        DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => Status::Conflict,
+        _ => Status::InternalServerError,
+    })?;
+
+    match confirmed {
+        true => Ok(Redirect::to(config.public_url.clone())),
+        false => Err(Status::NotFound),
+    }
+}
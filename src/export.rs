@@ -0,0 +1,358 @@
+use crate::api_result::ApiResult;
+use crate::{schema, Account, Course, DbConn, RECURRENCE_PRESETS};
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use uuid::Uuid;
+
+/// Version of the [`Export`] document format, to be bumped on breaking changes
+pub const EXPORT_VERSION: u32 = 1;
+
+/// Full dump of an account, meant for backups and data portability
+///
+/// Secrets (password and TOTP hashes, token hashes, webhook secrets, push and passkey keys) are
+/// left out. Only courses are restored by [`crate::import`], the rest describes what the account
+/// is linked to and has to be set up again on another instance.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Export {
+    pub version: u32,
+    pub exported_at: NaiveDateTime,
+    pub email: String,
+    pub settings: ExportSettings,
+    pub courses: Vec<ExportCourse>,
+    /// Shares of the account's courses, see [`crate::share`]
+    #[serde(default)]
+    pub shares: Vec<ExportShare>,
+    #[serde(default)]
+    pub sessions: Vec<ExportSession>,
+    #[serde(default)]
+    pub api_tokens: Vec<ExportApiToken>,
+    #[serde(default)]
+    pub passkeys: Vec<ExportPasskey>,
+    #[serde(default)]
+    pub oidc_identities: Vec<ExportOidcIdentity>,
+    #[serde(default)]
+    pub push_subscriptions: Vec<ExportPushSubscription>,
+    #[serde(default)]
+    pub webhooks: Vec<ExportWebhook>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ExportSettings {
+    pub recurrences: Vec<Vec<u32>>,
+    #[serde(default)]
+    pub email_verified: bool,
+    #[serde(default)]
+    pub totp_enabled: bool,
+    #[serde(default)]
+    pub digest: Option<ExportDigest>,
+}
+
+#[derive(Queryable, serde::Serialize, serde::Deserialize)]
+pub struct ExportDigest {
+    pub enabled: bool,
+    pub time: NaiveTime,
+    pub utc_offset: i32,
+}
+
+#[derive(Queryable, serde::Serialize, serde::Deserialize)]
+pub struct ExportShare {
+    pub course: Uuid,
+    pub email: String,
+    pub mode: String,
+    pub created_at: NaiveDateTime,
+    pub accepted_at: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, serde::Serialize, serde::Deserialize)]
+pub struct ExportSession {
+    pub id: Uuid,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    pub expires: NaiveDateTime,
+}
+
+#[derive(Queryable, serde::Serialize, serde::Deserialize)]
+pub struct ExportApiToken {
+    pub id: Uuid,
+    pub name: String,
+    pub read_only: bool,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, serde::Serialize, serde::Deserialize)]
+pub struct ExportPasskey {
+    pub id: Uuid,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, serde::Serialize, serde::Deserialize)]
+pub struct ExportOidcIdentity {
+    pub provider: String,
+    pub subject: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// Push endpoints are capability URLs, only the push service they belong to is exported
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ExportPushSubscription {
+    /// Origin of the endpoint, e.g. `https://fcm.googleapis.com`
+    pub push_service: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// Webhook URLs often hold secrets in their path or query, only their origin is exported
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ExportWebhook {
+    pub id: Uuid,
+    pub url_origin: String,
+    pub event_types: Vec<String>,
+    pub created_at: NaiveDateTime,
+}
+
+/// Scheme and host of a URL, without any user info
+fn url_origin(url: &str) -> String {
+    let (scheme, rest) = url.split_once("://").unwrap_or(("", url));
+    let authority = rest
+        .split(|c| c == '/' || c == '?' || c == '#')
+        .next()
+        .unwrap_or_default();
+    let host = authority.rsplit('@').next().unwrap_or_default();
+
+    format!("{}://{}", scheme, host)
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ExportCourse {
+    pub id: Uuid,
+    pub name: String,
+    pub description: Option<String>,
+    pub j_0: NaiveDate,
+    pub j_end: NaiveDate,
+    pub recurrence: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub archived: bool,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub events: Vec<ExportEvent>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ExportEvent {
+    pub j: i64,
+    pub date: NaiveDate,
+    pub marking: Option<String>,
+}
+
+/// Everything of the account besides its courses, in the order of [`Export`]
+type ExportExtras = (
+    (ExportDigest, bool),
+    Vec<ExportShare>,
+    Vec<ExportSession>,
+    Vec<ExportApiToken>,
+    Vec<ExportPasskey>,
+    Vec<ExportOidcIdentity>,
+    Vec<ExportPushSubscription>,
+    Vec<ExportWebhook>,
+);
+
+fn load_extras(db: &mut PgConnection, owner: Uuid) -> QueryResult<ExportExtras> {
+    use schema::accounts::dsl as a_dsl;
+    use schema::api_tokens::dsl as t_dsl;
+    use schema::course_shares::dsl as cs_dsl;
+    use schema::courses::dsl as c_dsl;
+    use schema::oidc_identities::dsl as o_dsl;
+    use schema::passkeys::dsl as p_dsl;
+    use schema::push_subscriptions::dsl as ps_dsl;
+    use schema::sessions::dsl as s_dsl;
+    use schema::webhooks::dsl as w_dsl;
+
+    let account = a_dsl::accounts
+        .find(owner)
+        .select((
+            (
+                a_dsl::digest_enabled,
+                a_dsl::digest_time,
+                a_dsl::digest_utc_offset,
+            ),
+            a_dsl::totp_enabled,
+        ))
+        .first::<(ExportDigest, bool)>(db)?;
+
+    let shares = cs_dsl::course_shares
+        .inner_join(c_dsl::courses)
+        .filter(c_dsl::owner.eq(owner))
+        .order_by(cs_dsl::created_at.asc())
+        .select((
+            cs_dsl::course,
+            cs_dsl::email,
+            cs_dsl::mode,
+            cs_dsl::created_at,
+            cs_dsl::accepted_at,
+        ))
+        .load::<ExportShare>(db)?;
+
+    let sessions = s_dsl::sessions
+        .filter(s_dsl::account.eq(owner))
+        .filter(s_dsl::expires.gt(diesel::dsl::now))
+        .order_by(s_dsl::created_at.asc())
+        .select((
+            s_dsl::id,
+            s_dsl::user_agent,
+            s_dsl::ip,
+            s_dsl::created_at,
+            s_dsl::last_seen_at,
+            s_dsl::expires,
+        ))
+        .load::<ExportSession>(db)?;
+
+    let api_tokens = t_dsl::api_tokens
+        .filter(t_dsl::account.eq(owner))
+        .order_by(t_dsl::created_at.asc())
+        .select((
+            t_dsl::id,
+            t_dsl::name,
+            t_dsl::read_only,
+            t_dsl::created_at,
+            t_dsl::expires_at,
+            t_dsl::last_used_at,
+        ))
+        .load::<ExportApiToken>(db)?;
+
+    let passkeys = p_dsl::passkeys
+        .filter(p_dsl::account.eq(owner))
+        .order_by(p_dsl::created_at.asc())
+        .select((
+            p_dsl::id,
+            p_dsl::name,
+            p_dsl::created_at,
+            p_dsl::last_used_at,
+        ))
+        .load::<ExportPasskey>(db)?;
+
+    let oidc_identities = o_dsl::oidc_identities
+        .filter(o_dsl::account.eq(owner))
+        .order_by(o_dsl::created_at.asc())
+        .select((o_dsl::provider, o_dsl::subject, o_dsl::created_at))
+        .load::<ExportOidcIdentity>(db)?;
+
+    let push_subscriptions = ps_dsl::push_subscriptions
+        .filter(ps_dsl::account.eq(owner))
+        .order_by(ps_dsl::created_at.asc())
+        .select((ps_dsl::endpoint, ps_dsl::created_at))
+        .load::<(String, NaiveDateTime)>(db)?
+        .into_iter()
+        .map(|(endpoint, created_at)| ExportPushSubscription {
+            push_service: url_origin(&endpoint),
+            created_at,
+        })
+        .collect();
+
+    let webhooks = w_dsl::webhooks
+        .filter(w_dsl::account.eq(owner))
+        .order_by(w_dsl::created_at.asc())
+        .select((w_dsl::id, w_dsl::url, w_dsl::event_types, w_dsl::created_at))
+        .load::<(Uuid, String, Vec<String>, NaiveDateTime)>(db)?
+        .into_iter()
+        .map(|(id, url, event_types, created_at)| ExportWebhook {
+            id,
+            url_origin: url_origin(&url),
+            event_types,
+            created_at,
+        })
+        .collect();
+
+    Ok((
+        account,
+        shares,
+        sessions,
+        api_tokens,
+        passkeys,
+        oidc_identities,
+        push_subscriptions,
+        webhooks,
+    ))
+}
+
+#[get("/api/export")]
+pub async fn export(db: DbConn, a: Account) -> ApiResult<Export> {
+    let owner = a.id;
+
+    let (courses, extras) = with_db!(db => {
+        use schema::courses::dsl as c_dsl;
+        use schema::events::dsl as e_dsl;
+
+        db.transaction::<_, diesel::result::Error, _>(|db| {
+            let courses = c_dsl::courses
+                .order_by(c_dsl::j_0.asc())
+                .filter(c_dsl::owner.eq(owner))
+                .load::<Course>(db)?
+                .into_iter()
+                .map(|c| {
+                    let events = e_dsl::events
+                        .order_by((e_dsl::date.asc(), e_dsl::j.asc()))
+                        .filter(e_dsl::course.eq(c.id))
+                        .select((e_dsl::j, e_dsl::date, e_dsl::marking))
+                        .load::<(i64, NaiveDate, Option<String>)>(db)?
+                        .into_iter()
+                        .map(|(j, date, marking)| ExportEvent { j, date, marking })
+                        .collect();
+
+                    Ok(ExportCourse {
+                        id: c.id,
+                        name: c.name,
+                        description: c.description,
+                        j_0: c.j_0,
+                        j_end: c.j_end,
+                        recurrence: c.recurrence,
+                        tags: c.tags,
+                        archived: c.archived,
+                        created_at: c.created_at,
+                        updated_at: c.updated_at,
+                        events,
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok((courses, load_extras(db, owner)?))
+        })
+    }?);
+
+    let (
+        (digest, totp_enabled),
+        shares,
+        sessions,
+        api_tokens,
+        passkeys,
+        oidc_identities,
+        push_subscriptions,
+        webhooks,
+    ) = extras;
+
+    ApiResult::Ok(Export {
+        version: EXPORT_VERSION,
+        exported_at: Utc::now().naive_utc(),
+        email: a.email,
+        settings: ExportSettings {
+            recurrences: RECURRENCE_PRESETS.iter().map(|r| r.to_vec()).collect(),
+            email_verified: a.email_verified,
+            totp_enabled,
+            digest: Some(digest),
+        },
+        courses,
+        shares,
+        sessions,
+        api_tokens,
+        passkeys,
+        oidc_identities,
+        push_subscriptions,
+        webhooks,
+    })
+}
@@ -0,0 +1,329 @@
+use crate::api_result::ApiResult;
+use crate::api_token::hash_token;
+use crate::{random_token, schema, Config, CookieAccount, DbConn, DATE_FORMAT};
+use chrono::{Duration, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Timelike, Utc};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use icalendar::{Alarm, Calendar, Component, EventLike, Property};
+use rocket::http::{ContentType, Status};
+use rocket::State;
+use uuid::Uuid;
+
+#[derive(Queryable)]
+pub struct FeedEvent {
+    pub course: Uuid,
+    pub j: i64,
+    pub marking: Option<String>,
+    pub date: NaiveDate,
+    updated_at: NaiveDateTime,
+    sequence: i32,
+    course_name: String,
+    course_description: Option<String>,
+    course_updated_at: NaiveDateTime,
+    course_sequence: i32,
+}
+
+impl FeedEvent {
+    /// Stable across refreshes and recurrence changes, so that calendar clients update the event
+    /// in place instead of duplicating it
+    pub fn uid(&self) -> String {
+        format!("{}-{}@mdj", self.course, self.j)
+    }
+
+    /// Time of the last change to anything the VEVENT is built from
+    fn last_modified(&self) -> NaiveDateTime {
+        self.updated_at.max(self.course_updated_at)
+    }
+
+    /// Drawn by the database from a single counter whenever the date or marking of the event or
+    /// the name or description of its course change, so that it also keeps increasing when the
+    /// event is re-created by a recurrence change
+    pub fn sequence(&self) -> i32 {
+        self.sequence.max(self.course_sequence)
+    }
+}
+
+/// CSS color name and description prefix of a VEVENT, from the least to the most mastered marking
+fn color_name_for_mark(mark: Option<&str>) -> (&'static str, &'static str) {
+    match mark {
+        Some("red") => ("red", "[Non maîtrisé]\n"),
+        Some("orange") => ("orange", "[Peu maîtrisé]\n"),
+        Some("yellow") => ("yellow", "[Presque maîtrisé]\n"),
+        Some("green") => ("green", "[Maîtrisé]\n"),
+        _ => ("white", ""),
+    }
+}
+
+/// Completion state of a revision, for VTODOs, from the least to the most mastered marking
+pub fn todo_status_for_mark(mark: Option<&str>) -> (&'static str, u8) {
+    match mark {
+        Some("red") => ("IN-PROCESS", 25),
+        Some("orange") => ("IN-PROCESS", 50),
+        Some("yellow") => ("IN-PROCESS", 75),
+        Some("green") => ("COMPLETED", 100),
+        _ => ("NEEDS-ACTION", 0),
+    }
+}
+
+/// Kind of calendar component a revision is exported as
+#[derive(FromFormField)]
+pub enum FeedMode {
+    Event,
+    Todo,
+}
+
+fn date_property(key: &str, date: NaiveDate) -> Property {
+    Property::new(key, &date.format("%Y%m%d").to_string())
+        .add_parameter("VALUE", "DATE")
+        .done()
+}
+
+/// Display VALARM at `time` on the day an all-day component starts
+fn display_alarm(time: NaiveTime, description: &str) -> Alarm {
+    let trigger = Duration::seconds(time.num_seconds_from_midnight() as i64);
+    Alarm::display(description, trigger)
+}
+
+/// Account whose [`ical_link`] holds `token`
+pub fn feed_account(db: &mut PgConnection, token: &str) -> QueryResult<Option<Uuid>> {
+    use schema::accounts::dsl;
+
+    dsl::accounts
+        .filter(dsl::ical_token_hash.eq(hash_token(token)))
+        .select(dsl::id)
+        .first::<Uuid>(db)
+        .optional()
+}
+
+/// Revisions of the unarchived courses of `account`, optionally restricted to one `course`, to the
+/// courses with a `tag` and/or to the `from`..=`to` date window
+pub fn feed_events(
+    db: &mut PgConnection,
+    account: Uuid,
+    course: Option<Uuid>,
+    tag: Option<&str>,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+) -> QueryResult<Vec<FeedEvent>> {
+    use schema::courses::dsl as c_dsl;
+    use schema::events::dsl as e_dsl;
+
+    let mut query = e_dsl::events
+        .inner_join(c_dsl::courses)
+        .filter(c_dsl::owner.eq(account).and(c_dsl::archived.eq(false)))
+        .order_by((e_dsl::date.asc(), e_dsl::j.asc()))
+        .select((
+            e_dsl::course,
+            e_dsl::j,
+            e_dsl::marking,
+            e_dsl::date,
+            e_dsl::updated_at,
+            e_dsl::sequence,
+            c_dsl::name,
+            c_dsl::description,
+            c_dsl::updated_at,
+            c_dsl::sequence,
+        ))
+        .into_boxed();
+
+    if let Some(course) = course {
+        query = query.filter(e_dsl::course.eq(course));
+    }
+
+    if let Some(tag) = tag {
+        query = query.filter(c_dsl::tags.contains(vec![tag.trim().to_owned()]));
+    }
+
+    if let Some(from) = from {
+        query = query.filter(e_dsl::date.ge(from));
+    }
+
+    if let Some(to) = to {
+        query = query.filter(e_dsl::date.le(to));
+    }
+
+    query.load::<FeedEvent>(db)
+}
+
+fn summary(event: &FeedEvent) -> String {
+    format!("MdJ: {} #{}", event.course_name.as_str(), event.j)
+}
+
+/// Timestamps are stored in UTC, like those compared with `Utc::now().naive_utc()` elsewhere
+fn dtstamp(event: &FeedEvent) -> String {
+    Utc.from_utc_datetime(&event.last_modified())
+        .format("%Y%m%dT%H%M%SZ")
+        .to_string()
+}
+
+/// VTODO of a revision, due on its date
+pub fn todo(event: &FeedEvent, public_url: &str, alarm: Option<NaiveTime>) -> icalendar::Todo {
+    let (status, percent_complete) = todo_status_for_mark(event.marking.as_deref());
+    let summary = summary(event);
+
+    let mut cal_todo_ = icalendar::Todo::new();
+    let cal_todo = cal_todo_
+        .uid(&event.uid())
+        .add_property("DTSTAMP", &dtstamp(event))
+        .add_property("SEQUENCE", &event.sequence().to_string())
+        .append_property(date_property("DTSTART", event.date))
+        // Exclusive like the end of an all-day event, DUE must come after DTSTART
+        .append_property(date_property("DUE", event.date + Duration::days(1)))
+        .summary(&summary)
+        .add_property("STATUS", status)
+        .add_property("PERCENT-COMPLETE", &percent_complete.to_string())
+        .add_property("URL", &format!("{}courses/{}", public_url, event.course));
+
+    if let Some(description) = &event.course_description {
+        cal_todo.description(description);
+    }
+
+    if let Some(time) = alarm {
+        cal_todo.alarm(display_alarm(time, &summary));
+    }
+
+    cal_todo_
+}
+
+#[derive(serde::Serialize)]
+pub struct IcalLink {
+    /// Feed URL, holding a secret that is only returned when the link is created
+    url: String,
+}
+
+/// Creates the secret link of the account's feed, revoking the previous one
+#[post("/api/account/ical")]
+pub async fn ical_link(
+    db: DbConn,
+    a: CookieAccount,
+    config: &State<Config>,
+) -> ApiResult<IcalLink> {
+    let CookieAccount(a) = a;
+    let token = random_token(40);
+    let token_hash = hash_token(&token);
+
+    with_db!(db => {
+        use schema::accounts::dsl;
+
+        diesel::update(dsl::accounts.find(a.id))
+            .set(dsl::ical_token_hash.eq(token_hash))
+            .execute(db)
+    }?);
+
+    ApiResult::Ok(IcalLink {
+        url: format!("{}ical/{}", config.public_url, token),
+    })
+}
+
+/// Disables the account's feed
+#[delete("/api/account/ical")]
+pub async fn ical_link_delete(db: DbConn, a: CookieAccount) -> ApiResult {
+    let CookieAccount(a) = a;
+
+    with_db!(db => {
+        use schema::accounts::dsl;
+
+        diesel::update(dsl::accounts.find(a.id))
+            .set(dsl::ical_token_hash.eq(None::<String>))
+            .execute(db)
+    }?);
+
+    ApiResult::success()
+}
+
+/// iCal feed of the revisions of an account, optionally restricted to one `course`, to the courses
+/// with a `tag` and/or to the `from`..=`to` date window, with an `alarm` (`HH:MM`) on the day of each revision
+///
+/// The account is found from the secret `token` of its [`ical_link`], as calendar clients can't
+/// log in.
+///
+/// With `mode=todo`, revisions are exported as VTODOs due on their date, for task managers.
+#[get("/ical/<token>?<mode>&<course>&<tag>&<from>&<to>&<alarm>")]
+pub async fn ical(
+    db: DbConn,
+    config: &State<Config>,
+    token: &str,
+    mode: Option<FeedMode>,
+    course: Option<Uuid>,
+    tag: Option<&str>,
+    from: Option<String>,
+    to: Option<String>,
+    alarm: Option<String>,
+) -> Result<(ContentType, String), (Status, String)> {
+    let parse_date = |date: Option<String>| match date {
+        Some(date) => NaiveDate::parse_from_str(&date, DATE_FORMAT)
+            .map(Some)
+            .map_err(|e| (Status::BadRequest, format!("Invalid date: {}", e))),
+        None => Ok(None),
+    };
+
+    let from = parse_date(from)?;
+    let to = parse_date(to)?;
+    let alarm = alarm
+        .map(|alarm| NaiveTime::parse_from_str(&alarm, "%H:%M"))
+        .transpose()
+        .map_err(|e| (Status::BadRequest, format!("Invalid alarm time: {}", e)))?;
+
+    let mut calendar = Calendar::new();
+
+    let events = with_db!(db => {
+        let account = match feed_account(db, token)? {
+            Some(account) => account,
+            None => return Ok(None),
+        };
+
+        feed_events(db, account, course, tag, from, to).map(Some)
+    })
+    .map_err(|e| (Status::ServiceUnavailable, format!("Database error: {}", e)))?;
+
+    let events = events.ok_or_else(|| (Status::NotFound, String::from("Unknown feed")))?;
+
+    calendar.name("Calendrier Méthode des J");
+
+    for event in events {
+        let date = match Utc.from_local_date(&event.date) {
+            LocalResult::Single(date) => date,
+            other => {
+                eprintln!("couldn't convert local date to utc: {:?}", other);
+                continue;
+            }
+        };
+
+        let summary = summary(&event);
+        let url = format!("{}courses/{}", config.public_url, event.course);
+        let dtstamp = dtstamp(&event);
+
+        match mode {
+            None | Some(FeedMode::Event) => {
+                let (mark_color, mark_name) = color_name_for_mark(event.marking.as_deref());
+
+                let mut cal_event_ = icalendar::Event::new();
+                let cal_event = cal_event_
+                    .uid(&event.uid())
+                    .add_property("DTSTAMP", &dtstamp)
+                    .add_property("SEQUENCE", &event.sequence().to_string())
+                    .all_day(date.naive_utc())
+                    .summary(&summary)
+                    .add_property("COLOR", mark_color)
+                    .add_property("URL", &url);
+
+                if let Some(description) = &event.course_description {
+                    cal_event.description(&format!("{}{}", mark_name, description));
+                } else {
+                    cal_event.description(mark_name);
+                }
+
+                if let Some(time) = alarm {
+                    cal_event.alarm(display_alarm(time, &summary));
+                }
+
+                calendar.push(cal_event_);
+            }
+            Some(FeedMode::Todo) => {
+                calendar.push(todo(&event, &config.public_url, alarm));
+            }
+        }
+    }
+
+    Ok((ContentType::Calendar, calendar.to_string()))
+}
@@ -0,0 +1,307 @@
+use crate::api_result::ApiResult;
+use crate::export::{Export, EXPORT_VERSION};
+use crate::model::{normalize_tags, NewCourse, NewEvent};
+use crate::webhook::{self, WebhookEvent};
+use crate::{
+    insert_course, schema, Account, CourseAndOccurrences, DbConn, DATE_FORMAT, RECURRENCE_PRESETS,
+};
+use chrono::NaiveDate;
+use diesel::prelude::*;
+use diesel::PgConnection;
+use rocket::data::{Data, ToByteUnit};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use std::collections::HashSet;
+use uuid::Uuid;
+
+/// Course read from an import file, before validation
+pub struct ImportedCourse {
+    pub name: String,
+    pub description: Option<String>,
+    pub j_0: NaiveDate,
+    pub j_end: NaiveDate,
+    pub recurrence: String,
+    pub tags: Vec<String>,
+    pub archived: bool,
+    pub markings: Vec<(i64, String)>,
+}
+
+/// First recurrence preset, as a recurrence string
+pub fn default_recurrence() -> String {
+    let preset = RECURRENCE_PRESETS[0].iter().map(u32::to_string);
+    preset.collect::<Vec<_>>().join(",")
+}
+
+#[derive(serde::Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ImportStatus {
+    Created {
+        id: Uuid,
+    },
+    WouldCreate,
+    /// A course with the same name and J0 already exists, either in the account or earlier in the
+    /// imported data (in which case `existing` is `None`). It is skipped.
+    Conflict {
+        existing: Option<Uuid>,
+    },
+    Invalid {
+        error: String,
+    },
+}
+
+#[derive(serde::Serialize)]
+pub struct ImportItem {
+    name: String,
+    #[serde(flatten)]
+    status: ImportStatus,
+}
+
+#[derive(serde::Serialize)]
+pub struct ImportReport {
+    dry_run: bool,
+    /// `false` if nothing was written, either because of `dry_run` or because an item is invalid
+    committed: bool,
+    items: Vec<ImportItem>,
+}
+
+fn validate(course: &ImportedCourse) -> Result<(), String> {
+    if course.name.trim().is_empty() {
+        return Err(String::from("name is empty"));
+    }
+
+    if course.j_end < course.j_0 {
+        return Err(String::from("j_end is before j_0"));
+    }
+
+    NewEvent::try_parse_recurrence(&course.recurrence).map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+/// Validates all courses and, unless `dry_run` is set or one of them is invalid, creates them
+/// through [`insert_course`] and notifies webhooks of them. All-or-nothing.
+///
+/// With `dedup`, courses sharing their name and J0 with an existing one are reported as conflicts
+/// and skipped.
+pub fn import(
+    db: &mut PgConnection,
+    owner: Uuid,
+    courses: Vec<Result<ImportedCourse, (String, String)>>,
+    dry_run: bool,
+    dedup: bool,
+) -> QueryResult<ImportReport> {
+    use schema::courses::dsl as c_dsl;
+    use schema::events::dsl as e_dsl;
+
+    db.transaction::<_, diesel::result::Error, _>(|db| {
+        let existing = c_dsl::courses
+            .filter(c_dsl::owner.eq(owner))
+            .select((c_dsl::id, c_dsl::name, c_dsl::j_0))
+            .load::<(Uuid, String, NaiveDate)>(db)?;
+
+        let mut seen = HashSet::new();
+        let mut to_create = Vec::new();
+
+        let mut items = courses
+            .into_iter()
+            .map(|course| {
+                let course = match course {
+                    Ok(course) => course,
+                    Err((name, error)) => {
+                        let status = ImportStatus::Invalid { error };
+                        return ImportItem { name, status };
+                    }
+                };
+
+                let conflict = existing
+                    .iter()
+                    .filter(|_| dedup)
+                    .find(|(_, name, j_0)| name == &course.name && j_0 == &course.j_0)
+                    .map(|(id, ..)| *id);
+
+                let status = if let Err(error) = validate(&course) {
+                    ImportStatus::Invalid { error }
+                } else if conflict.is_some() {
+                    ImportStatus::Conflict { existing: conflict }
+                } else if dedup && !seen.insert((course.name.clone(), course.j_0)) {
+                    ImportStatus::Conflict { existing: None }
+                } else {
+                    ImportStatus::WouldCreate
+                };
+
+                let name = course.name.clone();
+                if let ImportStatus::WouldCreate = status {
+                    to_create.push(course);
+                }
+
+                ImportItem { name, status }
+            })
+            .collect::<Vec<_>>();
+
+        let invalid = items
+            .iter()
+            .any(|i| matches!(i.status, ImportStatus::Invalid { .. }));
+
+        if dry_run || invalid {
+            return Ok(ImportReport {
+                dry_run,
+                committed: false,
+                items,
+            });
+        }
+
+        let mut created = items
+            .iter_mut()
+            .filter(|i| matches!(i.status, ImportStatus::WouldCreate));
+
+        for (course, item) in to_create.into_iter().zip(&mut created) {
+            let inserted = insert_course(
+                db,
+                NewCourse {
+                    owner,
+                    name: course.name,
+                    description: course.description.filter(|d| !d.is_empty()),
+                    j_0: course.j_0,
+                    j_end: course.j_end,
+                    recurrence: course.recurrence,
+                    tags: normalize_tags(course.tags),
+                },
+            )?;
+
+            if course.archived {
+                diesel::update(c_dsl::courses.find(inserted.id))
+                    .set(c_dsl::archived.eq(true))
+                    .execute(db)?;
+            }
+
+            for (j, marking) in course.markings {
+                diesel::update(e_dsl::events.find((inserted.id, j)))
+                    .set(e_dsl::marking.eq(marking))
+                    .execute(db)?;
+            }
+
+            // Sent as the course ends up, rather than one event per marking
+            let created = CourseAndOccurrences::load(db, owner, inserted.id)?;
+            webhook::enqueue(db, owner, WebhookEvent::CourseCreated, &created)?;
+
+            item.status = ImportStatus::Created { id: inserted.id };
+        }
+
+        Ok(ImportReport {
+            dry_run,
+            committed: true,
+            items,
+        })
+    })
+}
+
+/// Imports courses from a document produced by `/api/export`, markings included
+#[post("/api/import?<dry_run>", format = "json", data = "<json>")]
+pub async fn import_json(
+    db: DbConn,
+    a: Account,
+    dry_run: Option<bool>,
+    json: Json<Export>,
+) -> ApiResult<ImportReport> {
+    let export = json.into_inner();
+
+    if export.version > EXPORT_VERSION {
+        return ApiResult::Error(Status::BadRequest, "unsupported_version");
+    }
+
+    let courses = export
+        .courses
+        .into_iter()
+        .map(|c| {
+            Ok(ImportedCourse {
+                name: c.name,
+                description: c.description,
+                j_0: c.j_0,
+                j_end: c.j_end,
+                recurrence: c.recurrence,
+                tags: c.tags,
+                archived: c.archived,
+                markings: c
+                    .events
+                    .into_iter()
+                    .filter_map(|e| e.marking.map(|m| (e.j, m)))
+                    .collect(),
+            })
+        })
+        .collect();
+
+    let report = with_db!(db => {
+        import(db, a.id, courses, dry_run.unwrap_or(false), true)
+    }?);
+
+    ApiResult::Ok(report)
+}
+
+#[derive(serde::Deserialize)]
+struct CsvCourse {
+    name: String,
+    description: Option<String>,
+    j_0: String,
+    j_end: String,
+    recurrence: Option<String>,
+}
+
+fn parse_csv_course(record: CsvCourse) -> Result<ImportedCourse, (String, String)> {
+    let parse_date = |date: &str, field: &str| {
+        NaiveDate::parse_from_str(date.trim(), DATE_FORMAT)
+            .map_err(|e| (record.name.clone(), format!("invalid {}: {}", field, e)))
+    };
+
+    let recurrence = record
+        .recurrence
+        .as_deref()
+        .map(str::trim)
+        .filter(|r| !r.is_empty())
+        .map(String::from)
+        .unwrap_or_else(default_recurrence);
+
+    Ok(ImportedCourse {
+        j_0: parse_date(&record.j_0, "j_0")?,
+        j_end: parse_date(&record.j_end, "j_end")?,
+        name: record.name,
+        description: record.description,
+        recurrence,
+        tags: Vec::new(),
+        archived: false,
+        markings: Vec::new(),
+    })
+}
+
+/// Imports courses from a CSV file with a `name,description,j_0,j_end,recurrence` header
+///
+/// Dates use the `YYYY-MM-DD` format, and an empty recurrence stands for the default preset.
+#[post("/api/import?<dry_run>", format = "text/csv", data = "<data>")]
+pub async fn import_csv(
+    db: DbConn,
+    a: Account,
+    dry_run: Option<bool>,
+    data: Data<'_>,
+) -> ApiResult<ImportReport> {
+    let csv = match data.open(2.mebibytes()).into_string().await {
+        Ok(csv) if csv.is_complete() => csv.into_inner(),
+        Ok(_) => return ApiResult::Error(Status::PayloadTooLarge, "payload_too_large"),
+        Err(_) => return ApiResult::Error(Status::BadRequest, "invalid_encoding"),
+    };
+
+    let courses = csv::ReaderBuilder::new()
+        .trim(csv::Trim::Headers)
+        .from_reader(csv.as_bytes())
+        .deserialize::<CsvCourse>()
+        .enumerate()
+        .map(|(i, record)| match record {
+            Ok(record) => parse_csv_course(record),
+            Err(e) => Err((format!("line {}", i + 2), e.to_string())),
+        })
+        .collect();
+
+    let report = with_db!(db => {
+        import(db, a.id, courses, dry_run.unwrap_or(false), true)
+    }?);
+
+    ApiResult::Ok(report)
+}
@@ -5,30 +5,82 @@ extern crate diesel_migrations;
 #[macro_use]
 extern crate rocket;
 
+macro_rules! with_db {
+    ($db:ident => $b:block ?) => {
+        match with_db!($db => $b) {
+            Ok(t) => t,
+            Err(err) => return $crate::api_result::ApiResult::from(err),
+        }
+    };
+    ($db:ident => || $b:block ?) => {
+        match with_db!($db => || $b) {
+            Ok(t) => t,
+            Err(err) => return $crate::api_result::ApiResult::from(err),
+        }
+    };
+    ($db:ident => $b:block) => {
+        $db.run(move |$db| $b).await
+    };
+    ($db:ident => || $b:block) => {
+        $db.run(move |$db| $db.transaction::<_, diesel::result::Error, _>(|$db| $b))
+            .await
+    };
+}
+
 mod api_result;
+mod api_token;
 mod asset;
+mod caldav;
+mod csrf;
+mod digest;
+mod email;
+mod export;
+mod ical;
+mod import;
+mod marking;
 mod model;
+mod oidc;
+mod passkey;
+mod password;
+mod push;
+mod rate_limit;
 mod schema;
 mod schema_ext;
+mod session;
+mod share;
+mod sync;
+#[cfg(test)]
+mod tests;
+mod timetable;
+mod totp;
+mod webhook;
 
 use crate::api_result::ApiResult;
 use crate::asset::{Asset, AssetName};
-use crate::model::NewEvent;
-use chrono::{LocalResult, NaiveDate, TimeZone, Utc};
+use crate::csrf::SameOrigin;
+use crate::marking::MarkingResult;
+use crate::model::{normalize_tags, NewCourse, NewEvent};
+use crate::rate_limit::{LoginLimiter, LoginOutcome, RateLimited};
+use crate::session::ClientInfo;
+use crate::webhook::WebhookEvent;
+use chrono::{NaiveDate, NaiveDateTime, Utc};
 use diesel::prelude::*;
+use diesel::PgConnection;
 use diesel_migrations::{EmbeddedMigrations, MigrationHarness};
-use icalendar::{Calendar, Component};
 use rand::Rng;
 use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
 use rocket::form::{Form, FromForm};
-use rocket::http::{ContentType, Cookie, CookieJar, SameSite, Status};
+use rocket::http::{Cookie, CookieJar, Method, Status};
 use rocket::outcome::try_outcome;
 use rocket::outcome::IntoOutcome;
 use rocket::request::{FromRequest, Outcome};
 use rocket::response::Redirect;
 use rocket::serde::json::Json;
-use rocket::{Request, Rocket};
+use rocket::{Build, Request, Rocket, State};
 use schema::accounts as accounts_table;
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::path::PathBuf;
 use uuid::Uuid;
 
@@ -36,12 +88,61 @@ pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
 const DATE_FORMAT: &str = "%Y-%m-%d";
 
+/// Application settings, read from the Rocket configuration
+#[derive(serde::Deserialize)]
+pub struct Config {
+    /// Public URL of the frontend, with a trailing slash
+    #[serde(default = "Config::default_public_url")]
+    public_url: String,
+    /// Outgoing mail server, emails are disabled if it is missing
+    smtp: Option<email::SmtpConfig>,
+    /// Only let accounts with a verified email address use the API
+    #[serde(default)]
+    require_verified_email: bool,
+    /// Lock emails out of the IPs with repeated failed logins, on top of the in-memory rate limiting
+    #[serde(default = "Config::default_login_lockout")]
+    login_lockout: bool,
+    /// `Secure` attribute of the session cookie, set if `public_url` uses HTTPS by default
+    secure_cookies: Option<bool>,
+    /// OpenID Connect providers users can log in with, by URL key
+    #[serde(default)]
+    oidc: HashMap<String, oidc::OidcProvider>,
+    /// Reverse proxies whose `X-Real-IP` header is trusted as the client address
+    #[serde(default)]
+    trusted_proxies: Vec<IpAddr>,
+}
+
+impl Config {
+    fn default_public_url() -> String {
+        String::from("https://mdj.edgar.bzh/")
+    }
+
+    fn default_login_lockout() -> bool {
+        true
+    }
+
+    fn secure_cookies(&self) -> bool {
+        self.secure_cookies
+            .unwrap_or_else(|| self.public_url.starts_with("https://"))
+    }
+}
+
 #[rocket_sync_db_pools::database("mdj")]
-struct DbConn(rocket_sync_db_pools::diesel::PgConnection);
+pub struct DbConn(rocket_sync_db_pools::diesel::PgConnection);
 
 #[rocket::launch]
 async fn launch() -> _ {
-    Rocket::build()
+    let mut args = std::env::args().skip(1);
+    if let Some("reset-password") = args.next().as_deref() {
+        password::reset_password_cli(args.next());
+    }
+
+    rocket(rocket::Config::figment())
+}
+
+/// The whole application, configured by `figment`
+fn rocket(figment: Figment) -> Rocket<Build> {
+    Rocket::custom(figment)
         .mount(
             "/",
             routes![
@@ -50,18 +151,87 @@ async fn launch() -> _ {
                 login,
                 logout,
                 account_info,
+                account_delete,
+                api_token::tokens,
+                api_token::tokens_insert,
+                api_token::tokens_delete,
+                password::password_change,
+                password::password_reset_request,
+                password::password_reset,
+                session::sessions,
+                session::sessions_delete,
+                session::sessions_delete_all,
+                totp::totp_enrol,
+                totp::totp_verify,
+                totp::totp_disable,
+                totp::login_totp,
+                oidc::oidc_login,
+                oidc::oidc_callback,
+                passkey::passkeys,
+                passkey::passkeys_register_start,
+                passkey::passkeys_register_finish,
+                passkey::passkeys_delete,
+                passkey::login_passkey_start,
+                passkey::login_passkey_finish,
+                email::register,
+                email::email_verification_send,
+                email::email_change,
+                email::email_confirm,
                 courses,
+                courses_get,
                 courses_insert,
                 courses_update,
                 courses_update_recurrence,
                 courses_archive,
                 courses_delete,
+                share::shares,
+                share::shares_insert,
+                share::shares_delete,
+                share::invitations,
+                share::invitations_accept,
+                share::invitations_delete,
                 timeline,
                 mark,
-                ical,
+                marking::markings,
+                export::export,
+                import::import_json,
+                import::import_csv,
+                timetable::import_ical,
+                ical::ical,
+                ical::ical_link,
+                ical::ical_link_delete,
+                caldav::caldav_options,
+                caldav::caldav_collection,
+                caldav::caldav_object,
+                caldav::caldav_object_put,
+                sync::sync,
+                sync::sync_markings,
+                digest::digest_settings,
+                digest::digest_settings_update,
+                digest::digest_unsubscribe_confirm,
+                digest::digest_unsubscribe,
+                digest::digest_mark_confirm,
+                digest::digest_mark,
+                push::push_key,
+                push::push_subscribe,
+                push::push_unsubscribe,
+                webhook::webhooks,
+                webhook::webhooks_insert,
+                webhook::webhooks_delete,
+                webhook::webhooks_deliveries,
             ],
         )
         .attach(DbConn::fairing())
+        .attach(AdHoc::config::<Config>())
+        .manage(LoginLimiter::default())
+        .manage(passkey::Registrations::default())
+        .manage(passkey::Authentications::default())
+        .attach(digest::scheduler())
+        .attach(push::scheduler())
+        .attach(webhook::scheduler())
+        .attach(sync::scheduler())
+        .attach(session::scheduler())
+        .attach(rate_limit::scheduler())
         .attach(AdHoc::on_liftoff("migration runner", |rocket| {
             Box::pin(async move {
                 let conn = DbConn::get_one(rocket)
@@ -75,28 +245,6 @@ async fn launch() -> _ {
         }))
 }
 
-macro_rules! with_db {
-    ($db:ident => $b:block ?) => {
-        match with_db!($db => $b) {
-            Ok(t) => t,
-            Err(err) => return ApiResult::from(err),
-        }
-    };
-    ($db:ident => || $b:block ?) => {
-        match with_db!($db => $b) {
-            Ok(t) => t,
-            Err(err) => return ApiResult::from(err),
-        }
-    };
-    ($db:ident => $b:block) => {
-        $db.run(move |$db| $b).await
-    };
-    ($db:ident => || $b:block) => {
-        $db.run(move |$db| $db.transaction::<_, diesel::result::Error, _>(|| $b))
-            .await
-    };
-}
-
 #[get("/<_anything..>", rank = 10)]
 async fn index(_anything: PathBuf) -> Asset {
     Asset::open(AssetName::index_html()).await.unwrap()
@@ -115,19 +263,46 @@ struct LoginForm {
 
 const COOKIE_SESSION_NAME: &str = "mdj:session";
 
+/// Random alphanumeric string, for secrets handed out to clients
+fn random_token(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
 #[derive(Debug, Identifiable, Queryable)]
 #[diesel(table_name = accounts_table)]
-struct Account {
+pub struct Account {
     id: Uuid,
     email: String,
     password: Option<String>,
+    email_verified: bool,
 }
 
+/// Authenticated account, whether its email address was verified or not
+///
+/// [`Account`] rejects unverified accounts if `require_verified_email` is set, this guard is meant
+/// for the routes they still need.
+pub struct AnyAccount(Account);
+
+/// Account authenticated by a session cookie, for the routes managing tokens and credentials
+///
+/// Bearer tokens are refused, so that a leaked token can't mint more tokens or take over the
+/// account. Like [`AnyAccount`], the email address doesn't need to be verified.
+pub struct CookieAccount(Account);
+
 #[derive(Debug)]
-enum AccountAuthError {
+pub enum AccountAuthError {
     NoCookie,
     NoDatabase,
     AccountOrSessionNotFound,
+    InvalidToken,
+    ReadOnlyToken,
+    EmailNotVerified,
+    CrossOrigin,
+    TokenNotAllowed,
 }
 
 #[rocket::async_trait]
@@ -135,34 +310,141 @@ impl<'r> FromRequest<'r> for Account {
     type Error = AccountAuthError;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let AnyAccount(account) = try_outcome!(request.guard::<AnyAccount>().await);
+
+        let require_verified_email = request
+            .rocket()
+            .state::<Config>()
+            .map_or(false, |c| c.require_verified_email);
+
+        if require_verified_email && !account.email_verified {
+            return Outcome::Failure((Status::Forbidden, AccountAuthError::EmailNotVerified));
+        }
+
+        Outcome::Success(account)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CookieAccount {
+    type Error = AccountAuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let bearer = request
+            .headers()
+            .get_one("Authorization")
+            .map_or(false, |h| h.starts_with("Bearer "));
+
+        if bearer {
+            return Outcome::Failure((Status::Forbidden, AccountAuthError::TokenNotAllowed));
+        }
+
+        let AnyAccount(account) = try_outcome!(request.guard::<AnyAccount>().await);
+        Outcome::Success(CookieAccount(account))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AnyAccount {
+    type Error = AccountAuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let bearer = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(str::to_string);
+
         let cookies = request.cookies();
-        let cookie = match cookies
-            .get_private(COOKIE_SESSION_NAME)
-            .ok_or((Status::Unauthorized, AccountAuthError::NoCookie))
-        {
-            Ok(cookie) => cookie,
-            Err(err) => return Outcome::Failure(err),
-        };
+        let cookie = cookies.get_private(COOKIE_SESSION_NAME);
+
+        if bearer.is_none() && cookie.is_none() {
+            return Outcome::Failure((Status::Unauthorized, AccountAuthError::NoCookie));
+        }
 
         let db = try_outcome!(request
             .guard::<DbConn>()
             .await
             .map_failure(|(status, _)| (status, AccountAuthError::NoDatabase)));
 
-        let session_token = cookie.value().to_string();
+        if let Some(token) = bearer {
+            let (account, read_only) = try_outcome!(with_db!(db => {
+                api_token::authenticate(db, &token)
+            })
+            .map_err(|_| AccountAuthError::InvalidToken)
+            .into_outcome(Status::Unauthorized));
+
+            // Read-only tokens are limited to safe methods, as every other route modifies something
+            if read_only && !matches!(request.method(), Method::Get | Method::Head) {
+                return Outcome::Failure((Status::Forbidden, AccountAuthError::ReadOnlyToken));
+            }
+
+            return Outcome::Success(AnyAccount(account));
+        }
+
+        // Unlike bearer tokens, cookies are sent along cross-site requests
+        try_outcome!(request
+            .guard::<SameOrigin>()
+            .await
+            .map_failure(|(status, _)| (status, AccountAuthError::CrossOrigin)));
+
+        let session_token = cookie.unwrap().value().to_string();
 
         let account = with_db!(db => {
             use schema::accounts::dsl;
 
-            dsl::accounts.select((dsl::id, dsl::email, dsl::password))
+            let account = dsl::accounts.select((dsl::id, dsl::email, dsl::password, dsl::email_verified))
                 .inner_join(schema::sessions::dsl::sessions)
-                .filter(schema::sessions::dsl::token.eq(session_token))
-                .first::<Account>(db)
+                .filter(schema::sessions::dsl::token.eq(&session_token))
+                .filter(schema::sessions::dsl::expires.gt(diesel::dsl::now))
+                .first::<Account>(db)?;
+
+            session::touch(db, &session_token)?;
+            QueryResult::Ok(account)
         })
         .map_err(|_| AccountAuthError::AccountOrSessionNotFound)
         .into_outcome(Status::Unauthorized);
 
-        Outcome::Success(try_outcome!(account))
+        Outcome::Success(AnyAccount(try_outcome!(account)))
+    }
+}
+
+/// Logs in once the account is authenticated, going through the TOTP step first if it is enabled
+async fn open_session(
+    db: &DbConn,
+    cookies: &CookieJar<'_>,
+    config: &Config,
+    account: Uuid,
+    totp_enabled: bool,
+    client: ClientInfo,
+) -> QueryResult<Redirect> {
+    if totp_enabled {
+        let token = with_db!(db => {
+            totp::start_pending_login(db, account, client)
+        })?;
+
+        totp::set_pending_login_cookie(cookies, config, token);
+        return Ok(Redirect::to("/login/totp"));
+    }
+
+    let (token, expires) = with_db!(db => {
+        session::create(db, account, client)
+    })?;
+
+    session::set_cookie(cookies, config, token, expires);
+    Ok(Redirect::to("/"))
+}
+
+/// Error responses of [`login`]
+#[derive(Responder)]
+pub enum LoginError {
+    Error((Status, rocket::response::content::RawJson<&'static str>)),
+    RateLimited(RateLimited),
+}
+
+impl From<(Status, rocket::response::content::RawJson<&'static str>)> for LoginError {
+    fn from(error: (Status, rocket::response::content::RawJson<&'static str>)) -> Self {
+        Self::Error(error)
     }
 }
 
@@ -171,100 +453,193 @@ async fn login(
     db: DbConn,
     form: Form<LoginForm>,
     cookies: &CookieJar<'_>,
-) -> Result<Redirect, (Status, rocket::response::content::RawJson<&'static str>)> {
+    config: &State<Config>,
+    limiter: &State<LoginLimiter>,
+    client: ClientInfo,
+    _same_origin: SameOrigin,
+) -> Result<Redirect, LoginError> {
     use rocket::response::content::RawJson as ContentJson;
 
     let form = form.into_inner();
-    let email = form.email;
+    let email = email::normalize_email(&form.email);
+    let ClientInfo { user_agent, ip } = client;
 
+    let database_error = |_| {
+        (
+            Status::InternalServerError,
+            ContentJson(r#"{"error_kind":"database"}"#),
+        )
+    };
+
+    // Longer addresses can't belong to an account, nor fit in `login_attempts`
+    if email.chars().count() > 256 {
+        return Err(LoginError::from((
+            Status::Unauthorized,
+            ContentJson(r#"{"error_kind":"invalid_credentials"}"#),
+        )));
+    }
+
+    let mut keys = vec![format!("email:{}", email)];
+    keys.extend(ip.as_ref().map(|ip| format!("ip:{}", ip)));
+
+    let login_lockout = config.login_lockout;
+    let limited = match limiter.take(&keys) {
+        Err(retry_after) => Some(retry_after),
+        Ok(()) if login_lockout => {
+            let (email, ip) = (email.clone(), ip.clone());
+            with_db!(db => { rate_limit::lockout(db, &email, ip.as_deref()) })
+                .map_err(database_error)?
+        }
+        Ok(()) => None,
+    };
+
+    if let Some(retry_after) = limited {
+        let (email, ip) = (email.clone(), ip.clone());
+        with_db!(db => {
+            rate_limit::record_attempt(db, &email, ip, LoginOutcome::RateLimited)
+        })
+        .map_err(database_error)?;
+
+        return Err(LoginError::RateLimited(RateLimited::new(retry_after)));
+    }
+
+    let lookup_email = email.clone();
     let account = with_db!(db => {
         use schema::accounts::dsl;
 
         dsl::accounts
-            .filter(dsl::email.eq(email))
-            .first::<Account>(db)
+            .select((
+                (dsl::id, dsl::email, dsl::password, dsl::email_verified),
+                dsl::totp_enabled,
+            ))
+            .filter(dsl::email.eq(lookup_email))
+            .first::<(Account, bool)>(db)
+            .optional()
     })
-    .map_err(|_| {
-        (
-            Status::InternalServerError,
-            ContentJson(r#"{"error_kind":"database"}"#),
-        )
-    })?;
+    .map_err(database_error)?;
 
-    if bcrypt::verify(&form.password, &account.password.unwrap_or_default()).unwrap_or_default() {
-        let account_id = account.id;
+    let password = account.as_ref().and_then(|(a, _)| a.password.clone());
+    let valid = bcrypt::verify(&form.password, &password.unwrap_or_default()).unwrap_or_default();
 
-        let session = with_db!(db => {
-            use schema::sessions::dsl;
+    let outcome = match valid {
+        true => LoginOutcome::Success,
+        false => LoginOutcome::InvalidCredentials,
+    };
 
-            use schema::sessions as sessions_table;
-            #[derive(Insertable)]
-            #[diesel(table_name = sessions_table)]
-            struct Session<'a> {
-                token: &'a str,
-                account: Uuid,
-            }
+    let attempt_ip = ip.clone();
+    with_db!(db => {
+        rate_limit::record_attempt(db, &email, attempt_ip, outcome)
+    })
+    .map_err(database_error)?;
 
-            let token: String = rand::thread_rng()
-                .sample_iter(&rand::distributions::Alphanumeric)
-                .take(64)
-                .map(char::from)
-                .collect();
-
-            diesel::insert_into(dsl::sessions)
-                .values(Session {token: &token, account: account_id})
-                .execute(db)
-                .map(|_| token)
-        });
-
-        match session {
-            Ok(session) => {
-                cookies.add_private(
-                    Cookie::build(COOKIE_SESSION_NAME, session)
-                        .same_site(SameSite::Strict)
-                        .finish(),
-                );
-                Ok(Redirect::to("/"))
-            }
-            Err(_) => Err((
-                Status::InternalServerError,
-                ContentJson(r#"{"error_kind":"database"}"#),
-            )),
+    match account {
+        Some((account, totp_enabled)) if valid => {
+            let client = ClientInfo { user_agent, ip };
+
+            let redirect = open_session(&db, cookies, config, account.id, totp_enabled, client)
+                .await
+                .map_err(database_error)?;
+
+            Ok(redirect)
         }
-    } else {
-        Err((
+        _ => Err(LoginError::from((
             Status::Unauthorized,
             ContentJson(r#"{"error_kind":"invalid_credentials"}"#),
-        ))
+        ))),
     }
 }
 
+/// Ends the session of the request, deleting it so that its token can't be used anymore
 #[get("/logout")]
-fn logout(cookies: &CookieJar) -> Redirect {
+async fn logout(db: DbConn, cookies: &CookieJar<'_>) -> Result<Redirect, Status> {
+    if let Some(cookie) = cookies.get_private(COOKIE_SESSION_NAME) {
+        let token = cookie.value().to_string();
+
+        with_db!(db => {
+            use schema::sessions::dsl;
+
+            diesel::delete(dsl::sessions.filter(dsl::token.eq(token))).execute(db)
+        })
+        .map_err(|_| Status::InternalServerError)?;
+    }
+
     cookies.remove_private(Cookie::named(COOKIE_SESSION_NAME));
-    Redirect::to("/login")
+    Ok(Redirect::to("/login"))
 }
 
+/// Recurrence presets offered to every account, the first one being the default
+const RECURRENCE_PRESETS: [&[u32]; 1] = [&[0, 1, 3, 7, 14, 21, 30, 45, 60, 75, 90, 95, 110]];
+
 #[derive(serde::Serialize)]
 struct AccountInfo {
     id: Uuid,
     email: String,
+    email_verified: bool,
     recurrences: [&'static [u32]; 1],
 }
 
 #[get("/api/account")]
-fn account_info(a: Account) -> ApiResult<AccountInfo> {
+fn account_info(a: AnyAccount) -> ApiResult<AccountInfo> {
+    let AnyAccount(a) = a;
+
     ApiResult::Ok(AccountInfo {
         id: a.id,
         email: a.email,
-        recurrences: [&[0, 1, 3, 7, 14, 21, 30, 45, 60, 75, 90, 95, 110]],
+        email_verified: a.email_verified,
+        recurrences: RECURRENCE_PRESETS,
     })
 }
 
+#[derive(serde::Deserialize)]
+struct AccountDeletion {
+    /// Required if the account has a password
+    password: Option<String>,
+}
+
+/// Deletes the account along with all of its data, and logs out
+#[delete("/api/account", data = "<json>")]
+async fn account_delete(
+    db: DbConn,
+    a: CookieAccount,
+    cookies: &CookieJar<'_>,
+    json: Json<AccountDeletion>,
+) -> ApiResult {
+    let CookieAccount(a) = a;
+
+    if let Some(hash) = &a.password {
+        let password = json.into_inner().password.unwrap_or_default();
+        if !bcrypt::verify(&password, hash).unwrap_or_default() {
+            return ApiResult::Error(Status::Unauthorized, "invalid_credentials");
+        }
+    }
+
+    with_db!(db => {
+        use schema::accounts::dsl as a_dsl;
+        use schema::login_attempts::dsl as l_dsl;
+        use schema::tombstones::dsl as t_dsl;
+
+        db.transaction::<_, diesel::result::Error, _>(|db| {
+            // Sessions, courses, events, tokens and every other row referencing the account are
+            // removed by `on delete cascade`
+            diesel::delete(a_dsl::accounts.find(a.id)).execute(db)?;
+
+            // Login attempts are keyed by email rather than by account
+            diesel::delete(l_dsl::login_attempts.filter(l_dsl::email.eq(&a.email))).execute(db)?;
+
+            // Tombstones have no foreign key, and the cascade above just created some more
+            diesel::delete(t_dsl::tombstones.filter(t_dsl::owner.eq(a.id))).execute(db)
+        })
+    }?);
+
+    cookies.remove_private(Cookie::named(COOKIE_SESSION_NAME));
+
+    ApiResult::success()
+}
+
 #[derive(Queryable, serde::Serialize)]
 pub struct Course {
     id: Uuid,
-    author: Uuid,
+    owner: Uuid,
     name: String,
     description: Option<String>,
     j_0: NaiveDate,
@@ -272,38 +647,92 @@ pub struct Course {
     recurrence: String,
     cache_key: Uuid,
     archived: bool,
+    created_at: NaiveDateTime,
+    updated_at: NaiveDateTime,
+    /// Filtered on by [`sync`], never sent to clients
+    #[serde(skip)]
+    #[allow(dead_code)]
+    updated_xid: i64,
+    /// Read-only share this course follows, see [`share`]
+    share: Option<Uuid>,
+    tags: Vec<String>,
+    /// Bumped with the `SEQUENCE` of the course's events in the [`ical`] feed, never sent to clients
+    #[serde(skip)]
+    #[allow(dead_code)]
+    sequence: i32,
 }
 
+/// `(date, j, marking)` triple describing one event of a course
+type Occurrence = (NaiveDate, i64, Option<String>);
+
 #[derive(serde::Serialize)]
 pub struct CourseAndOccurrences {
     id: Uuid,
-    author: Uuid,
+    owner: Uuid,
     name: String,
     description: Option<String>,
     j_0: NaiveDate,
     j_end: NaiveDate,
     recurrence: String,
     cache_key: Uuid,
+    archived: bool,
+    created_at: NaiveDateTime,
+    updated_at: NaiveDateTime,
+    share: Option<Uuid>,
+    tags: Vec<String>,
 
-    occurrences: Vec<(NaiveDate, i64, Option<String>)>,
+    occurrences: Vec<Occurrence>,
 }
 
-impl From<(Course, Vec<(NaiveDate, i64, Option<String>)>)> for CourseAndOccurrences {
-    fn from((c, occurrences): (Course, Vec<(NaiveDate, i64, Option<String>)>)) -> Self {
+impl From<(Course, Vec<Occurrence>)> for CourseAndOccurrences {
+    fn from((c, occurrences): (Course, Vec<Occurrence>)) -> Self {
         Self {
             id: c.id,
-            author: c.id,
+            owner: c.owner,
             name: c.name,
             description: c.description,
             j_0: c.j_0,
             j_end: c.j_end,
             recurrence: c.recurrence,
             cache_key: c.cache_key,
+            archived: c.archived,
+            created_at: c.created_at,
+            updated_at: c.updated_at,
+            share: c.share,
+            tags: c.tags,
             occurrences,
         }
     }
 }
 
+impl CourseAndOccurrences {
+    /// Loads a single course of `owner` along with its occurrences, failing with
+    /// [`diesel::result::Error::NotFound`] if it doesn't exist or belongs to someone else
+    fn load(db: &mut PgConnection, owner: Uuid, id: Uuid) -> QueryResult<Self> {
+        use schema::courses::dsl as c_dsl;
+
+        let course = c_dsl::courses
+            .filter(c_dsl::owner.eq(owner).and(c_dsl::id.eq(id)))
+            .first::<Course>(db)?;
+
+        let mut course = Self::from((course, Vec::new()));
+        course.load_occurrences(db)?;
+        Ok(course)
+    }
+
+    fn load_occurrences(&mut self, db: &mut PgConnection) -> QueryResult<()> {
+        use schema::events::dsl as e_dsl;
+
+        self.occurrences = e_dsl::events
+            .order_by(e_dsl::date.asc())
+            .filter(e_dsl::course.eq(self.id))
+            .select((e_dsl::date, e_dsl::j, e_dsl::marking))
+            .load::<Occurrence>(db)?;
+
+        Ok(())
+    }
+}
+
 macro_rules! try_block_mut {
     { $($token:tt)* } => {{
         let mut l = || {
@@ -313,33 +742,56 @@ macro_rules! try_block_mut {
     }}
 }
 
-#[get("/api/courses?<archived>")]
+#[get("/api/courses?<archived>&<modified_since>")]
 async fn courses(
     db: DbConn,
     a: Account,
     archived: Option<bool>,
+    modified_since: Option<String>,
 ) -> ApiResult<Vec<CourseAndOccurrences>> {
-    let archived = archived.unwrap_or(false);
+    let modified_since = match modified_since.map(|date| date.parse::<NaiveDateTime>()) {
+        Some(Err(_)) => return ApiResult::Error(Status::BadRequest, "invalid_modified_since"),
+        Some(Ok(since)) => Some(since),
+        None => None,
+    };
+
+    // Incremental fetches must also see courses that were just archived or unarchived
+    let archived = match (archived, modified_since) {
+        (None, None) => Some(false),
+        (archived, _) => archived,
+    };
 
     let courses = with_db!(db => {
         use schema::courses::dsl as c_dsl;
         use schema::events::dsl as e_dsl;
 
         try_block_mut! {
-            let mut courses: Vec<CourseAndOccurrences> = c_dsl::courses
+            let mut query = c_dsl::courses
                 .order_by(c_dsl::j_0.asc())
-                .filter(c_dsl::owner.eq(a.id).and(c_dsl::archived.eq(archived)))
+                .filter(c_dsl::owner.eq(a.id))
+                .into_boxed();
+
+            if let Some(archived) = archived {
+                query = query.filter(c_dsl::archived.eq(archived));
+            }
+
+            if let Some(since) = modified_since {
+                let modified_events = e_dsl::events
+                    .select(e_dsl::course)
+                    .filter(e_dsl::owner.eq(a.id).and(e_dsl::updated_at.ge(since)));
+
+                query = query
+                    .filter(c_dsl::updated_at.ge(since).or(c_dsl::id.eq_any(modified_events)));
+            }
+
+            let mut courses: Vec<CourseAndOccurrences> = query
                 .load::<Course>(db)?
                 .into_iter()
                 .map(|c| CourseAndOccurrences::from((c, Vec::new())))
                 .collect::<Vec<_>>();
 
             for course in &mut courses {
-                course.occurrences = e_dsl::events
-                    .order_by(e_dsl::date.asc())
-                    .filter(e_dsl::course.eq(course.id))
-                    .select((e_dsl::date, e_dsl::j, e_dsl::marking))
-                    .load::<(NaiveDate, i64, Option<String>)>(db)?;
+                course.load_occurrences(db)?;
             }
 
             Ok(courses) as Result<_, diesel::result::Error>
@@ -349,6 +801,15 @@ async fn courses(
     ApiResult::Ok(courses)
 }
 
+#[get("/api/courses/<id>")]
+async fn courses_get(db: DbConn, a: Account, id: Uuid) -> ApiResult<CourseAndOccurrences> {
+    let course = with_db!(db => {
+        CourseAndOccurrences::load(db, a.id, id)
+    }?);
+
+    ApiResult::Ok(course)
+}
+
 #[derive(serde::Deserialize)]
 struct CourseBody {
     name: String,
@@ -356,6 +817,39 @@ struct CourseBody {
     j_0: NaiveDate,
     j_end: NaiveDate,
     recurrence: String,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+/// Inserts a course along with the events generated from its recurrence
+fn insert_course(db: &mut PgConnection, course: NewCourse) -> QueryResult<CourseAndOccurrences> {
+    use schema::courses::dsl as c_dsl;
+    use schema::events::dsl as e_dsl;
+
+    let offsets = NewEvent::parse_recurrence(&course.recurrence);
+
+    db.transaction::<_, diesel::result::Error, _>(|db| {
+        let course = diesel::insert_into(c_dsl::courses)
+            .values(course)
+            .get_result::<Course>(db)?;
+        let dates = NewEvent::from_offsets(
+            &offsets,
+            course.owner,
+            course.id,
+            course.j_0,
+            course.j_end,
+            course.cache_key,
+        );
+        let occurrences = dates
+            .iter()
+            .zip(&offsets)
+            .map(|(date, o)| (date.date, *o as _, None))
+            .collect();
+        diesel::insert_into(e_dsl::events)
+            .values(dates)
+            .execute(db)?;
+        Ok(CourseAndOccurrences::from((course, occurrences)))
+    })
 }
 
 #[post("/api/courses", data = "<json>")]
@@ -366,20 +860,6 @@ async fn courses_insert(
 ) -> ApiResult<CourseAndOccurrences> {
     let json = json.into_inner();
 
-    let offsets = NewEvent::parse_recurrence(&json.recurrence);
-
-    use schema::courses as courses_table;
-    #[derive(Insertable)]
-    #[diesel(table_name = courses_table)]
-    struct NewCourse {
-        owner: Uuid,
-        name: String,
-        description: Option<String>,
-        j_0: NaiveDate,
-        j_end: NaiveDate,
-        recurrence: String,
-    }
-
     let course = NewCourse {
         owner: a.id,
         name: json.name,
@@ -387,20 +867,14 @@ async fn courses_insert(
         j_0: json.j_0,
         j_end: json.j_end,
         recurrence: json.recurrence,
+        tags: normalize_tags(json.tags),
     };
 
     let course = with_db!(db => {
-        use schema::courses::dsl as c_dsl;
-        use schema::events::dsl as e_dsl;
-
         db.transaction::<_, diesel::result::Error, _>(|db| {
-            let course = diesel::insert_into(c_dsl::courses).values(course).get_result::<Course>(db)?;
-            let dates = NewEvent::from_offsets(
-                &offsets, course.author, course.id, course.j_0, course.j_end, course.cache_key
-            );
-            let occurrences = dates.iter().zip(&offsets).map(|(date, o)| (date.date, *o as _, None)).collect();
-            diesel::insert_into(e_dsl::events).values(dates).execute(db)?;
-            Ok(CourseAndOccurrences::from((course, occurrences)))
+            let course = insert_course(db, course)?;
+            webhook::enqueue(db, course.owner, WebhookEvent::CourseCreated, &course)?;
+            Ok(course)
         })
     }?);
 
@@ -411,41 +885,61 @@ async fn courses_insert(
 struct CourseMod {
     name: Option<String>,
     description: Option<String>,
+    tags: Option<Vec<String>>,
 }
 
 #[put("/api/courses/<id>", data = "<json>")]
-async fn courses_update(db: DbConn, a: Account, id: Uuid, json: Json<CourseMod>) -> ApiResult {
+async fn courses_update(
+    db: DbConn,
+    a: Account,
+    id: Uuid,
+    json: Json<CourseMod>,
+) -> ApiResult<CourseAndOccurrences> {
     let json = json.into_inner();
 
-    with_db!(db => || {
-        use schema::courses::dsl as c_dsl;
-
-        use schema::courses as courses_table;
-        #[derive(AsChangeset)]
-        #[diesel(table_name = courses_table)]
-        struct CourseChangeset {
-            name: Option<String>,
-            description: Option<Option<String>>,
-        }
+    let course = with_db!(db => {
+        db.transaction::<_, diesel::result::Error, _>(|db| {
+            if share::is_following(db, a.id, id)? {
+                return Ok(None);
+            }
 
-        let changes = CourseChangeset {
-            name: json.name,
-            description: match json.description {
-                Some(d) if d.is_empty() => Some(None),
-                Some(d) => Some(Some(d)),
-                None => None,
-            },
-        };
+            use schema::courses::dsl as c_dsl;
 
-        diesel::update(c_dsl::courses)
-            .set(changes)
-            .filter(c_dsl::owner.eq(a.id).and(c_dsl::id.eq(id)))
-            .execute(db)?;
+            use schema::courses as courses_table;
+            #[derive(AsChangeset)]
+            #[diesel(table_name = courses_table)]
+            struct CourseChangeset {
+                name: Option<String>,
+                description: Option<Option<String>>,
+                tags: Option<Vec<String>>,
+            }
 
-        Result::<_, diesel::result::Error>::Ok(())
+            let changes = CourseChangeset {
+                name: json.name,
+                description: match json.description {
+                    Some(d) if d.is_empty() => Some(None),
+                    Some(d) => Some(Some(d)),
+                    None => None,
+                },
+                tags: json.tags.map(normalize_tags),
+            };
+
+            diesel::update(c_dsl::courses)
+                .set(changes)
+                .filter(c_dsl::owner.eq(a.id).and(c_dsl::id.eq(id)))
+                .execute(db)?;
+
+            let course = CourseAndOccurrences::load(db, a.id, id)?;
+            webhook::enqueue(db, a.id, WebhookEvent::CourseUpdated, &course)?;
+            share::sync_followers(db, &course)?;
+            Ok(Some(course))
+        })
     }?);
 
-    ApiResult::success()
+    match course {
+        Some(course) => ApiResult::Ok(course),
+        None => ApiResult::Error(Status::Forbidden, "shared_course_read_only"),
+    }
 }
 
 #[derive(serde::Deserialize)]
@@ -461,7 +955,7 @@ async fn courses_update_recurrence(
     a: Account,
     id: Uuid,
     json: Json<UpdateRecurrence>,
-) -> ApiResult {
+) -> ApiResult<CourseAndOccurrences> {
     let UpdateRecurrence {
         recurrence,
         j_0,
@@ -470,80 +964,123 @@ async fn courses_update_recurrence(
 
     let offsets = NewEvent::parse_recurrence(&recurrence);
 
-    with_db!(db => || {
-        use schema::courses::dsl as c_dsl;
-        use schema::events::dsl as e_dsl;
+    let course = with_db!(db => {
+        db.transaction::<_, diesel::result::Error, _>(|db| {
+            if share::is_following(db, a.id, id)? {
+                return Ok(None);
+            }
 
-        let cache_key = Uuid::new_v4();
+            use schema::courses::dsl as c_dsl;
+            use schema::events::dsl as e_dsl;
 
-        let course = c_dsl::courses
-            .filter(c_dsl::owner.eq(a.id).and(c_dsl::id.eq(id)))
-            .first::<Course>(db)?;
+            let cache_key = Uuid::new_v4();
 
-        let events = NewEvent::from_offsets(&offsets, a.id, id, j_0, j_end, cache_key);
+            // Fails with `NotFound` if the course belongs to someone else
+            c_dsl::courses
+                .filter(c_dsl::owner.eq(a.id).and(c_dsl::id.eq(id)))
+                .first::<Course>(db)?;
 
-        diesel::update(c_dsl::courses)
-            .filter(c_dsl::owner.eq(a.id).and(c_dsl::id.eq(id)))
-            .set((
-                c_dsl::recurrence.eq(recurrence),
-                c_dsl::cache_key.eq(cache_key),
-                c_dsl::j_0.eq(j_0),
-                c_dsl::j_end.eq(j_end),
-            ))
-            .execute(db)?;
+            let events = NewEvent::from_offsets(&offsets, a.id, id, j_0, j_end, cache_key);
 
-        diesel::insert_into(e_dsl::events)
-            .values(events)
-            .execute(db)
+            diesel::update(c_dsl::courses)
+                .filter(c_dsl::owner.eq(a.id).and(c_dsl::id.eq(id)))
+                .set((
+                    c_dsl::recurrence.eq(recurrence),
+                    c_dsl::cache_key.eq(cache_key),
+                    c_dsl::j_0.eq(j_0),
+                    c_dsl::j_end.eq(j_end),
+                ))
+                .execute(db)?;
+
+            diesel::insert_into(e_dsl::events)
+                .values(events)
+                .execute(db)?;
+
+            let course = CourseAndOccurrences::load(db, a.id, id)?;
+            webhook::enqueue(db, a.id, WebhookEvent::CourseUpdated, &course)?;
+            share::sync_followers(db, &course)?;
+            Ok(Some(course))
+        })
     }?);
 
-    ApiResult::success()
+    match course {
+        Some(course) => ApiResult::Ok(course),
+        None => ApiResult::Error(Status::Forbidden, "shared_course_read_only"),
+    }
 }
 
 #[put("/api/courses/<id>/archived", data = "<archived>")]
-async fn courses_archive(db: DbConn, a: Account, id: Uuid, archived: Json<bool>) -> ApiResult {
+async fn courses_archive(
+    db: DbConn,
+    a: Account,
+    id: Uuid,
+    archived: Json<bool>,
+) -> ApiResult<CourseAndOccurrences> {
     let archived = archived.into_inner();
 
-    with_db!(db => {
-        use schema::courses::dsl as c_dsl;
+    let course = with_db!(db => {
+        db.transaction::<_, diesel::result::Error, _>(|db| {
+            use schema::courses::dsl as c_dsl;
 
-        use schema::courses as courses_table;
-        #[derive(AsChangeset)]
-        #[diesel(table_name = courses_table)]
-        struct CourseChangeset {
-            archived: bool,
-        }
+            use schema::courses as courses_table;
+            #[derive(AsChangeset)]
+            #[diesel(table_name = courses_table)]
+            struct CourseChangeset {
+                archived: bool,
+            }
+
+            diesel::update(c_dsl::courses)
+                .set(CourseChangeset { archived })
+                .filter(c_dsl::owner.eq(a.id).and(c_dsl::id.eq(id)))
+                .execute(db)?;
 
-        diesel::update(c_dsl::courses)
-            .set(CourseChangeset { archived })
-            .filter(c_dsl::owner.eq(a.id).and(c_dsl::id.eq(id)))
-            .execute(db)
+            let course = CourseAndOccurrences::load(db, a.id, id)?;
+            webhook::enqueue(db, a.id, WebhookEvent::CourseArchived, &course)?;
+            Ok(course)
+        })
     }?);
 
-    ApiResult::success()
+    ApiResult::Ok(course)
 }
 
 #[delete("/api/courses/<id>")]
 async fn courses_delete(db: DbConn, a: Account, id: Uuid) -> ApiResult {
     with_db!(db => {
-        use schema::courses::dsl;
+        db.transaction::<_, diesel::result::Error, _>(|db| {
+            use schema::courses::dsl;
+
+            let deleted = diesel::delete(dsl::courses)
+                .filter(dsl::owner.eq(a.id).and(dsl::id.eq(id)))
+                .execute(db)?;
 
-        diesel::delete(dsl::courses)
-            .filter(dsl::owner.eq(a.id).and(dsl::id.eq(id)))
-            .execute(db)
+            if deleted > 0 {
+                webhook::enqueue(db, a.id, WebhookEvent::CourseDeleted, &webhook::Deleted { id })?;
+            }
+
+            Ok(())
+        })
     }?);
 
     ApiResult::success()
 }
 
 #[derive(Queryable, serde::Serialize)]
-struct Event {
+pub struct Event {
     owner: Uuid,
     course: Uuid,
     j: i64,
     marking: Option<String>,
     date: NaiveDate,
     cache_key: Option<Uuid>,
+    created_at: NaiveDateTime,
+    updated_at: NaiveDateTime,
+    /// Filtered on by [`sync`], sent back by clients along with their offline markings to detect
+    /// conflicts, see [`sync::sync_markings`]
+    updated_xid: i64,
+    /// `SEQUENCE` of the event in the [`ical`] feed, never sent to clients
+    #[serde(skip)]
+    #[allow(dead_code)]
+    sequence: i32,
 }
 
 #[derive(Queryable, serde::Serialize)]
@@ -559,6 +1096,8 @@ struct EventAndCourse {
     previous_marking: Option<String>,
     date: NaiveDate,
     cache_key: Option<Uuid>,
+    created_at: NaiveDateTime,
+    updated_at: NaiveDateTime,
 }
 
 #[get("/api/timeline?<after>")]
@@ -581,71 +1120,14 @@ async fn timeline(db: DbConn, a: Account, after: Option<String>) -> ApiResult<Ve
 
 #[put("/api/courses/<course>/events/<j>/marking", data = "<marking>")]
 async fn mark(db: DbConn, a: Account, course: Uuid, j: u32, marking: String) -> ApiResult {
-    let marking = Some(marking).filter(|m| !m.is_empty());
-
-    with_db!(db => {
-        use schema::events::dsl;
-
-        diesel::update(dsl::events)
-            .filter(dsl::owner.eq(a.id).and(dsl::course.eq(course).and(dsl::j.eq(j as i64))))
-            .set(dsl::marking.eq(marking))
-            .execute(db)
+    let marked = with_db!(db => {
+        db.transaction::<_, diesel::result::Error, _>(|db| {
+            marking::apply_marking(db, a.id, course, j as i64, Some(marking), None)
+        })
     }?);
 
-    ApiResult::success()
-}
-
-fn color_name_for_mark(mark: Option<&str>) -> (&'static str, &'static str) {
-    match mark {
-        Some("started") => ("yellow", "[Démarré]\n"),
-        Some("further_learning_required") => ("#ffc000", "[À approfondir]\n"),
-        Some("done") => ("#92d050", "[Terminé]\n"),
-        _ => ("white", ""),
+    match marked {
+        MarkingResult::NotFound => ApiResult::from(diesel::result::Error::NotFound),
+        _ => ApiResult::success(),
     }
 }
-
-// TODO secure
-#[get("/ical/<account>")]
-async fn ical(db: DbConn, account: Uuid) -> Result<(ContentType, String), (Status, String)> {
-    let mut calendar = Calendar::new();
-
-    let events: Vec<EventAndCourse> = with_db!(db => {
-        use schema_ext::timeline::dsl;
-
-        dsl::timeline
-            .filter(dsl::course_owner.eq(account))
-            .load::<EventAndCourse>(db)
-    })
-    .map_err(|e| (Status::ServiceUnavailable, format!("Database error: {}", e)))?;
-
-    calendar.name("Calendrier Méthode des J");
-
-    for event in events {
-        let date = match Utc.from_local_date(&event.date) {
-            LocalResult::Single(date) => date,
-            other => {
-                eprintln!("couldn't convert local date to utc: {:?}", other);
-                continue;
-            }
-        };
-
-        let (mark_color, mark_name) = color_name_for_mark(event.marking.as_deref());
-
-        let mut cal_event_ = icalendar::Event::new();
-        let cal_event = cal_event_
-            .all_day(date.naive_utc())
-            .summary(&format!("MdJ: {} #{}", event.course_name.as_str(), event.j))
-            .add_property("COLOR", mark_color)
-            .add_property("URL", "https://mdj.edgar.bzh/");
-
-        if let Some(description) = event.course_description {
-            cal_event.description(&format!("{}{}", mark_name, description));
-        } else {
-            cal_event.description(mark_name);
-        }
-
-        calendar.push(cal_event_);
-    }
-
-    Ok((ContentType::Calendar, calendar.to_string()))
-}
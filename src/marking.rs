@@ -0,0 +1,114 @@
+use crate::api_result::ApiResult;
+use crate::webhook::{self, Marked, WebhookEvent};
+use crate::{schema, Account, DbConn};
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use diesel::PgConnection;
+use rocket::serde::json::Json;
+use uuid::Uuid;
+
+#[derive(serde::Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum MarkingResult {
+    Applied {
+        updated_at: NaiveDateTime,
+        updated_xid: i64,
+    },
+    /// The event was changed on the server after the client last saw it, nothing was written
+    Conflict {
+        marking: Option<String>,
+        updated_at: NaiveDateTime,
+        updated_xid: i64,
+    },
+    NotFound,
+}
+
+/// Sets the marking of the `j` event of `course`, an empty marking clearing it
+///
+/// Every route that marks events goes through here, so that they all notify webhooks alike. It
+/// should be called in a transaction, along with anything else the route changes.
+///
+/// If `known_xid` is given and the event was modified by a later transaction, the marking isn't
+/// written and [`MarkingResult::Conflict`] is returned instead. Transaction ids are compared rather
+/// than timestamps, which can go backwards when the clock of the server is adjusted.
+pub fn apply_marking(
+    db: &mut PgConnection,
+    owner: Uuid,
+    course: Uuid,
+    j: i64,
+    marking: Option<String>,
+    known_xid: Option<i64>,
+) -> QueryResult<MarkingResult> {
+    use schema::events::dsl;
+
+    let event = dsl::events
+        .filter(
+            dsl::owner
+                .eq(owner)
+                .and(dsl::course.eq(course))
+                .and(dsl::j.eq(j)),
+        )
+        .select((dsl::marking, dsl::updated_at, dsl::updated_xid))
+        .for_update()
+        .first::<(Option<String>, NaiveDateTime, i64)>(db)
+        .optional()?;
+
+    Ok(match event {
+        None => MarkingResult::NotFound,
+        Some((marking, updated_at, updated_xid))
+            if known_xid.map_or(false, |k| updated_xid > k) =>
+        {
+            MarkingResult::Conflict {
+                marking,
+                updated_at,
+                updated_xid,
+            }
+        }
+        Some(_) => {
+            let marking = marking.filter(|m| !m.is_empty());
+
+            let (updated_at, updated_xid) = diesel::update(dsl::events.find((course, j)))
+                .set(dsl::marking.eq(&marking))
+                .returning((dsl::updated_at, dsl::updated_xid))
+                .get_result::<(NaiveDateTime, i64)>(db)?;
+
+            let marked = Marked { course, j, marking };
+            webhook::enqueue(db, owner, WebhookEvent::EventMarked, &marked)?;
+
+            MarkingResult::Applied {
+                updated_at,
+                updated_xid,
+            }
+        }
+    })
+}
+
+#[derive(serde::Deserialize)]
+pub struct BatchMarking {
+    course: Uuid,
+    j: u32,
+    marking: String,
+}
+
+/// Applies several markings at once, in a single transaction
+///
+/// Results are returned in the same order as the request items.
+#[put("/api/markings", data = "<json>")]
+pub async fn markings(
+    db: DbConn,
+    a: Account,
+    json: Json<Vec<BatchMarking>>,
+) -> ApiResult<Vec<MarkingResult>> {
+    let markings = json.into_inner();
+
+    let results = with_db!(db => {
+        db.transaction::<_, diesel::result::Error, _>(|db| {
+            markings
+                .into_iter()
+                .map(|m| apply_marking(db, a.id, m.course, m.j as i64, Some(m.marking), None))
+                .collect::<Result<Vec<_>, _>>()
+        })
+    }?);
+
+    ApiResult::Ok(results)
+}
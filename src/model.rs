@@ -1,3 +1,4 @@
+use crate::schema::courses as courses_table;
 use crate::schema::events as events_table;
 use chrono::{Duration, NaiveDate};
 use std::num::ParseIntError;
@@ -5,6 +6,30 @@ use uuid::Uuid;
 
 diesel::joinable!(crate::schema::events -> crate::schema::courses (course));
 
+#[derive(Insertable)]
+#[diesel(table_name = courses_table)]
+pub struct NewCourse {
+    pub owner: Uuid,
+    pub name: String,
+    pub description: Option<String>,
+    pub j_0: NaiveDate,
+    pub j_end: NaiveDate,
+    pub recurrence: String,
+    pub tags: Vec<String>,
+}
+
+/// Trims `tags` and drops the empty and duplicate ones
+pub fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut tags = tags
+        .into_iter()
+        .map(|t| t.trim().to_owned())
+        .filter(|t| !t.is_empty())
+        .collect::<Vec<_>>();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
 #[derive(Insertable)]
 #[diesel(table_name = events_table)]
 pub struct NewEvent {
@@ -17,13 +42,29 @@ pub struct NewEvent {
 }
 
 #[derive(Debug)]
-enum ParseRecurrenceError {
+pub enum ParseRecurrenceError {
     ParseInt(ParseIntError),
     NotAscending,
 }
 
+impl std::fmt::Display for ParseRecurrenceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseRecurrenceError::ParseInt(e) => write!(f, "invalid recurrence offset: {}", e),
+            ParseRecurrenceError::NotAscending => {
+                f.write_str("recurrence offsets must be ascending")
+            }
+        }
+    }
+}
+
 impl NewEvent {
+    /// Parses a recurrence, falling back to a single `J0` event if it is invalid
     pub fn parse_recurrence(str: &str) -> Vec<u32> {
+        Self::try_parse_recurrence(str).unwrap_or_else(|_| vec![0])
+    }
+
+    pub fn try_parse_recurrence(str: &str) -> Result<Vec<u32>, ParseRecurrenceError> {
         let mut max = -1i64;
 
         str.split(',')
@@ -40,7 +81,6 @@ impl NewEvent {
                     })
             })
             .collect::<Result<Vec<_>, _>>()
-            .unwrap_or_else(|_| vec![0])
     }
 
     pub fn from_offsets(
@@ -0,0 +1,420 @@
+use crate::email::normalize_email;
+use crate::session::ClientInfo;
+use crate::{open_session, random_token, schema, Config, DbConn, LoginError};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use isahc::AsyncReadResponseExt;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use rocket::http::{Cookie, CookieJar, RawStr, SameSite, Status};
+use rocket::response::content::RawJson;
+use rocket::response::Redirect;
+use rocket::serde::json;
+use rocket::State;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Private cookie holding the state of an authorization request until its callback
+const COOKIE_OIDC_NAME: &str = "mdj:oidc";
+
+/// OpenID Connect provider, configured under `oidc.<key>`
+///
+/// The redirect URI to register with the provider is `{public_url}login/oidc/<key>/callback`.
+/// Plain HTTP issuers are accepted, so that the flow can be tried against a local mock provider.
+#[derive(serde::Deserialize)]
+pub struct OidcProvider {
+    /// Issuer URL, without the `/.well-known/openid-configuration` suffix
+    issuer: String,
+    client_id: String,
+    /// Sent with `client_secret_post`, public clients only rely on PKCE
+    client_secret: Option<String>,
+    /// Algorithms ID tokens can be signed with, RS256 (which every provider supports) by default
+    #[serde(default = "default_algorithms")]
+    algorithms: Vec<Algorithm>,
+}
+
+fn default_algorithms() -> Vec<Algorithm> {
+    vec![Algorithm::RS256]
+}
+
+#[derive(serde::Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct AuthorizationState {
+    provider: String,
+    state: String,
+    nonce: String,
+    code_verifier: String,
+}
+
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(serde::Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    nonce: Option<String>,
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+}
+
+fn error(status: Status, body: &'static str) -> LoginError {
+    LoginError::from((status, RawJson(body)))
+}
+
+fn provider_error() -> LoginError {
+    error(Status::BadGateway, r#"{"error_kind":"oidc_provider"}"#)
+}
+
+async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T, LoginError> {
+    let mut response = isahc::get_async(url).await.map_err(|_| provider_error())?;
+
+    if !response.status().is_success() {
+        return Err(provider_error());
+    }
+
+    let body = response.text().await.map_err(|_| provider_error())?;
+    json::from_str(&body).map_err(|_| provider_error())
+}
+
+async fn discover(provider: &OidcProvider) -> Result<Discovery, LoginError> {
+    let issuer = provider.issuer.trim_end_matches('/');
+    let discovery: Discovery =
+        get_json(&format!("{}/.well-known/openid-configuration", issuer)).await?;
+
+    if discovery.issuer.trim_end_matches('/') != issuer {
+        return Err(provider_error());
+    }
+
+    Ok(discovery)
+}
+
+fn redirect_uri(config: &Config, provider: &str) -> String {
+    format!("{}login/oidc/{}/callback", config.public_url, provider)
+}
+
+fn url_encoded(pairs: &[(&str, &str)]) -> String {
+    let pairs = pairs
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, RawStr::new(v).percent_encode()));
+    pairs.collect::<Vec<_>>().join("&")
+}
+
+/// Redirects to the authorization endpoint of the provider
+#[get("/login/oidc/<provider>")]
+pub async fn oidc_login(
+    provider: &str,
+    config: &State<Config>,
+    cookies: &CookieJar<'_>,
+) -> Result<Redirect, LoginError> {
+    let oidc = match config.oidc.get(provider) {
+        Some(oidc) => oidc,
+        None => {
+            return Err(error(
+                Status::NotFound,
+                r#"{"error_kind":"oidc_unknown_provider"}"#,
+            ))
+        }
+    };
+
+    let discovery = discover(oidc).await?;
+
+    let state = AuthorizationState {
+        provider: provider.to_string(),
+        state: random_token(32),
+        nonce: random_token(32),
+        code_verifier: random_token(64),
+    };
+
+    let code_challenge = base64::encode_config(
+        Sha256::digest(state.code_verifier.as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    );
+
+    let query = url_encoded(&[
+        ("response_type", "code"),
+        ("client_id", &oidc.client_id),
+        ("redirect_uri", &redirect_uri(config, provider)),
+        ("scope", "openid email"),
+        ("state", &state.state),
+        ("nonce", &state.nonce),
+        ("code_challenge", &code_challenge),
+        ("code_challenge_method", "S256"),
+    ]);
+
+    cookies.add_private(
+        // `Lax`, as the callback is a cross-site navigation from the provider
+        Cookie::build(COOKIE_OIDC_NAME, json::to_string(&state).unwrap())
+            .same_site(SameSite::Lax)
+            .secure(config.secure_cookies())
+            .max_age(rocket::time::Duration::minutes(10))
+            .finish(),
+    );
+
+    let separator = match discovery.authorization_endpoint.contains('?') {
+        true => '&',
+        false => '?',
+    };
+
+    Ok(Redirect::to(format!(
+        "{}{}{}",
+        discovery.authorization_endpoint, separator, query
+    )))
+}
+
+/// Exchanges the authorization code and validates the returned ID token
+async fn verified_claims(
+    provider: &OidcProvider,
+    discovery: &Discovery,
+    redirect_uri: &str,
+    code: &str,
+    state: &AuthorizationState,
+) -> Result<IdTokenClaims, LoginError> {
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", redirect_uri),
+        ("client_id", &provider.client_id),
+        ("code_verifier", &state.code_verifier),
+    ];
+
+    if let Some(secret) = &provider.client_secret {
+        form.push(("client_secret", secret));
+    }
+
+    let request = isahc::Request::post(&discovery.token_endpoint)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("Accept", "application/json")
+        .body(url_encoded(&form))
+        .map_err(|_| provider_error())?;
+
+    let mut response = isahc::send_async(request)
+        .await
+        .map_err(|_| provider_error())?;
+
+    if !response.status().is_success() {
+        return Err(provider_error());
+    }
+
+    let body = response.text().await.map_err(|_| provider_error())?;
+    let tokens: TokenResponse = json::from_str(&body).map_err(|_| provider_error())?;
+
+    let invalid_token = || {
+        error(
+            Status::Unauthorized,
+            r#"{"error_kind":"oidc_invalid_id_token"}"#,
+        )
+    };
+
+    let header = jsonwebtoken::decode_header(&tokens.id_token).map_err(|_| invalid_token())?;
+    let jwks: JwkSet = get_json(&discovery.jwks_uri).await?;
+
+    // The header is chosen by whoever made the token, its algorithm is only trusted if the
+    // provider is configured with it and the key allows it
+    if !provider.algorithms.contains(&header.alg) {
+        return Err(invalid_token());
+    }
+
+    // Without a `kid`, the key can only be told if the provider has a single one
+    let jwk = match (&header.kid, jwks.keys.as_slice()) {
+        (Some(kid), _) => jwks.find(kid),
+        (None, [jwk]) => Some(jwk),
+        (None, _) => None,
+    };
+
+    let jwk = jwk.ok_or_else(invalid_token)?;
+    if jwk.common.algorithm.map_or(false, |alg| alg != header.alg) {
+        return Err(invalid_token());
+    }
+
+    let key = DecodingKey::from_jwk(jwk).map_err(|_| invalid_token())?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_audience(&[&provider.client_id]);
+    validation.set_issuer(&[&discovery.issuer]);
+
+    let claims = jsonwebtoken::decode::<IdTokenClaims>(&tokens.id_token, &key, &validation)
+        .map_err(|_| invalid_token())?
+        .claims;
+
+    if claims.nonce.as_deref() != Some(state.nonce.as_str()) {
+        return Err(invalid_token());
+    }
+
+    Ok(claims)
+}
+
+/// Removes every way into an account whose email address was never verified, before linking it to
+/// an identity proving the address belongs to someone else
+///
+/// Otherwise, anyone could register the address of a future user and keep access to the account
+/// once that user logs in with their provider.
+fn take_over(db: &mut PgConnection, account: Uuid) -> QueryResult<()> {
+    use schema::accounts::dsl as a_dsl;
+
+    diesel::update(a_dsl::accounts.find(account))
+        .set((
+            a_dsl::password.eq(None::<String>),
+            a_dsl::email_verified.eq(true),
+            a_dsl::totp_enabled.eq(false),
+            a_dsl::totp_secret.eq(None::<String>),
+            a_dsl::totp_last_step.eq(None::<i64>),
+        ))
+        .execute(db)?;
+
+    macro_rules! delete_all {
+        ($($table:ident),*) => {
+            $(
+                diesel::delete(schema::$table::table)
+                    .filter(schema::$table::account.eq(account))
+                    .execute(db)?;
+            )*
+        };
+    }
+
+    // Webhooks and push subscriptions would keep sending the data of the account elsewhere
+    delete_all!(
+        sessions,
+        pending_logins,
+        api_tokens,
+        passkeys,
+        oidc_identities,
+        totp_recovery_codes,
+        password_resets,
+        email_verifications,
+        webhooks,
+        push_subscriptions
+    );
+
+    Ok(())
+}
+
+/// Finds the account linked to an identity, linking or creating one with its email otherwise
+///
+/// An existing account whose address wasn't verified is taken over, see [`take_over`]. Returns the account and whether it has TOTP enabled.
+fn link_account(
+    db: &mut PgConnection,
+    provider: &str,
+    subject: &str,
+    email: &str,
+) -> QueryResult<(Uuid, bool)> {
+    use schema::accounts::dsl as a_dsl;
+    use schema::oidc_identities::dsl as i_dsl;
+
+    let email = normalize_email(email);
+
+    db.transaction::<_, diesel::result::Error, _>(|db| {
+        let linked = i_dsl::oidc_identities
+            .find((provider, subject))
+            .inner_join(a_dsl::accounts)
+            .select((a_dsl::id, a_dsl::totp_enabled))
+            .first::<(Uuid, bool)>(db)
+            .optional()?;
+
+        if let Some(linked) = linked {
+            return Ok(linked);
+        }
+
+        let existing = a_dsl::accounts
+            .filter(a_dsl::email.eq(&email))
+            .select((a_dsl::id, a_dsl::totp_enabled, a_dsl::email_verified))
+            .for_update()
+            .first::<(Uuid, bool, bool)>(db)
+            .optional()?;
+
+        let account = match existing {
+            Some((id, totp_enabled, true)) => (id, totp_enabled),
+            Some((id, _, false)) => {
+                take_over(db, id)?;
+                (id, false)
+            }
+            None => diesel::insert_into(a_dsl::accounts)
+                .values((a_dsl::email.eq(&email), a_dsl::email_verified.eq(true)))
+                .returning((a_dsl::id, a_dsl::totp_enabled))
+                .get_result::<(Uuid, bool)>(db)?,
+        };
+
+        diesel::insert_into(i_dsl::oidc_identities)
+            .values((
+                i_dsl::provider.eq(provider),
+                i_dsl::subject.eq(subject),
+                i_dsl::account.eq(account.0),
+            ))
+            .execute(db)?;
+
+        Ok(account)
+    })
+}
+
+/// Redirect URI of the providers, logs in with the account linked to the identity
+#[get("/login/oidc/<provider>/callback?<code>&<state>")]
+pub async fn oidc_callback(
+    db: DbConn,
+    provider: &str,
+    code: Option<&str>,
+    state: Option<&str>,
+    config: &State<Config>,
+    cookies: &CookieJar<'_>,
+    client: ClientInfo,
+) -> Result<Redirect, LoginError> {
+    let invalid_state = || error(Status::BadRequest, r#"{"error_kind":"oidc_invalid_state"}"#);
+
+    let expected = cookies
+        .get_private(COOKIE_OIDC_NAME)
+        .and_then(|c| json::from_str::<AuthorizationState>(c.value()).ok())
+        .ok_or_else(invalid_state)?;
+
+    cookies.remove_private(Cookie::named(COOKIE_OIDC_NAME));
+
+    // `code` is missing if the user denied the authorization
+    let code = code.ok_or_else(invalid_state)?;
+    if expected.provider != provider || state != Some(expected.state.as_str()) {
+        return Err(invalid_state());
+    }
+
+    let oidc = match config.oidc.get(provider) {
+        Some(oidc) => oidc,
+        None => {
+            return Err(error(
+                Status::NotFound,
+                r#"{"error_kind":"oidc_unknown_provider"}"#,
+            ))
+        }
+    };
+
+    let discovery = discover(oidc).await?;
+    let redirect_uri = redirect_uri(config, provider);
+    let claims = verified_claims(oidc, &discovery, &redirect_uri, code, &expected).await?;
+
+    let email = match claims.email {
+        Some(email) if claims.email_verified => email,
+        _ => {
+            return Err(error(
+                Status::Forbidden,
+                r#"{"error_kind":"oidc_email_not_verified"}"#,
+            ))
+        }
+    };
+
+    let database_error = |_| error(Status::InternalServerError, r#"{"error_kind":"database"}"#);
+
+    let (provider, subject) = (provider.to_string(), claims.sub);
+    let (account, totp_enabled) = with_db!(db => {
+        link_account(db, &provider, &subject, &email)
+    })
+    .map_err(database_error)?;
+
+    open_session(&db, cookies, config, account, totp_enabled, client)
+        .await
+        .map_err(database_error)
+}
@@ -0,0 +1,412 @@
+use crate::api_result::ApiResult;
+use crate::csrf::SameOrigin;
+use crate::email::normalize_email;
+use crate::rate_limit::{LoginLimiter, RateLimited};
+use crate::session::ClientInfo;
+use crate::{
+    open_session, random_token, schema, Account, Config, CookieAccount, DbConn, LoginError,
+};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
+use rocket::response::content::RawJson;
+use rocket::response::Redirect;
+use rocket::serde::json::{self, Json};
+use rocket::State;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use webauthn_rs::prelude::*;
+
+const RP_NAME: &str = "Méthode des J";
+
+/// Private cookie holding the key of an ongoing ceremony in [`Ceremonies`]
+const COOKIE_WEBAUTHN_NAME: &str = "mdj:webauthn";
+
+/// How long users have to answer a challenge
+const CEREMONY_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Registration and authentication states, kept between the two requests of a ceremony
+///
+/// They stay in memory as webauthn-rs discourages serializing them, so both requests must reach
+/// the same instance.
+pub struct Ceremonies<T> {
+    states: Mutex<HashMap<String, (T, Instant)>>,
+}
+
+impl<T> Default for Ceremonies<T> {
+    fn default() -> Self {
+        Self {
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T> Ceremonies<T> {
+    fn start(&self, cookies: &CookieJar<'_>, config: &Config, state: T) {
+        let key = random_token(32);
+
+        let mut states = self.states.lock().unwrap();
+        states.retain(|_, (_, started)| started.elapsed() < CEREMONY_TIMEOUT);
+        states.insert(key.clone(), (state, Instant::now()));
+
+        cookies.add_private(
+            Cookie::build(COOKIE_WEBAUTHN_NAME, key)
+                .same_site(SameSite::Strict)
+                .secure(config.secure_cookies())
+                .max_age(rocket::time::Duration::minutes(5))
+                .finish(),
+        );
+    }
+
+    fn take(&self, cookies: &CookieJar<'_>) -> Option<T> {
+        let key = cookies
+            .get_private(COOKIE_WEBAUTHN_NAME)?
+            .value()
+            .to_string();
+        cookies.remove_private(Cookie::named(COOKIE_WEBAUTHN_NAME));
+
+        let (state, started) = self.states.lock().unwrap().remove(&key)?;
+        Some(state).filter(|_| started.elapsed() < CEREMONY_TIMEOUT)
+    }
+}
+
+pub type Registrations = Ceremonies<(Uuid, PasskeyRegistration)>;
+/// `None` for the dummy ceremonies of emails without passkeys, see [`login_passkey_start`]
+pub type Authentications = Ceremonies<Option<(Uuid, PasskeyAuthentication)>>;
+
+/// Name of the secret dummy credential IDs are derived from, in `server_secrets`
+const DUMMY_CREDENTIAL_SECRET: &str = "passkey_dummy_credential";
+
+fn dummy_credential_secret(db: &mut PgConnection) -> QueryResult<String> {
+    use schema::server_secrets::dsl;
+
+    diesel::insert_into(dsl::server_secrets)
+        .values((
+            dsl::name.eq(DUMMY_CREDENTIAL_SECRET),
+            dsl::value.eq(random_token(64)),
+        ))
+        .on_conflict_do_nothing()
+        .execute(db)?;
+
+    dsl::server_secrets
+        .find(DUMMY_CREDENTIAL_SECRET)
+        .select(dsl::value)
+        .first(db)
+}
+
+/// Credential offered for an email without passkeys, the same on every request so that it can't
+/// be told from a real one
+fn dummy_credential(secret: &str, email: &str) -> json::Value {
+    let id = Sha256::digest(format!("{}:{}", secret, email));
+
+    json::json!({
+        "type": "public-key",
+        "id": base64::encode_config(id, base64::URL_SAFE_NO_PAD),
+    })
+}
+
+/// Relying party matching `public_url`, whose host is the RP ID
+fn webauthn(config: &Config) -> Result<Webauthn, WebauthnError> {
+    let origin = Url::parse(&config.public_url).map_err(|_| WebauthnError::Configuration)?;
+    let rp_id = origin.host_str().ok_or(WebauthnError::Configuration)?;
+
+    WebauthnBuilder::new(rp_id, &origin)?
+        .rp_name(RP_NAME)
+        .build()
+}
+
+fn credential_id(id: &CredentialID) -> String {
+    base64::encode_config(&id.0, base64::URL_SAFE_NO_PAD)
+}
+
+#[derive(Queryable, serde::Serialize)]
+pub struct PasskeyInfo {
+    id: Uuid,
+    name: String,
+    created_at: NaiveDateTime,
+    last_used_at: Option<NaiveDateTime>,
+}
+
+#[get("/api/account/passkeys")]
+pub async fn passkeys(db: DbConn, a: Account) -> ApiResult<Vec<PasskeyInfo>> {
+    let passkeys = with_db!(db => {
+        use schema::passkeys::dsl;
+
+        dsl::passkeys
+            .filter(dsl::account.eq(a.id))
+            .order_by(dsl::created_at.asc())
+            .select((dsl::id, dsl::name, dsl::created_at, dsl::last_used_at))
+            .load::<PasskeyInfo>(db)
+    }?);
+
+    ApiResult::Ok(passkeys)
+}
+
+/// First step of registering a passkey, the response is meant for `navigator.credentials.create`
+#[post("/api/account/passkeys/start")]
+pub async fn passkeys_register_start(
+    db: DbConn,
+    a: CookieAccount,
+    config: &State<Config>,
+    cookies: &CookieJar<'_>,
+    registrations: &State<Registrations>,
+) -> ApiResult<CreationChallengeResponse> {
+    let CookieAccount(a) = a;
+
+    let webauthn = match webauthn(config) {
+        Ok(webauthn) => webauthn,
+        Err(_) => return ApiResult::Error(Status::InternalServerError, "webauthn_configuration"),
+    };
+
+    let existing = with_db!(db => {
+        use schema::passkeys::dsl;
+
+        dsl::passkeys
+            .filter(dsl::account.eq(a.id))
+            .select(dsl::passkey)
+            .load::<String>(db)
+    }?);
+
+    // Authenticators refuse to register the same credential twice
+    let exclude = existing
+        .iter()
+        .filter_map(|p| json::from_str::<Passkey>(p).ok())
+        .map(|p| p.cred_id().clone())
+        .collect::<Vec<_>>();
+
+    match webauthn.start_passkey_registration(a.id, &a.email, &a.email, Some(exclude)) {
+        Ok((challenge, state)) => {
+            registrations.start(cookies, config, (a.id, state));
+            ApiResult::Ok(challenge)
+        }
+        Err(_) => ApiResult::Error(Status::InternalServerError, "webauthn"),
+    }
+}
+
+/// Second step of registering a passkey, with the result of `navigator.credentials.create`
+#[post("/api/account/passkeys/finish?<name>", data = "<json>")]
+pub async fn passkeys_register_finish(
+    db: DbConn,
+    a: CookieAccount,
+    name: Option<String>,
+    config: &State<Config>,
+    cookies: &CookieJar<'_>,
+    registrations: &State<Registrations>,
+    json: Json<RegisterPublicKeyCredential>,
+) -> ApiResult<PasskeyInfo> {
+    let CookieAccount(a) = a;
+
+    let webauthn = match webauthn(config) {
+        Ok(webauthn) => webauthn,
+        Err(_) => return ApiResult::Error(Status::InternalServerError, "webauthn_configuration"),
+    };
+
+    let state = match registrations.take(cookies) {
+        Some((account, state)) if account == a.id => state,
+        _ => return ApiResult::Error(Status::BadRequest, "no_pending_ceremony"),
+    };
+
+    let passkey = match webauthn.finish_passkey_registration(&json, &state) {
+        Ok(passkey) => passkey,
+        Err(_) => return ApiResult::Error(Status::BadRequest, "invalid_credential"),
+    };
+
+    let name = name
+        .filter(|n| !n.trim().is_empty())
+        .unwrap_or_else(|| String::from("Clé d'accès"));
+
+    let passkey_info = with_db!(db => {
+        use schema::passkeys::dsl;
+
+        diesel::insert_into(dsl::passkeys)
+            .values((
+                dsl::account.eq(a.id),
+                dsl::credential_id.eq(credential_id(passkey.cred_id())),
+                dsl::name.eq(name),
+                dsl::passkey.eq(json::to_string(&passkey).unwrap()),
+            ))
+            .returning((dsl::id, dsl::name, dsl::created_at, dsl::last_used_at))
+            .get_result::<PasskeyInfo>(db)
+    }?);
+
+    ApiResult::Ok(passkey_info)
+}
+
+#[delete("/api/account/passkeys/<id>")]
+pub async fn passkeys_delete(db: DbConn, a: CookieAccount, id: Uuid) -> ApiResult {
+    let CookieAccount(a) = a;
+
+    with_db!(db => {
+        use schema::passkeys::dsl;
+
+        diesel::delete(dsl::passkeys)
+            .filter(dsl::account.eq(a.id).and(dsl::id.eq(id)))
+            .execute(db)
+    }?);
+
+    ApiResult::success()
+}
+
+fn error(status: Status, body: &'static str) -> LoginError {
+    LoginError::from((status, RawJson(body)))
+}
+
+#[derive(serde::Deserialize)]
+pub struct PasskeyLoginStart {
+    email: String,
+}
+
+/// First step of logging in with a passkey, the response is meant for `navigator.credentials.get`
+///
+/// Emails without passkeys get a challenge for a dummy credential, so that the response doesn't
+/// tell which emails have an account.
+#[post("/login/passkey/start", data = "<json>")]
+pub async fn login_passkey_start(
+    db: DbConn,
+    config: &State<Config>,
+    cookies: &CookieJar<'_>,
+    limiter: &State<LoginLimiter>,
+    authentications: &State<Authentications>,
+    json: Json<PasskeyLoginStart>,
+    _same_origin: SameOrigin,
+) -> Result<Json<json::Value>, LoginError> {
+    let email = normalize_email(&json.into_inner().email);
+
+    if let Err(retry_after) = limiter.take(&[format!("email:{}", email)]) {
+        return Err(LoginError::RateLimited(RateLimited::new(retry_after)));
+    }
+
+    let webauthn = webauthn(config).map_err(|_| {
+        error(
+            Status::InternalServerError,
+            r#"{"error_kind":"webauthn_configuration"}"#,
+        )
+    })?;
+
+    let lookup_email = email.clone();
+    let (passkeys, secret) = with_db!(db => {
+        use schema::accounts::dsl as a_dsl;
+        use schema::passkeys::dsl as p_dsl;
+
+        let passkeys = p_dsl::passkeys
+            .inner_join(a_dsl::accounts)
+            .filter(a_dsl::email.eq(lookup_email))
+            .select((a_dsl::id, p_dsl::passkey))
+            .load::<(Uuid, String)>(db)?;
+
+        QueryResult::Ok((passkeys, dummy_credential_secret(db)?))
+    })
+    .map_err(|_| error(Status::InternalServerError, r#"{"error_kind":"database"}"#))?;
+
+    let account = passkeys.first().map(|(account, _)| *account);
+    let passkeys = passkeys
+        .iter()
+        .filter_map(|(_, p)| json::from_str::<Passkey>(p).ok())
+        .collect::<Vec<_>>();
+
+    let webauthn_error = || error(Status::InternalServerError, r#"{"error_kind":"webauthn"}"#);
+
+    let (challenge, state) = match account {
+        Some(account) if !passkeys.is_empty() => {
+            let (challenge, state) = webauthn
+                .start_passkey_authentication(&passkeys)
+                .map_err(|_| webauthn_error())?;
+            let challenge = json::to_value(&challenge).map_err(|_| webauthn_error())?;
+
+            (challenge, Some((account, state)))
+        }
+        _ => {
+            let (challenge, _) = webauthn
+                .start_discoverable_authentication()
+                .map_err(|_| webauthn_error())?;
+            let mut challenge = json::to_value(&challenge).map_err(|_| webauthn_error())?;
+            challenge["publicKey"]["allowCredentials"] =
+                json::json!([dummy_credential(&secret, &email)]);
+
+            (challenge, None)
+        }
+    };
+
+    authentications.start(cookies, config, state);
+    Ok(Json(challenge))
+}
+
+/// Second step of logging in with a passkey, with the result of `navigator.credentials.get`
+///
+/// Passkeys already are a strong factor, so the TOTP step is skipped.
+#[post("/login/passkey/finish", data = "<json>")]
+pub async fn login_passkey_finish(
+    db: DbConn,
+    config: &State<Config>,
+    cookies: &CookieJar<'_>,
+    authentications: &State<Authentications>,
+    client: ClientInfo,
+    json: Json<PublicKeyCredential>,
+    _same_origin: SameOrigin,
+) -> Result<Redirect, LoginError> {
+    let database_error = |_| error(Status::InternalServerError, r#"{"error_kind":"database"}"#);
+
+    let webauthn = webauthn(config).map_err(|_| {
+        error(
+            Status::InternalServerError,
+            r#"{"error_kind":"webauthn_configuration"}"#,
+        )
+    })?;
+
+    let invalid_credentials = || {
+        error(
+            Status::Unauthorized,
+            r#"{"error_kind":"invalid_credentials"}"#,
+        )
+    };
+
+    let (account, state) = authentications
+        .take(cookies)
+        .ok_or_else(|| {
+            error(
+                Status::BadRequest,
+                r#"{"error_kind":"no_pending_ceremony"}"#,
+            )
+        })?
+        .ok_or_else(invalid_credentials)?;
+
+    let result = webauthn
+        .finish_passkey_authentication(&json, &state)
+        .map_err(|_| invalid_credentials())?;
+
+    let credential = credential_id(result.cred_id());
+
+    with_db!(db => {
+        use schema::passkeys::dsl;
+
+        db.transaction::<_, diesel::result::Error, _>(|db| {
+            let (id, passkey) = dsl::passkeys
+                .filter(dsl::account.eq(account).and(dsl::credential_id.eq(credential)))
+                .select((dsl::id, dsl::passkey))
+                .first::<(Uuid, String)>(db)?;
+
+            // Keeps the signature counter up to date, to detect cloned authenticators
+            let mut passkey = json::from_str::<Passkey>(&passkey)
+                .map_err(|e| diesel::result::Error::DeserializationError(Box::new(e)))?;
+            passkey.update_credential(&result);
+            let passkey = json::to_string(&passkey)
+                .map_err(|e| diesel::result::Error::SerializationError(Box::new(e)))?;
+
+            diesel::update(dsl::passkeys.find(id))
+                .set((
+                    dsl::passkey.eq(passkey),
+                    dsl::last_used_at.eq(Utc::now().naive_utc()),
+                ))
+                .execute(db)
+        })
+    })
+    .map_err(database_error)?;
+
+    open_session(&db, cookies, config, account, false, client)
+        .await
+        .map_err(database_error)
+}
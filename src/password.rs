@@ -0,0 +1,257 @@
+use crate::api_result::ApiResult;
+use crate::api_token::hash_token;
+use crate::email::normalize_email;
+use crate::rate_limit::{LoginLimiter, RateLimited};
+use crate::session::ClientInfo;
+use crate::{random_token, schema, Config, CookieAccount, DbConn, COOKIE_SESSION_NAME};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use rocket::http::{CookieJar, Status};
+use rocket::serde::json::Json;
+use rocket::State;
+use uuid::Uuid;
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+pub fn hash_password(password: &str) -> Result<String, ApiResult> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(ApiResult::Error(Status::BadRequest, "weak_password"));
+    }
+
+    bcrypt::hash(password, bcrypt::DEFAULT_COST)
+        .map_err(|_| ApiResult::Error(Status::InternalServerError, "hash"))
+}
+
+#[derive(serde::Deserialize)]
+pub struct PasswordChange {
+    /// Can be omitted if the account has no password yet
+    current_password: Option<String>,
+    new_password: String,
+}
+
+/// Changes the password of the account and logs out every other session
+#[put("/api/account/password", data = "<json>")]
+pub async fn password_change(
+    db: DbConn,
+    a: CookieAccount,
+    cookies: &CookieJar<'_>,
+    json: Json<PasswordChange>,
+) -> ApiResult {
+    let CookieAccount(a) = a;
+    let PasswordChange {
+        current_password,
+        new_password,
+    } = json.into_inner();
+
+    if let Some(hash) = &a.password {
+        let current_password = current_password.unwrap_or_default();
+        if !bcrypt::verify(&current_password, hash).unwrap_or_default() {
+            return ApiResult::Error(Status::Unauthorized, "invalid_credentials");
+        }
+    }
+
+    let hash = match hash_password(&new_password) {
+        Ok(hash) => hash,
+        Err(err) => return err,
+    };
+
+    let current_session = cookies
+        .get_private(COOKIE_SESSION_NAME)
+        .map(|c| c.value().to_string())
+        .unwrap_or_default();
+
+    with_db!(db => {
+        use schema::accounts::dsl as a_dsl;
+        use schema::sessions::dsl as s_dsl;
+
+        db.transaction::<_, diesel::result::Error, _>(|db| {
+            diesel::update(a_dsl::accounts.find(a.id))
+                .set(a_dsl::password.eq(hash))
+                .execute(db)?;
+
+            diesel::delete(s_dsl::sessions)
+                .filter(s_dsl::account.eq(a.id).and(s_dsl::token.ne(current_session)))
+                .execute(db)
+        })
+    }?);
+
+    ApiResult::success()
+}
+
+/// Creates a reset token for the account with the given email, if there is one
+///
+/// Only its hash is stored, the token itself is returned to be sent to the user.
+pub fn create_reset_token(db: &mut PgConnection, email: &str) -> QueryResult<Option<String>> {
+    use schema::accounts::dsl as a_dsl;
+    use schema::password_resets::dsl as r_dsl;
+
+    let account = a_dsl::accounts
+        .filter(a_dsl::email.eq(email))
+        .select(a_dsl::id)
+        .first::<Uuid>(db)
+        .optional()?;
+
+    let account = match account {
+        Some(account) => account,
+        None => return Ok(None),
+    };
+
+    let token = random_token(64);
+
+    diesel::insert_into(r_dsl::password_resets)
+        .values((
+            r_dsl::token_hash.eq(hash_token(&token)),
+            r_dsl::account.eq(account),
+        ))
+        .execute(db)?;
+
+    Ok(Some(token))
+}
+
+pub fn reset_link(public_url: &str, token: &str) -> String {
+    format!("{}reset-password?token={}", public_url, token)
+}
+
+#[derive(serde::Deserialize)]
+pub struct PasswordResetRequest {
+    email: String,
+}
+
+/// Emails a password reset link
+///
+/// The response doesn't tell whether the email belongs to an account, and is sent before the
+/// email so that its timing doesn't either. Requests are limited like logins.
+#[post("/api/account/password/reset", data = "<json>")]
+pub async fn password_reset_request(
+    db: DbConn,
+    config: &State<Config>,
+    limiter: &State<LoginLimiter>,
+    client: ClientInfo,
+    json: Json<PasswordResetRequest>,
+) -> Result<ApiResult, RateLimited> {
+    let email = normalize_email(&json.into_inner().email);
+
+    let smtp = match &config.smtp {
+        Some(smtp) => smtp.clone(),
+        None => {
+            return Ok(ApiResult::Error(
+                Status::ServiceUnavailable,
+                "email_disabled",
+            ))
+        }
+    };
+
+    let mut keys = vec![format!("reset:{}", email)];
+    keys.extend(client.ip.map(|ip| format!("reset-ip:{}", ip)));
+
+    if let Err(retry_after) = limiter.take(&keys) {
+        return Err(RateLimited::new(retry_after));
+    }
+
+    let lookup_email = email.clone();
+    let token = match with_db!(db => { create_reset_token(db, &lookup_email) }) {
+        Ok(token) => token,
+        Err(e) => return Ok(ApiResult::from(e)),
+    };
+
+    if let Some(token) = token {
+        let body = format!(
+            "Bonjour,\n\nPour choisir un nouveau mot de passe, ouvrez ce lien dans l'heure : \
+             {}\n\nSi vous n'êtes pas à l'origine de cette demande, ignorez cet email.\n",
+            reset_link(&config.public_url, &token),
+        );
+
+        rocket::tokio::spawn(async move {
+            let subject = "Méthode des J : mot de passe oublié";
+            if let Err(e) = smtp.send_async(email.clone(), subject, body).await {
+                eprintln!("couldn't send password reset email to {}: {}", email, e);
+            }
+        });
+    }
+
+    Ok(ApiResult::success())
+}
+
+#[derive(serde::Deserialize)]
+pub struct PasswordReset {
+    token: String,
+    new_password: String,
+}
+
+/// Sets a new password with a reset token, and logs out every session
+///
+/// Every reset token of the account is used up, along with the logins waiting for their TOTP code.
+#[post("/api/account/password/reset/confirm", data = "<json>")]
+pub async fn password_reset(db: DbConn, json: Json<PasswordReset>) -> ApiResult {
+    let PasswordReset {
+        token,
+        new_password,
+    } = json.into_inner();
+
+    let hash = match hash_password(&new_password) {
+        Ok(hash) => hash,
+        Err(err) => return err,
+    };
+
+    let reset = with_db!(db => {
+        use schema::accounts::dsl as a_dsl;
+        use schema::password_resets::dsl as r_dsl;
+        use schema::pending_logins::dsl as p_dsl;
+        use schema::sessions::dsl as s_dsl;
+
+        db.transaction::<_, diesel::result::Error, _>(|db| {
+            let account = diesel::delete(r_dsl::password_resets)
+                .filter(r_dsl::token_hash.eq(hash_token(&token)))
+                .filter(r_dsl::expires_at.gt(diesel::dsl::now))
+                .returning(r_dsl::account)
+                .get_result::<Uuid>(db)
+                .optional()?;
+
+            if let Some(account) = account {
+                diesel::update(a_dsl::accounts.find(account))
+                    .set(a_dsl::password.eq(hash))
+                    .execute(db)?;
+
+                diesel::delete(s_dsl::sessions.filter(s_dsl::account.eq(account))).execute(db)?;
+                diesel::delete(p_dsl::pending_logins.filter(p_dsl::account.eq(account)))
+                    .execute(db)?;
+                diesel::delete(r_dsl::password_resets.filter(r_dsl::account.eq(account)))
+                    .execute(db)?;
+            }
+
+            Ok(account.is_some())
+        })
+    }?);
+
+    match reset {
+        true => ApiResult::success(),
+        false => ApiResult::Error(Status::BadRequest, "invalid_token"),
+    }
+}
+
+/// `mdj reset-password <email>`: prints a reset link, for when emails aren't configured
+pub fn reset_password_cli(email: Option<String>) -> ! {
+    let email = email.unwrap_or_else(|| {
+        eprintln!("usage: mdj reset-password <email>");
+        std::process::exit(2);
+    });
+
+    let figment = rocket::Config::figment();
+    let config = figment.extract::<Config>().expect("invalid configuration");
+    let url = figment
+        .extract_inner::<String>("databases.mdj.url")
+        .expect("no database configured");
+
+    let mut db = PgConnection::establish(&url).expect("couldn't connect to the database");
+
+    match create_reset_token(&mut db, &email).expect("couldn't create the reset token") {
+        Some(token) => {
+            println!("{}", reset_link(&config.public_url, &token));
+            std::process::exit(0);
+        }
+        None => {
+            eprintln!("no account with email {}", email);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,328 @@
+use crate::api_result::ApiResult;
+use crate::{schema, schema_ext, Account, Config, DbConn, EventAndCourse};
+use chrono::{Duration, NaiveDate, NaiveTime, Utc};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::serde::json::{self, Json};
+use std::error::Error;
+use uuid::Uuid;
+use web_push::{
+    ContentEncoding, IsahcWebPushClient, SubscriptionInfo, VapidSignatureBuilder, WebPushClient,
+    WebPushError, WebPushMessageBuilder,
+};
+
+const VAPID_PRIVATE_KEY: &str = "vapid_private_key";
+
+/// Returns the VAPID private key of the server, generating it on first use
+///
+/// It is stored in the database as push services tie subscriptions to the matching public key.
+fn vapid_private_key(db: &mut PgConnection) -> QueryResult<String> {
+    use schema::server_secrets::dsl;
+
+    let key = p256::SecretKey::random(&mut rand::rngs::OsRng);
+    let key = base64::encode_config(key.to_be_bytes(), base64::URL_SAFE_NO_PAD);
+
+    diesel::insert_into(dsl::server_secrets)
+        .values((dsl::name.eq(VAPID_PRIVATE_KEY), dsl::value.eq(key)))
+        .on_conflict_do_nothing()
+        .execute(db)?;
+
+    dsl::server_secrets
+        .find(VAPID_PRIVATE_KEY)
+        .select(dsl::value)
+        .first(db)
+}
+
+#[derive(serde::Serialize)]
+pub struct PushKey {
+    /// `applicationServerKey` to subscribe with, URL-safe base64
+    public_key: String,
+}
+
+#[get("/api/push/key")]
+pub async fn push_key(db: DbConn, _a: Account) -> ApiResult<PushKey> {
+    let private_key = with_db!(db => {
+        vapid_private_key(db)
+    }?);
+
+    let public_key =
+        VapidSignatureBuilder::from_base64_no_sub(&private_key, base64::URL_SAFE_NO_PAD)
+            .map(|builder| builder.get_public_key())
+            .expect("invalid VAPID private key in database");
+
+    ApiResult::Ok(PushKey {
+        public_key: base64::encode_config(public_key, base64::URL_SAFE_NO_PAD),
+    })
+}
+
+#[derive(serde::Deserialize)]
+pub struct PushSubscriptionKeys {
+    p256dh: String,
+    auth: String,
+}
+
+/// JSON form of a browser `PushSubscription`
+#[derive(serde::Deserialize)]
+pub struct PushSubscription {
+    endpoint: String,
+    keys: PushSubscriptionKeys,
+}
+
+#[post("/api/push/subscriptions", data = "<json>")]
+pub async fn push_subscribe(db: DbConn, a: Account, json: Json<PushSubscription>) -> ApiResult {
+    let subscription = json.into_inner();
+
+    let subscribed = with_db!(db => {
+        use schema::push_subscriptions::dsl;
+
+        let PushSubscription { endpoint, keys } = subscription;
+        let keys = (dsl::p256dh.eq(keys.p256dh), dsl::auth.eq(keys.auth));
+
+        let inserted = diesel::insert_into(dsl::push_subscriptions)
+            .values((dsl::endpoint.eq(&endpoint), dsl::account.eq(a.id), keys.clone()))
+            .on_conflict_do_nothing()
+            .execute(db)?;
+
+        // An existing endpoint is only updated by its own account, so that it can't be taken over
+        let updated = match inserted {
+            0 => diesel::update(dsl::push_subscriptions)
+                .filter(dsl::endpoint.eq(&endpoint).and(dsl::account.eq(a.id)))
+                .set(keys)
+                .execute(db)?,
+            _ => 0,
+        };
+
+        QueryResult::Ok(inserted + updated > 0)
+    }?);
+
+    match subscribed {
+        true => ApiResult::success(),
+        false => ApiResult::Error(Status::Conflict, "endpoint_taken"),
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct PushUnsubscription {
+    endpoint: String,
+}
+
+#[delete("/api/push/subscriptions", data = "<json>")]
+pub async fn push_unsubscribe(db: DbConn, a: Account, json: Json<PushUnsubscription>) -> ApiResult {
+    let endpoint = json.into_inner().endpoint;
+
+    with_db!(db => {
+        use schema::push_subscriptions::dsl;
+
+        diesel::delete(dsl::push_subscriptions)
+            .filter(dsl::account.eq(a.id).and(dsl::endpoint.eq(endpoint)))
+            .execute(db)
+    }?);
+
+    ApiResult::success()
+}
+
+/// Notification payload, displayed by the service worker of the frontend
+///
+/// Each action is a marking; clicking it should `PUT` it to `mark_url`.
+#[derive(serde::Serialize)]
+struct Notification {
+    title: String,
+    body: String,
+    tag: String,
+    actions: [NotificationAction; 2],
+    mark_url: String,
+}
+
+#[derive(serde::Serialize)]
+struct NotificationAction {
+    action: &'static str,
+    title: &'static str,
+}
+
+impl Notification {
+    fn for_event(event: &EventAndCourse) -> Self {
+        Self {
+            title: format!("{} (J{})", event.course_name, event.j),
+            body: event.course_description.clone().unwrap_or_default(),
+            tag: format!("{}-{}", event.course, event.j),
+            actions: [
+                NotificationAction {
+                    action: "green",
+                    title: "Vert",
+                },
+                NotificationAction {
+                    action: "red",
+                    title: "Rouge",
+                },
+            ],
+            mark_url: format!("/api/courses/{}/events/{}/marking", event.course, event.j),
+        }
+    }
+}
+
+struct PendingNotifications {
+    account: Uuid,
+    today: NaiveDate,
+    subscriptions: Vec<SubscriptionInfo>,
+    notifications: Vec<String>,
+}
+
+/// Collects today's unmarked revisions of the accounts whose reminder time (the digest one) has
+/// passed and that haven't been notified yet today
+fn pending_notifications(db: &mut PgConnection) -> QueryResult<Vec<PendingNotifications>> {
+    use schema::accounts::dsl as a_dsl;
+    use schema::push_subscriptions::dsl as p_dsl;
+    use schema_ext::timeline::dsl as t_dsl;
+
+    let now = Utc::now().naive_utc();
+
+    let accounts = a_dsl::accounts
+        .filter(a_dsl::id.eq_any(p_dsl::push_subscriptions.select(p_dsl::account)))
+        .select((
+            a_dsl::id,
+            a_dsl::digest_time,
+            a_dsl::digest_utc_offset,
+            a_dsl::push_last_sent,
+        ))
+        .load::<(Uuid, NaiveTime, i32, Option<NaiveDate>)>(db)?;
+
+    let mut pending = Vec::new();
+
+    for (account, time, utc_offset, last_sent) in accounts {
+        let local = now + Duration::minutes(utc_offset as i64);
+        let today = local.date();
+
+        if local.time() < time || last_sent.map_or(false, |d| d >= today) {
+            continue;
+        }
+
+        let notifications = t_dsl::timeline
+            .filter(t_dsl::course_owner.eq(account).and(t_dsl::date.eq(today)))
+            .filter(t_dsl::marking.is_null())
+            .load::<EventAndCourse>(db)?
+            .iter()
+            .map(|event| json::to_string(&Notification::for_event(event)).unwrap())
+            .collect();
+
+        let subscriptions = p_dsl::push_subscriptions
+            .filter(p_dsl::account.eq(account))
+            .select((p_dsl::endpoint, p_dsl::p256dh, p_dsl::auth))
+            .load::<(String, String, String)>(db)?
+            .into_iter()
+            .map(|(endpoint, p256dh, auth)| SubscriptionInfo::new(endpoint, p256dh, auth))
+            .collect();
+
+        pending.push(PendingNotifications {
+            account,
+            today,
+            subscriptions,
+            notifications,
+        });
+    }
+
+    Ok(pending)
+}
+
+async fn send_notification(
+    client: &IsahcWebPushClient,
+    private_key: &str,
+    contact: &str,
+    subscription: &SubscriptionInfo,
+    payload: &str,
+) -> Result<(), WebPushError> {
+    let mut signature =
+        VapidSignatureBuilder::from_base64(private_key, base64::URL_SAFE_NO_PAD, subscription)?;
+
+    // Contact of the sender, required by some push services
+    signature.add_claim("sub", contact);
+    let signature = signature.build()?;
+
+    let mut builder = WebPushMessageBuilder::new(subscription)?;
+    builder.set_payload(ContentEncoding::Aes128Gcm, payload.as_bytes());
+    builder.set_vapid_signature(signature);
+
+    client.send(builder.build()?).await
+}
+
+/// Notifies every subscription of due revisions, forgetting the ones the push service rejects
+///
+/// `contact` is the `sub` claim of the VAPID signatures, the `public_url` of the server.
+pub async fn send_due_notifications(
+    db: &DbConn,
+    client: &IsahcWebPushClient,
+    contact: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (private_key, pending) = db
+        .run(|db| QueryResult::Ok((vapid_private_key(db)?, pending_notifications(db)?)))
+        .await?;
+
+    for pending in pending {
+        let mut expired = Vec::new();
+
+        for subscription in &pending.subscriptions {
+            for notification in &pending.notifications {
+                let sent =
+                    send_notification(client, &private_key, contact, subscription, notification);
+
+                match sent.await {
+                    Ok(()) => {}
+                    Err(WebPushError::EndpointNotValid) | Err(WebPushError::EndpointNotFound) => {
+                        expired.push(subscription.endpoint.clone());
+                        break;
+                    }
+                    Err(e) => eprintln!("couldn't send push notification: {}", e),
+                }
+            }
+        }
+
+        let PendingNotifications { account, today, .. } = pending;
+
+        db.run(move |db| {
+            use schema::accounts::dsl as a_dsl;
+            use schema::push_subscriptions::dsl as p_dsl;
+
+            diesel::delete(p_dsl::push_subscriptions.filter(p_dsl::endpoint.eq_any(expired)))
+                .execute(db)?;
+
+            diesel::update(a_dsl::accounts.find(account))
+                .set(a_dsl::push_last_sent.eq(today))
+                .execute(db)
+        })
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Checks every minute for revisions to notify of
+pub fn scheduler() -> AdHoc {
+    AdHoc::on_liftoff("push notification scheduler", |rocket| {
+        Box::pin(async move {
+            let contact = match rocket.state::<Config>() {
+                Some(config) => config.public_url.trim_end_matches('/').to_string(),
+                None => return,
+            };
+
+            let conn = DbConn::get_one(rocket)
+                .await
+                .expect("no database available for sending push notifications");
+
+            let client = IsahcWebPushClient::new().expect("couldn't create the web push client");
+
+            rocket::tokio::spawn(async move {
+                let mut interval =
+                    rocket::tokio::time::interval(std::time::Duration::from_secs(60));
+
+                loop {
+                    interval.tick().await;
+
+                    if let Err(e) = send_due_notifications(&conn, &client, &contact).await {
+                        eprintln!("couldn't send push notifications: {}", e);
+                    }
+                }
+            });
+        })
+    })
+}
@@ -0,0 +1,215 @@
+use crate::{schema, DbConn};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::pg::PgExpressionMethods;
+use diesel::prelude::*;
+use diesel::PgConnection;
+use rocket::fairing::AdHoc;
+use rocket::http::Header;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Login attempts that can be made in a burst, per IP and per email
+const BUCKET_CAPACITY: f64 = 10.0;
+/// One more attempt is allowed every 30 seconds
+const BUCKET_REFILL_PER_SECOND: f64 = 1.0 / 30.0;
+/// Buckets are pruned once there are this many of them
+const MAX_BUCKETS: usize = 10_000;
+
+/// Consecutive failed logins after which an email is locked out for the IP they came from, so that
+/// failures from elsewhere can't lock its owner out
+const LOCKOUT_FAILURES: i64 = 5;
+const LOCKOUT_MINUTES: i64 = 15;
+
+/// Login attempts are kept this long for auditing, then deleted by [`scheduler`]
+const ATTEMPTS_RETENTION_DAYS: i64 = 90;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * BUCKET_REFILL_PER_SECOND).min(BUCKET_CAPACITY);
+        self.updated = now;
+    }
+}
+
+/// In-memory token buckets limiting login attempts
+///
+/// They are lost on restart and aren't shared between instances, the lockout stored in the
+/// database covers that.
+#[derive(Default)]
+pub struct LoginLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl LoginLimiter {
+    /// Takes a token from the bucket of every key, or returns how many seconds to wait before one of
+    /// them is refilled
+    pub fn take(&self, keys: &[String]) -> Result<(), i64> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, bucket| {
+                bucket.refill(now);
+                bucket.tokens < BUCKET_CAPACITY
+            });
+        }
+
+        let mut retry_after = 0;
+
+        for key in keys {
+            let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+                tokens: BUCKET_CAPACITY,
+                updated: now,
+            });
+            bucket.refill(now);
+
+            if bucket.tokens < 1.0 {
+                let wait = (1.0 - bucket.tokens) / BUCKET_REFILL_PER_SECOND;
+                retry_after = retry_after.max(wait.ceil() as i64);
+            }
+        }
+
+        if retry_after > 0 {
+            return Err(retry_after);
+        }
+
+        for key in keys {
+            buckets.get_mut(key).unwrap().tokens -= 1.0;
+        }
+
+        Ok(())
+    }
+}
+
+pub enum LoginOutcome {
+    Success,
+    InvalidCredentials,
+    RateLimited,
+}
+
+impl LoginOutcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LoginOutcome::Success => "success",
+            LoginOutcome::InvalidCredentials => "invalid_credentials",
+            LoginOutcome::RateLimited => "rate_limited",
+        }
+    }
+}
+
+/// Adds a login attempt to the audit log
+pub fn record_attempt(
+    db: &mut PgConnection,
+    email: &str,
+    ip: Option<String>,
+    outcome: LoginOutcome,
+) -> QueryResult<()> {
+    use schema::login_attempts::dsl;
+
+    diesel::insert_into(dsl::login_attempts)
+        .values((
+            dsl::email.eq(email),
+            dsl::ip.eq(ip),
+            dsl::outcome.eq(outcome.as_str()),
+        ))
+        .execute(db)?;
+
+    Ok(())
+}
+
+/// Returns the remaining lockout of `email` from `ip` in seconds, if it had too many failed logins
+/// from there since its last successful one
+pub fn lockout(db: &mut PgConnection, email: &str, ip: Option<&str>) -> QueryResult<Option<i64>> {
+    use schema::login_attempts::dsl;
+
+    // `created_at` defaults to the database's clock
+    let now = diesel::select(diesel::dsl::now).get_result::<NaiveDateTime>(db)?;
+
+    let last_success = dsl::login_attempts
+        .filter(dsl::email.eq(email).and(dsl::ip.is_not_distinct_from(ip)))
+        .filter(dsl::outcome.eq("success"))
+        .select(diesel::dsl::max(dsl::created_at))
+        .first::<Option<NaiveDateTime>>(db)?;
+
+    let window_start = now - Duration::minutes(LOCKOUT_MINUTES);
+    let since = last_success.map_or(window_start, |s| s.max(window_start));
+
+    let failures = dsl::login_attempts
+        .filter(
+            dsl::email
+                .eq(email)
+                .and(dsl::ip.is_not_distinct_from(ip))
+                .and(dsl::outcome.eq("invalid_credentials")),
+        )
+        .filter(dsl::created_at.gt(since))
+        .order_by(dsl::created_at.desc())
+        .limit(LOCKOUT_FAILURES)
+        .select(dsl::created_at)
+        .load::<NaiveDateTime>(db)?;
+
+    if (failures.len() as i64) < LOCKOUT_FAILURES {
+        return Ok(None);
+    }
+
+    // The lockout ends when the oldest of the last failures leaves the window
+    let ends = failures[failures.len() - 1] + Duration::minutes(LOCKOUT_MINUTES);
+    Ok(Some((ends - now).num_seconds().max(1)))
+}
+
+/// `429 Too Many Requests` response to `login`
+#[derive(Responder)]
+#[response(status = 429, content_type = "json")]
+pub struct RateLimited {
+    body: &'static str,
+    retry_after: Header<'static>,
+}
+
+impl RateLimited {
+    pub fn new(retry_after: i64) -> Self {
+        Self {
+            body: r#"{"error_kind":"rate_limited"}"#,
+            retry_after: Header::new("Retry-After", retry_after.to_string()),
+        }
+    }
+}
+
+/// Deletes the login attempts older than [`ATTEMPTS_RETENTION_DAYS`] every day
+pub fn scheduler() -> AdHoc {
+    AdHoc::on_liftoff("login attempts garbage collector", |rocket| {
+        Box::pin(async move {
+            let conn = DbConn::get_one(rocket)
+                .await
+                .expect("no database available for deleting old login attempts");
+
+            rocket::tokio::spawn(async move {
+                let mut interval =
+                    rocket::tokio::time::interval(std::time::Duration::from_secs(24 * 60 * 60));
+
+                loop {
+                    interval.tick().await;
+
+                    let deleted = conn
+                        .run(|db| {
+                            use schema::login_attempts::dsl;
+
+                            let retention = Duration::days(ATTEMPTS_RETENTION_DAYS);
+                            diesel::delete(dsl::login_attempts)
+                                .filter(dsl::created_at.lt(Utc::now().naive_utc() - retention))
+                                .execute(db)
+                        })
+                        .await;
+
+                    if let Err(e) = deleted {
+                        eprintln!("couldn't delete old login attempts: {}", e);
+                    }
+                }
+            });
+        })
+    })
+}
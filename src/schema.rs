@@ -3,6 +3,41 @@ table! {
         id -> Uuid,
         email -> Varchar,
         password -> Nullable<Varchar>,
+        digest_enabled -> Bool,
+        digest_time -> Time,
+        digest_utc_offset -> Int4,
+        digest_last_sent -> Nullable<Date>,
+        push_last_sent -> Nullable<Date>,
+        email_verified -> Bool,
+        totp_secret -> Nullable<Varchar>,
+        totp_enabled -> Bool,
+        totp_last_step -> Nullable<Int8>,
+        ical_token_hash -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    api_tokens (id) {
+        id -> Uuid,
+        account -> Uuid,
+        name -> Varchar,
+        token_hash -> Varchar,
+        read_only -> Bool,
+        created_at -> Timestamp,
+        expires_at -> Nullable<Timestamp>,
+        last_used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    course_shares (id) {
+        id -> Uuid,
+        course -> Uuid,
+        email -> Varchar,
+        mode -> Varchar,
+        account -> Nullable<Uuid>,
+        created_at -> Timestamp,
+        accepted_at -> Nullable<Timestamp>,
     }
 }
 
@@ -17,6 +52,29 @@ table! {
         recurrence -> Varchar,
         cache_key -> Uuid,
         archived -> Bool,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        updated_xid -> Int8,
+        share -> Nullable<Uuid>,
+        tags -> Array<Varchar>,
+        sequence -> Int4,
+    }
+}
+
+table! {
+    digest_tokens (token_hash) {
+        token_hash -> Varchar,
+        account -> Uuid,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    email_verifications (token_hash) {
+        token_hash -> Varchar,
+        account -> Uuid,
+        email -> Varchar,
+        expires_at -> Timestamp,
     }
 }
 
@@ -28,6 +86,76 @@ table! {
         marking -> Nullable<Varchar>,
         date -> Date,
         cache_key -> Nullable<Uuid>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        updated_xid -> Int8,
+        sequence -> Int4,
+    }
+}
+
+table! {
+    login_attempts (id) {
+        id -> Int8,
+        email -> Varchar,
+        ip -> Nullable<Varchar>,
+        outcome -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    oidc_identities (provider, subject) {
+        provider -> Varchar,
+        subject -> Varchar,
+        account -> Uuid,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    passkeys (id) {
+        id -> Uuid,
+        account -> Uuid,
+        credential_id -> Varchar,
+        name -> Varchar,
+        passkey -> Varchar,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    password_resets (token_hash) {
+        token_hash -> Varchar,
+        account -> Uuid,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    pending_logins (token_hash) {
+        token_hash -> Varchar,
+        account -> Uuid,
+        user_agent -> Nullable<Varchar>,
+        ip -> Nullable<Varchar>,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    push_subscriptions (endpoint) {
+        endpoint -> Varchar,
+        account -> Uuid,
+        p256dh -> Varchar,
+        auth -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    server_secrets (name) {
+        name -> Varchar,
+        value -> Varchar,
     }
 }
 
@@ -36,16 +164,104 @@ table! {
         token -> Varchar,
         account -> Uuid,
         expires -> Timestamp,
+        id -> Uuid,
+        user_agent -> Nullable<Varchar>,
+        ip -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        last_seen_at -> Timestamp,
+    }
+}
+
+table! {
+    tombstones (id) {
+        id -> Int8,
+        owner -> Uuid,
+        course -> Uuid,
+        j -> Nullable<Int8>,
+        deleted_at -> Timestamp,
+        deleted_xid -> Int8,
+    }
+}
+
+table! {
+    totp_recovery_codes (account, code_hash) {
+        account -> Uuid,
+        code_hash -> Varchar,
+    }
+}
+
+table! {
+    webhook_deliveries (id) {
+        id -> Int8,
+        webhook -> Uuid,
+        outbox_id -> Int8,
+        event_type -> Varchar,
+        attempt -> Int4,
+        status_code -> Nullable<Int4>,
+        error -> Nullable<Varchar>,
+        delivered_at -> Timestamp,
+    }
+}
+
+table! {
+    webhook_outbox (id) {
+        id -> Int8,
+        webhook -> Uuid,
+        event_type -> Varchar,
+        payload -> Varchar,
+        attempts -> Int4,
+        next_attempt_at -> Timestamp,
+    }
+}
+
+table! {
+    webhooks (id) {
+        id -> Uuid,
+        account -> Uuid,
+        url -> Varchar,
+        secret -> Varchar,
+        event_types -> Array<Varchar>,
+        created_at -> Timestamp,
     }
 }
 
+joinable!(api_tokens -> accounts (account));
+joinable!(course_shares -> accounts (account));
+joinable!(course_shares -> courses (course));
 joinable!(courses -> accounts (owner));
+joinable!(digest_tokens -> accounts (account));
+joinable!(email_verifications -> accounts (account));
 joinable!(events -> accounts (owner));
+joinable!(oidc_identities -> accounts (account));
+joinable!(passkeys -> accounts (account));
+joinable!(password_resets -> accounts (account));
+joinable!(pending_logins -> accounts (account));
+joinable!(push_subscriptions -> accounts (account));
 joinable!(sessions -> accounts (account));
+joinable!(totp_recovery_codes -> accounts (account));
+joinable!(webhook_deliveries -> webhooks (webhook));
+joinable!(webhook_outbox -> webhooks (webhook));
+joinable!(webhooks -> accounts (account));
 
 allow_tables_to_appear_in_same_query!(
     accounts,
+    api_tokens,
+    course_shares,
     courses,
+    digest_tokens,
+    email_verifications,
     events,
+    login_attempts,
+    oidc_identities,
+    passkeys,
+    password_resets,
+    pending_logins,
+    push_subscriptions,
+    server_secrets,
     sessions,
+    tombstones,
+    totp_recovery_codes,
+    webhook_deliveries,
+    webhook_outbox,
+    webhooks,
 );
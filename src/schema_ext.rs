@@ -10,5 +10,7 @@ table! {
         previous_marking -> Nullable<Varchar>,
         date -> Date,
         cache_key -> Nullable<Uuid>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
     }
 }
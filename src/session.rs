@@ -0,0 +1,222 @@
+use crate::api_result::ApiResult;
+use crate::{random_token, schema, Account, Config, DbConn, COOKIE_SESSION_NAME};
+use chrono::{NaiveDateTime, Utc};
+use diesel::dsl::{now, IntervalDsl};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use rocket::fairing::AdHoc;
+use rocket::http::{Cookie, CookieJar, SameSite};
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use uuid::Uuid;
+
+/// Device details recorded along new sessions
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientInfo {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let remote = request.remote().map(|r| r.ip());
+
+        // `X-Real-IP` can be set by anyone, it is only trusted when set by a known proxy
+        let trusted = request.rocket().state::<Config>().map_or(false, |c| {
+            remote.map_or(false, |r| c.trusted_proxies.contains(&r))
+        });
+
+        let ip = match trusted {
+            true => request.real_ip().or(remote),
+            false => remote,
+        };
+
+        Outcome::Success(ClientInfo {
+            user_agent: request.headers().get_one("User-Agent").map(String::from),
+            ip: ip.map(|ip| ip.to_string()),
+        })
+    }
+}
+
+/// Creates a session for `account`, returning its token and expiration time
+pub fn create(
+    db: &mut PgConnection,
+    account: Uuid,
+    client: ClientInfo,
+) -> QueryResult<(String, NaiveDateTime)> {
+    use schema::sessions::dsl;
+
+    let token = random_token(64);
+
+    let expires = diesel::insert_into(dsl::sessions)
+        .values((
+            dsl::token.eq(&token),
+            dsl::account.eq(account),
+            dsl::user_agent.eq(client.user_agent),
+            dsl::ip.eq(client.ip),
+        ))
+        .returning(dsl::expires)
+        .get_result::<NaiveDateTime>(db)?;
+
+    Ok((token, expires))
+}
+
+/// Hands a session created by [`create`] to the client
+pub fn set_cookie(cookies: &CookieJar<'_>, config: &Config, token: String, expires: NaiveDateTime) {
+    let max_age = (expires - Utc::now().naive_utc()).num_seconds();
+
+    cookies.add_private(
+        Cookie::build(COOKIE_SESSION_NAME, token)
+            .same_site(SameSite::Strict)
+            .secure(config.secure_cookies())
+            .max_age(rocket::time::Duration::seconds(max_age))
+            .finish(),
+    );
+}
+
+/// Updates the last-seen time of a session, unless it was already done in the last minute or the
+/// session expired
+///
+/// Like the defaults of the `sessions` table, this uses the database clock.
+pub fn touch(db: &mut PgConnection, token: &str) -> QueryResult<()> {
+    use schema::sessions::dsl;
+
+    diesel::update(dsl::sessions.find(token))
+        .filter(dsl::expires.gt(now))
+        .filter(dsl::last_seen_at.lt(now - 1.minutes()))
+        .set(dsl::last_seen_at.eq(now))
+        .execute(db)?;
+
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+pub struct SessionInfo {
+    id: Uuid,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    created_at: NaiveDateTime,
+    last_seen_at: NaiveDateTime,
+    expires: NaiveDateTime,
+    /// Whether this is the session making the request
+    current: bool,
+}
+
+/// `SessionInfo` fields, followed by the session token
+type SessionRow = (
+    Uuid,
+    Option<String>,
+    Option<String>,
+    NaiveDateTime,
+    NaiveDateTime,
+    NaiveDateTime,
+    String,
+);
+
+fn current_session(cookies: &CookieJar<'_>) -> Option<String> {
+    cookies
+        .get_private(COOKIE_SESSION_NAME)
+        .map(|c| c.value().to_string())
+}
+
+#[get("/api/account/sessions")]
+pub async fn sessions(
+    db: DbConn,
+    a: Account,
+    cookies: &CookieJar<'_>,
+) -> ApiResult<Vec<SessionInfo>> {
+    let current = current_session(cookies);
+
+    let sessions = with_db!(db => {
+        use schema::sessions::dsl;
+
+        dsl::sessions
+            .filter(dsl::account.eq(a.id))
+            .filter(dsl::expires.gt(now))
+            .order_by(dsl::last_seen_at.desc())
+            .select((
+                dsl::id,
+                dsl::user_agent,
+                dsl::ip,
+                dsl::created_at,
+                dsl::last_seen_at,
+                dsl::expires,
+                dsl::token,
+            ))
+            .load::<SessionRow>(db)
+    }?);
+
+    let sessions = sessions
+        .into_iter()
+        .map(
+            |(id, user_agent, ip, created_at, last_seen_at, expires, token)| SessionInfo {
+                id,
+                user_agent,
+                ip,
+                created_at,
+                last_seen_at,
+                expires,
+                current: current.as_ref() == Some(&token),
+            },
+        )
+        .collect();
+
+    ApiResult::Ok(sessions)
+}
+
+#[delete("/api/account/sessions/<id>")]
+pub async fn sessions_delete(db: DbConn, a: Account, id: Uuid) -> ApiResult {
+    with_db!(db => {
+        use schema::sessions::dsl;
+
+        diesel::delete(dsl::sessions)
+            .filter(dsl::account.eq(a.id).and(dsl::id.eq(id)))
+            .execute(db)
+    }?);
+
+    ApiResult::success()
+}
+
+/// Logs out everywhere, including the session making the request
+#[delete("/api/account/sessions")]
+pub async fn sessions_delete_all(db: DbConn, a: Account, cookies: &CookieJar<'_>) -> ApiResult {
+    with_db!(db => {
+        use schema::sessions::dsl;
+
+        diesel::delete(dsl::sessions.filter(dsl::account.eq(a.id))).execute(db)
+    }?);
+
+    cookies.remove_private(Cookie::named(COOKIE_SESSION_NAME));
+
+    ApiResult::success()
+}
+
+/// Deletes expired sessions and pending TOTP logins every hour
+pub fn scheduler() -> AdHoc {
+    AdHoc::on_liftoff("session garbage collector", |rocket| {
+        Box::pin(async move {
+            let conn = DbConn::get_one(rocket)
+                .await
+                .expect("no database available for deleting expired sessions");
+
+            rocket::tokio::spawn(async move {
+                let mut interval =
+                    rocket::tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+
+                loop {
+                    interval.tick().await;
+
+                    let deleted = conn
+                        .run(|db| diesel::sql_query("select expiration_gc()").execute(db))
+                        .await;
+
+                    if let Err(e) = deleted {
+                        eprintln!("couldn't delete expired sessions: {}", e);
+                    }
+                }
+            });
+        })
+    })
+}
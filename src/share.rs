@@ -0,0 +1,376 @@
+use crate::api_result::ApiResult;
+use crate::email::normalize_email;
+use crate::model::{NewCourse, NewEvent};
+use crate::webhook::{self, WebhookEvent};
+use crate::{insert_course, schema, Account, Config, Course, CourseAndOccurrences, DbConn};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel::PgConnection;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use uuid::Uuid;
+
+/// The recipient's course follows the original: its name, description and schedule are kept in
+/// sync and can't be edited
+const MODE_READ_ONLY: &str = "read_only";
+
+/// The recipient gets an independent copy of the course as it is when accepting
+const MODE_COPY: &str = "copy";
+
+/// Whether `course` of `owner` follows a read-only share, in which case only its events can be
+/// modified
+///
+/// The course is locked until the end of the transaction, so that it can't start or stop following
+/// a share before it is modified.
+pub fn is_following(db: &mut PgConnection, owner: Uuid, course: Uuid) -> QueryResult<bool> {
+    use schema::courses::dsl;
+
+    let share = dsl::courses
+        .filter(dsl::owner.eq(owner).and(dsl::id.eq(course)))
+        .select(dsl::share)
+        .for_update()
+        .first::<Option<Uuid>>(db)
+        .optional()?;
+
+    Ok(matches!(share, Some(Some(_))))
+}
+
+/// Copies the name, description and schedule of `course` to the courses following its read-only
+/// shares, regenerating their events if the schedule changed
+///
+/// Like any other course, followers lose their markings when their events are regenerated.
+pub fn sync_followers(db: &mut PgConnection, course: &CourseAndOccurrences) -> QueryResult<()> {
+    use schema::course_shares::dsl as s_dsl;
+    use schema::courses::dsl as c_dsl;
+    use schema::events::dsl as e_dsl;
+
+    let shares = s_dsl::course_shares
+        .filter(s_dsl::course.eq(course.id))
+        .select(s_dsl::id.nullable());
+
+    let followers = c_dsl::courses
+        .filter(c_dsl::share.eq_any(shares))
+        .load::<Course>(db)?;
+
+    for follower in followers {
+        diesel::update(c_dsl::courses.find(follower.id))
+            .set((
+                c_dsl::name.eq(&course.name),
+                c_dsl::description.eq(&course.description),
+                c_dsl::tags.eq(&course.tags),
+            ))
+            .execute(db)?;
+
+        let rescheduled = follower.j_0 != course.j_0
+            || follower.j_end != course.j_end
+            || follower.recurrence != course.recurrence;
+
+        if rescheduled {
+            let offsets = NewEvent::parse_recurrence(&course.recurrence);
+            let cache_key = Uuid::new_v4();
+
+            diesel::update(c_dsl::courses.find(follower.id))
+                .set((
+                    c_dsl::recurrence.eq(&course.recurrence),
+                    c_dsl::cache_key.eq(cache_key),
+                    c_dsl::j_0.eq(course.j_0),
+                    c_dsl::j_end.eq(course.j_end),
+                ))
+                .execute(db)?;
+
+            let events = NewEvent::from_offsets(
+                &offsets,
+                follower.owner,
+                follower.id,
+                course.j_0,
+                course.j_end,
+                cache_key,
+            );
+
+            diesel::insert_into(e_dsl::events)
+                .values(events)
+                .execute(db)?;
+        }
+
+        let follower = CourseAndOccurrences::load(db, follower.owner, follower.id)?;
+        webhook::enqueue(db, follower.owner, WebhookEvent::CourseUpdated, &follower)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Queryable, serde::Serialize)]
+pub struct CourseShare {
+    id: Uuid,
+    email: String,
+    mode: String,
+    created_at: NaiveDateTime,
+    accepted_at: Option<NaiveDateTime>,
+}
+
+/// Shares of one of the account's courses
+#[get("/api/courses/<id>/shares")]
+pub async fn shares(db: DbConn, a: Account, id: Uuid) -> ApiResult<Vec<CourseShare>> {
+    let shares = with_db!(db => {
+        use schema::course_shares::dsl as s_dsl;
+        use schema::courses::dsl as c_dsl;
+
+        s_dsl::course_shares
+            .inner_join(c_dsl::courses)
+            .filter(c_dsl::owner.eq(a.id).and(c_dsl::id.eq(id)))
+            .order_by(s_dsl::created_at.asc())
+            .select((
+                s_dsl::id,
+                s_dsl::email,
+                s_dsl::mode,
+                s_dsl::created_at,
+                s_dsl::accepted_at,
+            ))
+            .load::<CourseShare>(db)
+    }?);
+
+    ApiResult::Ok(shares)
+}
+
+#[derive(serde::Deserialize)]
+pub struct NewShare {
+    email: String,
+    /// `read_only` or `copy`
+    mode: String,
+}
+
+/// Invites another account to a course, emailing the recipient if SMTP is configured
+#[post("/api/courses/<id>/shares", data = "<json>")]
+pub async fn shares_insert(
+    db: DbConn,
+    a: Account,
+    id: Uuid,
+    config: &State<Config>,
+    json: Json<NewShare>,
+) -> ApiResult<CourseShare> {
+    let NewShare { email, mode } = json.into_inner();
+    let email = normalize_email(&email);
+
+    if mode != MODE_READ_ONLY && mode != MODE_COPY {
+        return ApiResult::Error(Status::BadRequest, "invalid_share_mode");
+    }
+
+    if email.len() > 256 || !email.contains('@') {
+        return ApiResult::Error(Status::BadRequest, "invalid_email");
+    }
+
+    if email == a.email {
+        return ApiResult::Error(Status::BadRequest, "cannot_share_with_self");
+    }
+
+    let share = with_db!(db => {
+        use schema::course_shares::dsl as s_dsl;
+        use schema::courses::dsl as c_dsl;
+
+        db.transaction::<_, DieselError, _>(|db| {
+            // Followers are only synced with the original course, not with courses following them
+            if is_following(db, a.id, id)? {
+                return Ok(None);
+            }
+
+            let name = c_dsl::courses
+                .filter(c_dsl::owner.eq(a.id).and(c_dsl::id.eq(id)))
+                .select(c_dsl::name)
+                .first::<String>(db)?;
+
+            let share = diesel::insert_into(s_dsl::course_shares)
+                .values((
+                    s_dsl::course.eq(id),
+                    s_dsl::email.eq(&email),
+                    s_dsl::mode.eq(mode),
+                ))
+                .returning((
+                    s_dsl::id,
+                    s_dsl::email,
+                    s_dsl::mode,
+                    s_dsl::created_at,
+                    s_dsl::accepted_at,
+                ))
+                .get_result::<CourseShare>(db)?;
+
+            Ok(Some((share, name)))
+        })
+    });
+
+    let (share, name) = match share {
+        Ok(Some(share)) => share,
+        Ok(None) => return ApiResult::Error(Status::Forbidden, "shared_course_read_only"),
+        Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            return ApiResult::Error(Status::Conflict, "already_shared")
+        }
+        Err(e) => return ApiResult::from(e),
+    };
+
+    if let Some(smtp) = config.smtp.clone() {
+        let body = format!(
+            "Bonjour,\n\n{} a partagé le cours « {} » avec vous. Connectez-vous sur {} pour \
+             l'ajouter à vos cours.\n",
+            a.email, name, config.public_url,
+        );
+
+        let to = share.email.clone();
+        rocket::tokio::spawn(async move {
+            let subject = "Méthode des J : un cours a été partagé avec vous";
+            if let Err(e) = smtp.send_async(to.clone(), subject, body).await {
+                eprintln!("couldn't send share invitation to {}: {}", to, e);
+            }
+        });
+    }
+
+    ApiResult::Ok(share)
+}
+
+/// Revokes a share, a course already created from it is kept by the recipient but stops following
+/// the original
+#[delete("/api/courses/<id>/shares/<share>")]
+pub async fn shares_delete(db: DbConn, a: Account, id: Uuid, share: Uuid) -> ApiResult {
+    with_db!(db => {
+        use schema::course_shares::dsl as s_dsl;
+        use schema::courses::dsl as c_dsl;
+
+        let owned = c_dsl::courses
+            .filter(c_dsl::owner.eq(a.id))
+            .select(c_dsl::id);
+
+        diesel::delete(s_dsl::course_shares)
+            .filter(s_dsl::id.eq(share).and(s_dsl::course.eq(id)))
+            .filter(s_dsl::course.eq_any(owned))
+            .execute(db)
+    }?);
+
+    ApiResult::success()
+}
+
+#[derive(Queryable, serde::Serialize)]
+pub struct Invitation {
+    id: Uuid,
+    course_name: String,
+    owner_email: String,
+    mode: String,
+    created_at: NaiveDateTime,
+    accepted_at: Option<NaiveDateTime>,
+}
+
+/// Courses shared with the account
+#[get("/api/shares")]
+pub async fn invitations(db: DbConn, a: Account) -> ApiResult<Vec<Invitation>> {
+    let invitations = with_db!(db => {
+        use schema::accounts::dsl as a_dsl;
+        use schema::course_shares::dsl as s_dsl;
+        use schema::courses::dsl as c_dsl;
+
+        s_dsl::course_shares
+            .inner_join(c_dsl::courses.inner_join(a_dsl::accounts))
+            .filter(
+                s_dsl::email
+                    .eq(a.email.clone())
+                    .or(s_dsl::account.assume_not_null().eq(a.id)),
+            )
+            .order_by(s_dsl::created_at.desc())
+            .select((
+                s_dsl::id,
+                c_dsl::name,
+                a_dsl::email,
+                s_dsl::mode,
+                s_dsl::created_at,
+                s_dsl::accepted_at,
+            ))
+            .load::<Invitation>(db)
+    }?);
+
+    ApiResult::Ok(invitations)
+}
+
+/// Accepts an invitation, creating the account's own course with its own events
+#[post("/api/shares/<id>/accept")]
+pub async fn invitations_accept(
+    db: DbConn,
+    a: Account,
+    id: Uuid,
+) -> ApiResult<CourseAndOccurrences> {
+    // Invitations are addressed by email, which must then belong to the account
+    if !a.email_verified {
+        return ApiResult::Error(Status::Forbidden, "email_not_verified");
+    }
+
+    let course = with_db!(db => {
+        use schema::course_shares::dsl as s_dsl;
+        use schema::courses::dsl as c_dsl;
+
+        db.transaction::<_, DieselError, _>(|db| {
+            let accepted = diesel::update(s_dsl::course_shares)
+                .filter(s_dsl::id.eq(id).and(s_dsl::email.eq(a.email.clone())))
+                .filter(s_dsl::accepted_at.is_null())
+                .set((
+                    s_dsl::account.eq(a.id),
+                    s_dsl::accepted_at.eq(Utc::now().naive_utc()),
+                ))
+                .returning((s_dsl::course, s_dsl::mode))
+                .get_result::<(Uuid, String)>(db)
+                .optional()?;
+
+            let (original, mode) = match accepted {
+                Some(accepted) => accepted,
+                None => return Ok(None),
+            };
+
+            let original = c_dsl::courses.find(original).first::<Course>(db)?;
+
+            let mut course = insert_course(
+                db,
+                NewCourse {
+                    owner: a.id,
+                    name: original.name,
+                    description: original.description,
+                    j_0: original.j_0,
+                    j_end: original.j_end,
+                    recurrence: original.recurrence,
+                    tags: original.tags,
+                },
+            )?;
+
+            if mode == MODE_READ_ONLY {
+                diesel::update(c_dsl::courses.find(course.id))
+                    .set(c_dsl::share.eq(id))
+                    .execute(db)?;
+
+                course.share = Some(id);
+            }
+
+            webhook::enqueue(db, a.id, WebhookEvent::CourseCreated, &course)?;
+            Ok(Some(course))
+        })
+    }?);
+
+    match course {
+        Some(course) => ApiResult::Ok(course),
+        None => ApiResult::Error(Status::NotFound, "invitation_not_found"),
+    }
+}
+
+/// Declines an invitation, or leaves an accepted one: the course created from it is then kept but
+/// stops following the original
+#[delete("/api/shares/<id>")]
+pub async fn invitations_delete(db: DbConn, a: Account, id: Uuid) -> ApiResult {
+    with_db!(db => {
+        use schema::course_shares::dsl;
+
+        diesel::delete(dsl::course_shares)
+            .filter(dsl::id.eq(id))
+            .filter(
+                dsl::email
+                    .eq(a.email.clone())
+                    .or(dsl::account.assume_not_null().eq(a.id)),
+            )
+            .execute(db)
+    }?);
+
+    ApiResult::success()
+}
@@ -0,0 +1,195 @@
+use crate::api_result::ApiResult;
+use crate::marking::{apply_marking, MarkingResult};
+use crate::{schema, Account, Course, DbConn, Event};
+use chrono::{NaiveDateTime, Utc};
+use diesel::dsl::{now, sql, IntervalDsl};
+use diesel::prelude::*;
+use diesel::sql_types::BigInt;
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use uuid::Uuid;
+
+/// How long a sync token stays valid, tombstones are kept for a day longer
+const MAX_TOKEN_AGE_DAYS: i64 = 30;
+
+/// Opaque position in the change history of an account, handed out by [`sync`]
+///
+/// It holds the oldest transaction that was still running when the sync transaction started: every
+/// change made by an older transaction was visible to it, while the changes of this transaction or
+/// newer ones are sent again on the next sync. This is harmless as clients apply changes
+/// idempotently. Timestamps can't be used instead, as a transaction that started before the sync
+/// but commits after it writes an `updated_at` earlier than the sync.
+///
+/// The time at which the token was issued is kept to refuse tokens older than the tombstones.
+struct SyncToken {
+    xid: i64,
+    issued_at: i64,
+}
+
+impl SyncToken {
+    fn parse(token: &str) -> Option<Self> {
+        let (xid, issued_at) = token.split_once('.')?;
+
+        Some(SyncToken {
+            xid: xid.parse().ok()?,
+            issued_at: issued_at.parse().ok()?,
+        })
+    }
+
+    fn is_expired(&self) -> bool {
+        Utc::now().timestamp() - self.issued_at > MAX_TOKEN_AGE_DAYS * 24 * 60 * 60
+    }
+}
+
+impl std::fmt::Display for SyncToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.xid, self.issued_at)
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct SyncResponse {
+    token: String,
+    /// If `true`, the client must discard its cache and replace it with this response
+    full: bool,
+
+    courses: Vec<Course>,
+    events: Vec<Event>,
+
+    /// Deletions have to be applied before the `courses` and `events` upserts, as an event can be
+    /// deleted and re-created under the same `(course, j)` when a recurrence is changed
+    deleted_courses: Vec<Uuid>,
+    deleted_events: Vec<(Uuid, i64)>,
+}
+
+/// Returns everything that changed since `since`, or the whole account if no valid token is given
+///
+/// Tokens older than [`MAX_TOKEN_AGE_DAYS`] are refused with `resync_required`, the client must
+/// then sync again without a token.
+#[get("/api/sync?<since>")]
+pub async fn sync(db: DbConn, a: Account, since: Option<String>) -> ApiResult<SyncResponse> {
+    let since = since.as_deref().and_then(SyncToken::parse);
+
+    if since.as_ref().map_or(false, SyncToken::is_expired) {
+        return ApiResult::Error(Status::Gone, "resync_required");
+    }
+
+    let response = with_db!(db => {
+        use schema::courses::dsl as c_dsl;
+        use schema::events::dsl as e_dsl;
+        use schema::tombstones::dsl as t_dsl;
+
+        db.transaction::<_, diesel::result::Error, _>(|db| {
+            let token = SyncToken {
+                xid: diesel::select(sql::<BigInt>("txid_snapshot_xmin(txid_current_snapshot())"))
+                    .get_result(db)?,
+                issued_at: Utc::now().timestamp(),
+            };
+
+            let mut courses = c_dsl::courses.filter(c_dsl::owner.eq(a.id)).into_boxed();
+            let mut events = e_dsl::events.filter(e_dsl::owner.eq(a.id)).into_boxed();
+
+            let mut deleted_courses = Vec::new();
+            let mut deleted_events = Vec::new();
+
+            if let Some(SyncToken { xid: since, .. }) = since {
+                courses = courses.filter(c_dsl::updated_xid.ge(since));
+                events = events.filter(e_dsl::updated_xid.ge(since));
+
+                let tombstones = t_dsl::tombstones
+                    .filter(t_dsl::owner.eq(a.id).and(t_dsl::deleted_xid.ge(since)))
+                    .select((t_dsl::course, t_dsl::j))
+                    .load::<(Uuid, Option<i64>)>(db)?;
+
+                for (course, j) in tombstones {
+                    match j {
+                        Some(j) => deleted_events.push((course, j)),
+                        None => deleted_courses.push(course),
+                    }
+                }
+            }
+
+            Ok(SyncResponse {
+                token: token.to_string(),
+                full: since.is_none(),
+                courses: courses.load::<Course>(db)?,
+                events: events.load::<Event>(db)?,
+                deleted_courses,
+                deleted_events,
+            })
+        })
+    }?);
+
+    ApiResult::Ok(response)
+}
+
+#[derive(serde::Deserialize)]
+pub struct OfflineMarking {
+    course: Uuid,
+    j: u32,
+    marking: String,
+    /// Last `updated_xid` of the event known to the client when the marking was made
+    updated_xid: i64,
+}
+
+/// Uploads markings made while offline
+///
+/// Each marking is only applied if the event wasn't modified in the meantime, otherwise the
+/// current server-side state is returned so that the client can resolve the conflict.
+#[put("/api/sync/markings", data = "<json>")]
+pub async fn sync_markings(
+    db: DbConn,
+    a: Account,
+    json: Json<Vec<OfflineMarking>>,
+) -> ApiResult<Vec<MarkingResult>> {
+    let markings = json.into_inner();
+
+    let results = with_db!(db => {
+        db.transaction::<_, diesel::result::Error, _>(|db| {
+            markings
+                .into_iter()
+                .map(|m| {
+                    let marking = Some(m.marking);
+                    apply_marking(db, a.id, m.course, m.j as i64, marking, Some(m.updated_xid))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })
+    }?);
+
+    ApiResult::Ok(results)
+}
+
+/// Deletes the tombstones no valid token can ask for anymore, every hour
+pub fn scheduler() -> AdHoc {
+    AdHoc::on_liftoff("tombstone pruner", |rocket| {
+        Box::pin(async move {
+            let conn = DbConn::get_one(rocket)
+                .await
+                .expect("no database available for pruning tombstones");
+
+            rocket::tokio::spawn(async move {
+                let mut interval =
+                    rocket::tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+
+                loop {
+                    interval.tick().await;
+
+                    let pruned = conn
+                        .run(|db| {
+                            use schema::tombstones::dsl;
+
+                            diesel::delete(dsl::tombstones)
+                                .filter(dsl::deleted_at.lt(now - (MAX_TOKEN_AGE_DAYS + 1).days()))
+                                .execute(db)
+                        })
+                        .await;
+
+                    if let Err(e) = pruned {
+                        eprintln!("couldn't prune tombstones: {}", e);
+                    }
+                }
+            });
+        })
+    })
+}
@@ -0,0 +1,1431 @@
+//! Routes tested through a local client, against the database `TEST_DATABASE_URL` points to
+//!
+//! The database is migrated once, then every test works on accounts of its own so that tests can
+//! run concurrently and without cleaning up.
+
+use crate::session::{self, ClientInfo};
+use crate::{schema, COOKIE_SESSION_NAME, MIGRATIONS};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use diesel_migrations::MigrationHarness;
+use jsonwebtoken::{Algorithm, EncodingKey};
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use rocket::http::{ContentType, Cookie, Header, Status};
+use rocket::local::asynchronous::{Client, LocalRequest};
+use rocket::response::content::RawJson;
+use rocket::serde::json::{json, Value};
+use rocket::State;
+use std::sync::{Arc, Mutex, Once};
+use uuid::Uuid;
+
+fn database_url() -> String {
+    std::env::var("TEST_DATABASE_URL")
+        .expect("TEST_DATABASE_URL must point to a scratch PostgreSQL database")
+}
+
+fn connect() -> PgConnection {
+    PgConnection::establish(&database_url()).expect("couldn't connect to the test database")
+}
+
+/// Runs the migrations before any client is created, as the migration runner of concurrent
+/// clients would conflict
+fn migrate() {
+    static MIGRATE: Once = Once::new();
+
+    MIGRATE.call_once(|| {
+        connect().run_pending_migrations(MIGRATIONS).unwrap();
+    });
+}
+
+/// Client logged in to a new account
+struct TestClient {
+    client: Client,
+    account: Uuid,
+    session: String,
+}
+
+impl TestClient {
+    async fn new() -> Self {
+        Self::with_config(|figment| figment).await
+    }
+
+    /// Client of an application whose configuration is adjusted by `configure`
+    async fn with_config(configure: impl FnOnce(Figment) -> Figment) -> Self {
+        migrate();
+
+        let mut db = connect();
+
+        let account = {
+            use schema::accounts::dsl;
+
+            diesel::insert_into(dsl::accounts)
+                .values(dsl::email.eq(format!("{}@example.com", Uuid::new_v4())))
+                .returning(dsl::id)
+                .get_result::<Uuid>(&mut db)
+                .unwrap()
+        };
+
+        let client_info = ClientInfo {
+            user_agent: None,
+            ip: None,
+        };
+        let (session, _) = session::create(&mut db, account, client_info).unwrap();
+
+        let figment = rocket::Config::figment()
+            .merge(("databases.mdj.url", database_url()))
+            .merge(("databases.mdj.pool_size", 2));
+        let figment = configure(figment);
+
+        Self {
+            client: Client::tracked(crate::rocket(figment)).await.unwrap(),
+            account,
+            session,
+        }
+    }
+
+    /// Authenticates `request` with the session cookie, as the frontend does
+    fn authenticated<'c>(&self, request: LocalRequest<'c>) -> LocalRequest<'c> {
+        request
+            .private_cookie(Cookie::new(COOKIE_SESSION_NAME, self.session.clone()))
+            .header(Header::new("X-Requested-With", "test"))
+    }
+
+    async fn get(&self, uri: &str) -> (Status, Value) {
+        let response = self.authenticated(self.client.get(uri)).dispatch().await;
+        (response.status(), response.into_json().await.unwrap())
+    }
+
+    async fn send(&self, request: LocalRequest<'_>, body: Value) -> (Status, Value) {
+        let response = self
+            .authenticated(request)
+            .header(ContentType::JSON)
+            .body(body.to_string())
+            .dispatch()
+            .await;
+        (response.status(), response.into_json().await.unwrap())
+    }
+
+    async fn insert_course(&self, name: &str) -> Value {
+        let body = json!({
+            "name": name,
+            "description": "",
+            "j_0": "2026-10-19",
+            "j_end": "2026-12-31",
+            "recurrence": "0,1,3,7",
+        });
+
+        let (status, course) = self.send(self.client.post("/api/courses"), body).await;
+        assert_eq!(status, Status::Ok);
+        course
+    }
+}
+
+#[rocket::async_test]
+async fn inserted_course_is_listed() {
+    let client = TestClient::new().await;
+
+    let course = client.insert_course("Biochimie").await;
+    assert_eq!(course["owner"], client.account.to_string());
+    assert_eq!(course["name"], "Biochimie");
+    assert_eq!(course["description"], Value::Null);
+    assert_eq!(course["archived"], false);
+    assert!(course["created_at"].is_string());
+    assert!(course["updated_at"].is_string());
+
+    let occurrences = course["occurrences"].as_array().unwrap();
+    let js = occurrences.iter().map(|o| o[1].clone()).collect::<Vec<_>>();
+    assert_eq!(js, [json!(0), json!(1), json!(3), json!(7)]);
+    assert_eq!(occurrences[3][0], "2026-10-26");
+
+    let (status, courses) = client.get("/api/courses").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(courses, json!([course]));
+
+    let uri = format!("/api/courses/{}", course["id"].as_str().unwrap());
+    let (status, fetched) = client.get(&uri).await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(fetched, course);
+}
+
+#[rocket::async_test]
+async fn archived_courses_are_listed_separately() {
+    let client = TestClient::new().await;
+
+    let kept = client.insert_course("Anatomie").await;
+    let archived = client.insert_course("Physiologie").await;
+
+    let uri = format!("/api/courses/{}/archived", archived["id"].as_str().unwrap());
+    let (status, course) = client
+        .send(client.client.put(uri.as_str()), json!(true))
+        .await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(course["archived"], true);
+
+    let (_, courses) = client.get("/api/courses").await;
+    let ids = courses.as_array().unwrap().iter().map(|c| &c["id"]);
+    assert_eq!(ids.collect::<Vec<_>>(), [&kept["id"]]);
+
+    let (_, courses) = client.get("/api/courses?archived=true").await;
+    let courses = courses.as_array().unwrap();
+    assert_eq!(courses.len(), 1);
+    assert_eq!(courses[0]["id"], archived["id"]);
+    assert_eq!(courses[0]["archived"], true);
+    assert_eq!(courses[0]["owner"], client.account.to_string());
+}
+
+#[rocket::async_test]
+async fn courses_of_other_accounts_are_hidden() {
+    let owner = TestClient::new().await;
+    let other = TestClient::new().await;
+
+    let course = owner.insert_course("Histologie").await;
+
+    let (status, courses) = other.get("/api/courses").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(courses, json!([]));
+
+    let uri = format!("/api/courses/{}", course["id"].as_str().unwrap());
+    let (status, _) = other.get(&uri).await;
+    assert_eq!(status, Status::NotFound);
+}
+
+#[rocket::async_test]
+async fn batch_markings_are_applied_in_order() {
+    let client = TestClient::new().await;
+    let other = TestClient::new().await;
+    let course = client.insert_course("Immunologie").await;
+    let course_id = course["id"].as_str().unwrap();
+    let other_course = other.insert_course("Génétique").await;
+    let other_id = other_course["id"].as_str().unwrap();
+
+    let body = json!([
+        { "course": course_id, "j": 0, "marking": "green" },
+        { "course": course_id, "j": 1, "marking": "red" },
+        { "course": course_id, "j": 1, "marking": "" },
+        { "course": course_id, "j": 9, "marking": "green" },
+        { "course": other_id, "j": 0, "marking": "green" },
+    ]);
+    let (status, results) = client.send(client.client.put("/api/markings"), body).await;
+    assert_eq!(status, Status::Ok);
+
+    let statuses = results
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| r["status"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        statuses,
+        ["applied", "applied", "applied", "not_found", "not_found"]
+    );
+
+    let (_, course) = client.get(&format!("/api/courses/{}", course_id)).await;
+    assert_eq!(course["occurrences"][0][2], "green");
+    assert_eq!(course["occurrences"][1][2], Value::Null);
+
+    let (_, other_course) = other.get(&format!("/api/courses/{}", other_id)).await;
+    assert_eq!(other_course["occurrences"][0][2], Value::Null);
+}
+
+#[rocket::async_test]
+async fn logout_deletes_the_session() {
+    let client = TestClient::new().await;
+
+    let response = client
+        .authenticated(client.client.get("/logout"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::SeeOther);
+
+    // The session can't be used anymore, even with a copy of its cookie
+    let response = client
+        .authenticated(client.client.get("/api/account"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Unauthorized);
+}
+
+#[rocket::async_test]
+async fn revoked_sessions_are_logged_out() {
+    let client = TestClient::new().await;
+    let client_info = ClientInfo {
+        user_agent: Some(String::from("Other device")),
+        ip: None,
+    };
+    let (other, _) = session::create(&mut connect(), client.account, client_info).unwrap();
+
+    let (status, sessions) = client.get("/api/account/sessions").await;
+    assert_eq!(status, Status::Ok);
+    let sessions = sessions.as_array().unwrap();
+    assert_eq!(sessions.len(), 2);
+    let revoked = sessions.iter().find(|s| s["current"] == false).unwrap();
+    assert_eq!(revoked["user_agent"], "Other device");
+
+    let uri = format!("/api/account/sessions/{}", revoked["id"].as_str().unwrap());
+    let (status, _) = client.send(client.client.delete(uri), json!({})).await;
+    assert_eq!(status, Status::Ok);
+
+    let response = client
+        .client
+        .get("/api/account")
+        .private_cookie(Cookie::new(COOKIE_SESSION_NAME, other))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Unauthorized);
+
+    let (status, _) = client.get("/api/account").await;
+    assert_eq!(status, Status::Ok);
+}
+
+/// Minimal CalDAV client, going through the steps clients such as DAVx⁵ take to sync a calendar
+///
+/// The collection is listed with a `PROPFIND`, its objects are fetched with a `calendar-multiget`
+/// `REPORT`, and changes are written back with `PUT`s conditioned on the ETag. WebDAV methods are
+/// sent the way the reverse proxy forwards them.
+struct CalDavClient<'c> {
+    client: &'c Client,
+    /// Path of the calendar, with a trailing slash
+    collection: String,
+    /// HTTP Basic password
+    password: Option<String>,
+}
+
+/// Object of a [`CalDavClient`] collection
+struct CalDavObject {
+    href: String,
+    etag: String,
+    calendar_data: String,
+}
+
+fn xml_elements<'x>(xml: &'x str, name: &str) -> Vec<&'x str> {
+    let (open, close) = (format!("<{}>", name), format!("</{}>", name));
+
+    xml.split(open.as_str())
+        .skip(1)
+        .filter_map(|element| element.split_once(close.as_str()).map(|(e, _)| e))
+        .collect()
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+impl CalDavClient<'_> {
+    async fn dav(&self, method: &str, depth: &str, body: String) -> (u16, String) {
+        let response = self
+            .client
+            .post(self.collection.trim_end_matches('/').to_string())
+            .header(Header::new("X-Dav-Method", method.to_string()))
+            .header(Header::new("Depth", depth.to_string()))
+            .header(ContentType::XML)
+            .body(body)
+            .dispatch()
+            .await;
+
+        (
+            response.status().code,
+            response.into_string().await.unwrap(),
+        )
+    }
+
+    /// Hrefs of the objects of the collection, after checking it is a calendar of VTODOs
+    async fn list(&self) -> Vec<String> {
+        let propfind = r#"<?xml version="1.0" encoding="utf-8"?>
+            <d:propfind xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+                <d:prop><d:resourcetype/><c:supported-calendar-component-set/></d:prop>
+            </d:propfind>"#;
+        let (status, body) = self.dav("PROPFIND", "0", propfind.to_string()).await;
+        assert_eq!(status, 207);
+        assert!(body.contains("<c:calendar/>"));
+        assert!(body.contains(r#"<c:comp name="VTODO"/>"#));
+
+        let propfind = r#"<?xml version="1.0" encoding="utf-8"?>
+            <d:propfind xmlns:d="DAV:"><d:prop><d:getetag/></d:prop></d:propfind>"#;
+        let (status, body) = self.dav("PROPFIND", "1", propfind.to_string()).await;
+        assert_eq!(status, 207);
+
+        xml_elements(&body, "d:href")
+            .into_iter()
+            .map(unescape_xml)
+            .filter(|href| *href != self.collection)
+            .collect()
+    }
+
+    async fn multiget(&self, hrefs: &[String]) -> Vec<CalDavObject> {
+        let hrefs = hrefs
+            .iter()
+            .map(|href| format!("<d:href>{}</d:href>", href))
+            .collect::<String>();
+        let report = format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+            <c:calendar-multiget xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+                <d:prop><d:getetag/><c:calendar-data/></d:prop>{}
+            </c:calendar-multiget>"#,
+            hrefs,
+        );
+        let (status, body) = self.dav("REPORT", "1", report).await;
+        assert_eq!(status, 207);
+
+        xml_elements(&body, "d:response")
+            .into_iter()
+            .map(|response| CalDavObject {
+                href: unescape_xml(xml_elements(response, "d:href")[0]),
+                etag: unescape_xml(xml_elements(response, "d:getetag")[0]),
+                calendar_data: unescape_xml(xml_elements(response, "c:calendar-data")[0]),
+            })
+            .collect()
+    }
+
+    async fn put(&self, object: &CalDavObject, calendar_data: String) -> Status {
+        let mut request = self
+            .client
+            .put(object.href.clone())
+            .header(Header::new("If-Match", object.etag.clone()))
+            .header(Header::new("Content-Type", "text/calendar; charset=utf-8"))
+            .body(calendar_data);
+
+        if let Some(password) = &self.password {
+            let credentials = base64::encode(format!("user:{}", password));
+            request = request.header(Header::new(
+                "Authorization",
+                format!("Basic {}", credentials),
+            ));
+        }
+
+        request.dispatch().await.status()
+    }
+}
+
+impl TestClient {
+    /// CalDAV client of the calendar of the account, writing with an API token if `password` is set
+    async fn caldav(&self, password: Option<String>) -> CalDavClient<'_> {
+        let (status, link) = self
+            .send(self.client.post("/api/account/ical"), json!({}))
+            .await;
+        assert_eq!(status, Status::Ok);
+        let token = link["url"].as_str().unwrap().rsplit('/').next().unwrap();
+
+        CalDavClient {
+            client: &self.client,
+            collection: format!("/caldav/{}/", token),
+            password,
+        }
+    }
+
+    async fn api_token(&self, read_only: bool) -> String {
+        let body = json!({ "name": "CalDAV", "read_only": read_only });
+        let (status, token) = self
+            .send(self.client.post("/api/account/tokens"), body)
+            .await;
+        assert_eq!(status, Status::Ok);
+        token["token"].as_str().unwrap().to_string()
+    }
+}
+
+#[rocket::async_test]
+async fn completing_a_caldav_todo_marks_the_event() {
+    let client = TestClient::new().await;
+    let course = client.insert_course("Pharmacologie").await;
+    let course_id = course["id"].as_str().unwrap();
+
+    let caldav = client.caldav(Some(client.api_token(false).await)).await;
+    let hrefs = caldav.list().await;
+    let href = hrefs
+        .iter()
+        .find(|href| href.ends_with(&format!("{}-1.ics", course_id)))
+        .unwrap();
+
+    let object = caldav.multiget(&[href.clone()]).await.remove(0);
+    assert!(object.calendar_data.contains("STATUS:NEEDS-ACTION"));
+
+    let completed = object
+        .calendar_data
+        .replace("STATUS:NEEDS-ACTION", "STATUS:COMPLETED")
+        .replace("DTSTART;VALUE=DATE:20261020", "DTSTART;VALUE=DATE:20261022")
+        .replace("DUE;VALUE=DATE:20261021", "DUE;VALUE=DATE:20261023");
+    assert_eq!(
+        caldav.put(&object, completed.clone()).await,
+        Status::NoContent
+    );
+
+    let (_, course) = client.get(&format!("/api/courses/{}", course_id)).await;
+    assert_eq!(course["occurrences"][1], json!(["2026-10-22", 1, "green"]));
+
+    // The ETag changed along with the object
+    assert_eq!(
+        caldav.put(&object, completed).await,
+        Status::PreconditionFailed
+    );
+}
+
+#[rocket::async_test]
+async fn caldav_writes_need_a_writable_api_token() {
+    let client = TestClient::new().await;
+    let course = client.insert_course("Sémiologie").await;
+    let course_id = course["id"].as_str().unwrap();
+
+    for (password, expected) in [
+        (None, Status::Unauthorized),
+        (Some(String::from("mdj_invalid")), Status::Unauthorized),
+        (Some(client.api_token(true).await), Status::Forbidden),
+        (
+            Some(TestClient::new().await.api_token(false).await),
+            Status::Forbidden,
+        ),
+    ] {
+        let caldav = client.caldav(password).await;
+        let hrefs = caldav.list().await;
+        let object = caldav.multiget(&hrefs[..1]).await.remove(0);
+
+        let completed = object
+            .calendar_data
+            .replace("STATUS:NEEDS-ACTION", "STATUS:COMPLETED");
+        assert_eq!(caldav.put(&object, completed).await, expected);
+    }
+
+    let (_, course) = client.get(&format!("/api/courses/{}", course_id)).await;
+    assert_eq!(course["occurrences"][0][2], Value::Null);
+}
+
+/// Values of the `name` properties of an iCal document, in order
+fn ical_properties<'c>(calendar: &'c str, name: &str) -> Vec<&'c str> {
+    calendar
+        .lines()
+        .filter_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+        .collect()
+}
+
+impl TestClient {
+    async fn ical_sequences(&self, feed: &str) -> Vec<i32> {
+        let response = self.client.get(feed).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let calendar = response.into_string().await.unwrap();
+
+        ical_properties(&calendar, "SEQUENCE")
+            .into_iter()
+            .map(|sequence| sequence.parse().unwrap())
+            .collect()
+    }
+}
+
+#[rocket::async_test]
+async fn ical_feed_is_filtered_by_tag_and_bumps_changed_sequences() {
+    let client = TestClient::new().await;
+    let course = client.insert_course("Anatomie").await;
+    let course_id = course["id"].as_str().unwrap();
+    client.insert_course("Histologie").await;
+
+    let body = json!({ "tags": [" partiels ", "partiels", ""] });
+    let (status, course) = client
+        .send(
+            client.client.put(format!("/api/courses/{}", course_id)),
+            body,
+        )
+        .await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(course["tags"], json!(["partiels"]));
+
+    let (_, link) = client
+        .send(client.client.post("/api/account/ical"), json!({}))
+        .await;
+    let token = link["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    let feed = format!("/ical/{}?tag=partiels", token);
+
+    let response = client.client.get(feed.clone()).dispatch().await;
+    let calendar = response.into_string().await.unwrap();
+    let summaries = ical_properties(&calendar, "SUMMARY");
+    assert_eq!(summaries.len(), 4);
+    assert!(summaries.iter().all(|s| s.starts_with("MdJ: Anatomie")));
+
+    let before = client.ical_sequences(&feed).await;
+
+    let status = client
+        .authenticated(
+            client
+                .client
+                .put(format!("/api/courses/{}/events/1/marking", course_id)),
+        )
+        .body("green")
+        .dispatch()
+        .await
+        .status();
+    assert_eq!(status, Status::Ok);
+
+    let marked = client.ical_sequences(&feed).await;
+    assert_eq!(marked[0], before[0]);
+    assert!(marked[1] > before[1]);
+
+    let (status, _) = client
+        .send(
+            client.client.put(format!("/api/courses/{}", course_id)),
+            json!({ "name": "Anatomie générale" }),
+        )
+        .await;
+    assert_eq!(status, Status::Ok);
+
+    let renamed = client.ical_sequences(&feed).await;
+    assert!(renamed.iter().zip(&marked).all(|(r, m)| r > m));
+}
+
+impl TestClient {
+    async fn import_csv(&self, csv: &str, dry_run: bool) -> Value {
+        let uri = format!("/api/import?dry_run={}", dry_run);
+        let response = self
+            .authenticated(self.client.post(uri))
+            .header(ContentType::CSV)
+            .body(csv)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        response.into_json().await.unwrap()
+    }
+}
+
+#[rocket::async_test]
+async fn import_dry_run_reports_without_writing() {
+    let client = TestClient::new().await;
+    client.insert_course("Cardiologie").await;
+
+    let csv = "name,description,j_0,j_end,recurrence\n\
+               Neurologie,,2026-10-19,2026-12-31,\n\
+               Cardiologie,,2026-10-19,2026-12-31,\n\
+               Néphrologie,,2026-12-31,2026-10-19,\n";
+
+    let report = client.import_csv(csv, true).await;
+    assert_eq!(report["dry_run"], true);
+    assert_eq!(report["committed"], false);
+    let statuses = report["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|i| (i["name"].clone(), i["status"].clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        statuses,
+        [
+            (json!("Neurologie"), json!("would_create")),
+            (json!("Cardiologie"), json!("conflict")),
+            (json!("Néphrologie"), json!("invalid")),
+        ]
+    );
+
+    let (_, courses) = client.get("/api/courses").await;
+    assert_eq!(courses.as_array().unwrap().len(), 1);
+
+    let csv = "name,description,j_0,j_end,recurrence\n\
+               Neurologie,,2026-10-19,2026-12-31,\n";
+    let report = client.import_csv(csv, false).await;
+    assert_eq!(report["committed"], true);
+    assert_eq!(report["items"][0]["status"], "created");
+
+    let (_, courses) = client.get("/api/courses").await;
+    assert_eq!(courses.as_array().unwrap().len(), 2);
+}
+
+#[rocket::async_test]
+async fn timetable_dates_are_in_local_time() {
+    let client = TestClient::new().await;
+
+    // The second DTSTART is 00:30 on the 21st in UTC+2, and its SUMMARY is folded
+    let ics = "BEGIN:VCALENDAR\r\n\
+               BEGIN:VEVENT\r\nUID:a\r\nSUMMARY:Génétique\r\n\
+               DTSTART;TZID=Europe/Paris:20261020T233000\r\nEND:VEVENT\r\n\
+               BEGIN:VEVENT\r\nUID:b\r\nSUMMARY:Immuno\r\n logie\r\n\
+               DTSTART:20261020T223000Z\r\nEND:VEVENT\r\n\
+               END:VCALENDAR\r\n";
+
+    let response = client
+        .authenticated(client.client.post("/api/import/ical?utc_offset=120"))
+        .header(ContentType::Calendar)
+        .body(ics)
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let (_, courses) = client.get("/api/courses").await;
+    let j_0s = courses
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|c| (c["name"].clone(), c["j_0"].clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        j_0s,
+        [
+            (json!("Génétique"), json!("2026-10-20")),
+            (json!("Immunologie"), json!("2026-10-21")),
+        ]
+    );
+}
+
+#[rocket::async_test]
+async fn invalid_modified_since_is_rejected() {
+    let client = TestClient::new().await;
+
+    let (status, error) = client.get("/api/courses?modified_since=yesterday").await;
+    assert_eq!(status, Status::BadRequest);
+    assert_eq!(error, json!({ "error_kind": "invalid_modified_since" }));
+}
+
+#[rocket::async_test]
+async fn incremental_sync_reports_deletions() {
+    let client = TestClient::new().await;
+    let deleted = client.insert_course("Bactériologie").await;
+    let deleted_id = deleted["id"].as_str().unwrap();
+    let rescheduled = client.insert_course("Virologie").await;
+    let rescheduled_id = rescheduled["id"].as_str().unwrap();
+
+    let (status, full) = client.get("/api/sync").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(full["full"], true);
+    assert_eq!(full["events"].as_array().unwrap().len(), 8);
+
+    let (status, _) = client
+        .send(
+            client.client.delete(format!("/api/courses/{}", deleted_id)),
+            json!({}),
+        )
+        .await;
+    assert_eq!(status, Status::Ok);
+
+    let body = json!({ "recurrence": "0,1", "j_0": "2026-10-19", "j_end": "2026-12-31" });
+    let uri = format!("/api/courses/{}/recurrence", rescheduled_id);
+    let (status, _) = client.send(client.client.post(uri), body).await;
+    assert_eq!(status, Status::Ok);
+
+    let since = full["token"].as_str().unwrap();
+    let (status, changes) = client.get(&format!("/api/sync?since={}", since)).await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(changes["full"], false);
+    assert_eq!(changes["deleted_courses"], json!([deleted_id]));
+
+    let deleted_events = changes["deleted_events"].as_array().unwrap();
+    assert!(deleted_events.contains(&json!([rescheduled_id, 3])));
+
+    let courses = changes["courses"].as_array().unwrap();
+    assert_eq!(courses.len(), 1);
+    assert_eq!(courses[0]["id"], rescheduled_id);
+}
+
+/// Sends `request` with `token` as its bearer, and no cookie nor `X-Requested-With` header
+async fn with_bearer(request: LocalRequest<'_>, token: &str, body: Option<Value>) -> Status {
+    let mut request = request.header(Header::new("Authorization", format!("Bearer {}", token)));
+
+    if let Some(body) = body {
+        request = request.header(ContentType::JSON).body(body.to_string());
+    }
+
+    request.dispatch().await.status()
+}
+
+#[rocket::async_test]
+async fn api_tokens_authenticate_and_read_only_ones_cannot_write() {
+    let client = TestClient::new().await;
+    let course = json!({
+        "name": "Physiologie",
+        "description": "",
+        "j_0": "2026-10-19",
+        "j_end": "2026-12-31",
+        "recurrence": "0,1,3",
+    });
+
+    let writable = client.api_token(false).await;
+    let status = with_bearer(
+        client.client.post("/api/courses"),
+        &writable,
+        Some(course.clone()),
+    )
+    .await;
+    assert_eq!(status, Status::Ok);
+
+    let read_only = client.api_token(true).await;
+    let status = with_bearer(client.client.get("/api/courses"), &read_only, None).await;
+    assert_eq!(status, Status::Ok);
+    let status = with_bearer(client.client.post("/api/courses"), &read_only, Some(course)).await;
+    assert_eq!(status, Status::Forbidden);
+
+    // Tokens can't manage tokens
+    let status = with_bearer(client.client.get("/api/account/tokens"), &writable, None).await;
+    assert_eq!(status, Status::Forbidden);
+
+    let status = with_bearer(client.client.get("/api/courses"), "mdj_invalid", None).await;
+    assert_eq!(status, Status::Unauthorized);
+
+    let (_, courses) = client.get("/api/courses").await;
+    assert_eq!(courses.as_array().unwrap().len(), 1);
+}
+
+impl TestClient {
+    /// Posts the login form from `ip`, returning the status and `Retry-After` header
+    async fn login(&self, email: &str, password: &str, ip: &str) -> (Status, Option<String>) {
+        let response = self
+            .client
+            .post("/login")
+            .remote(format!("{}:443", ip).parse().unwrap())
+            .header(ContentType::Form)
+            .header(Header::new("X-Requested-With", "test"))
+            .body(format!("email={}&password={}", email, password))
+            .dispatch()
+            .await;
+
+        let retry_after = response.headers().get_one("Retry-After").map(String::from);
+        (response.status(), retry_after)
+    }
+}
+
+#[rocket::async_test]
+async fn repeated_failed_logins_lock_the_email_out_of_their_ip() {
+    let client = TestClient::new().await;
+    let (_, account) = client.get("/api/account").await;
+    let email = account["email"].as_str().unwrap();
+
+    for _ in 0..5 {
+        let (status, _) = client.login(email, "wrong", "203.0.113.1").await;
+        assert_eq!(status, Status::Unauthorized);
+    }
+
+    let (status, retry_after) = client.login(email, "wrong", "203.0.113.1").await;
+    assert_eq!(status, Status::TooManyRequests);
+    let retry_after = retry_after.unwrap().parse::<i64>().unwrap();
+    assert!((1..=15 * 60).contains(&retry_after));
+
+    // Failures from elsewhere don't lock the owner out
+    let (status, _) = client.login(email, "wrong", "203.0.113.2").await;
+    assert_eq!(status, Status::Unauthorized);
+}
+
+#[rocket::async_test]
+async fn cross_site_requests_are_rejected() {
+    let client = TestClient::new().await;
+    let body = json!({
+        "name": "Embryologie",
+        "description": "",
+        "j_0": "2026-10-19",
+        "j_end": "2026-12-31",
+        "recurrence": "0,1,3",
+    });
+    let cookie = Cookie::new(COOKIE_SESSION_NAME, client.session.clone());
+
+    let cross_site = client
+        .client
+        .post("/api/courses")
+        .private_cookie(cookie.clone())
+        .header(Header::new("Origin", "https://attacker.example"))
+        .header(ContentType::JSON)
+        .body(body.to_string());
+    assert_eq!(cross_site.dispatch().await.status(), Status::Forbidden);
+
+    // Without `Origin` nor `Referer`, only requests with `X-Requested-With` are let through
+    let anonymous = client
+        .client
+        .post("/api/courses")
+        .private_cookie(cookie.clone())
+        .header(ContentType::JSON)
+        .body(body.to_string());
+    assert_eq!(anonymous.dispatch().await.status(), Status::Forbidden);
+
+    let same_site = client
+        .client
+        .post("/api/courses")
+        .private_cookie(cookie)
+        .header(Header::new("Host", "mdj.test"))
+        .header(Header::new("Origin", "https://mdj.test"))
+        .header(ContentType::JSON)
+        .body(body.to_string());
+    assert_eq!(same_site.dispatch().await.status(), Status::Ok);
+
+    let (_, courses) = client.get("/api/courses").await;
+    assert_eq!(courses.as_array().unwrap().len(), 1);
+}
+
+#[rocket::async_test]
+async fn read_only_shares_are_followed() {
+    let owner = TestClient::new().await;
+    let follower = TestClient::new().await;
+    let course = owner.insert_course("Dermatologie").await;
+    let course_id = course["id"].as_str().unwrap();
+
+    // Invitations are addressed by email, which must be verified to accept them
+    let email = {
+        use schema::accounts::dsl;
+
+        diesel::update(dsl::accounts.find(follower.account))
+            .set(dsl::email_verified.eq(true))
+            .returning(dsl::email)
+            .get_result::<String>(&mut connect())
+            .unwrap()
+    };
+
+    let body = json!({ "email": email.to_uppercase(), "mode": "read_only" });
+    let uri = format!("/api/courses/{}/shares", course_id);
+    let (status, share) = owner.send(owner.client.post(uri), body).await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(share["email"], email);
+
+    let (_, invitations) = follower.get("/api/shares").await;
+    assert_eq!(invitations[0]["course_name"], "Dermatologie");
+    let uri = format!(
+        "/api/shares/{}/accept",
+        invitations[0]["id"].as_str().unwrap()
+    );
+    let (status, followed) = follower.send(follower.client.post(uri), json!({})).await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(followed["share"], share["id"]);
+    let followed_id = followed["id"].as_str().unwrap();
+
+    let (status, _) = owner
+        .send(
+            owner.client.put(format!("/api/courses/{}", course_id)),
+            json!({ "name": "Dermatologie pédiatrique" }),
+        )
+        .await;
+    assert_eq!(status, Status::Ok);
+
+    let (_, followed) = follower.get(&format!("/api/courses/{}", followed_id)).await;
+    assert_eq!(followed["name"], "Dermatologie pédiatrique");
+
+    let (status, error) = follower
+        .send(
+            follower.client.put(format!("/api/courses/{}", followed_id)),
+            json!({ "name": "Dermatologie" }),
+        )
+        .await;
+    assert_eq!(status, Status::Forbidden);
+    assert_eq!(error, json!({ "error_kind": "shared_course_read_only" }));
+}
+
+impl TestClient {
+    /// `allowCredentials` of the passkey challenge for `email`
+    async fn passkey_credentials(&self, email: &str) -> Value {
+        let response = self
+            .client
+            .post("/login/passkey/start")
+            .header(Header::new("X-Requested-With", "test"))
+            .header(ContentType::JSON)
+            .body(json!({ "email": email }).to_string())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let challenge = response.into_json::<Value>().await.unwrap();
+        challenge["publicKey"]["allowCredentials"].clone()
+    }
+}
+
+#[rocket::async_test]
+async fn emails_without_passkeys_get_a_stable_dummy_credential() {
+    let client = TestClient::new().await;
+    let unknown = random_email();
+
+    let credentials = client.passkey_credentials(&unknown).await;
+    assert_eq!(credentials.as_array().unwrap().len(), 1);
+    assert_eq!(credentials[0]["type"], "public-key");
+
+    // Asking again can't tell the dummy credential from a real one
+    assert_eq!(client.passkey_credentials(&unknown).await, credentials);
+    assert_ne!(
+        client.passkey_credentials(&random_email()).await,
+        credentials
+    );
+}
+
+/// Keys of the mock OpenID Connect provider, which signs its ID tokens with the `real` one
+const OIDC_PRIVATE_KEY: &str = include_str!("tests/oidc_key.pem");
+const OIDC_PUBLIC_KEY: &str = include_str!("tests/oidc_key.pub.pem");
+/// The `decoy` key comes first, so that picking the first key instead of the `kid` one fails
+const OIDC_JWKS: &str = include_str!("tests/oidc_jwks.json");
+
+fn random_email() -> String {
+    format!("{}@example.com", Uuid::new_v4())
+}
+
+/// Mock OpenID Connect provider, whose token endpoint returns the ID token set by the test
+struct MockProvider {
+    issuer: String,
+    id_token: Mutex<String>,
+}
+
+#[get("/.well-known/openid-configuration")]
+fn mock_discovery(mock: &State<Arc<MockProvider>>) -> Value {
+    json!({
+        "issuer": mock.issuer,
+        "authorization_endpoint": format!("{}/authorize", mock.issuer),
+        "token_endpoint": format!("{}/token", mock.issuer),
+        "jwks_uri": format!("{}/jwks", mock.issuer),
+    })
+}
+
+#[get("/jwks")]
+fn mock_jwks() -> RawJson<&'static str> {
+    RawJson(OIDC_JWKS)
+}
+
+#[post("/token")]
+fn mock_token(mock: &State<Arc<MockProvider>>) -> Value {
+    json!({ "id_token": *mock.id_token.lock().unwrap() })
+}
+
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Launches a mock server on `port` with `routes` and `state`, which runs until the end of the test
+async fn launch_mock<T: Send + Sync + 'static>(port: u16, routes: Vec<rocket::Route>, state: T) {
+    let figment = rocket::Config::figment()
+        .merge(("port", port))
+        .merge(("log_level", "off"));
+
+    let (launched, liftoff) = rocket::tokio::sync::oneshot::channel();
+    let mock = rocket::custom(figment)
+        .manage(state)
+        .mount("/", routes)
+        .attach(AdHoc::on_liftoff("Launched", |_| {
+            Box::pin(async move {
+                let _ = launched.send(());
+            })
+        }));
+
+    rocket::tokio::spawn(mock.launch());
+    liftoff.await.unwrap();
+}
+
+/// Starts a mock provider on a free port
+async fn mock_provider() -> Arc<MockProvider> {
+    let port = free_port();
+
+    let mock = Arc::new(MockProvider {
+        issuer: format!("http://127.0.0.1:{}", port),
+        id_token: Mutex::new(String::new()),
+    });
+
+    let routes = routes![mock_discovery, mock_jwks, mock_token];
+    launch_mock(port, routes, mock.clone()).await;
+
+    mock
+}
+
+/// Runs the login flow with the mock provider, signing the ID token with `alg`, `key` and `kid`
+async fn oidc_login(
+    alg: Algorithm,
+    key: EncodingKey,
+    kid: Option<&str>,
+    email: &str,
+) -> (Status, TestClient) {
+    let mock = mock_provider().await;
+    let issuer = mock.issuer.clone();
+
+    let client = TestClient::with_config(|figment| {
+        figment
+            .merge(("oidc.mock.issuer", issuer.clone()))
+            .merge(("oidc.mock.client_id", "mdj"))
+    })
+    .await;
+
+    let response = client.client.get("/login/oidc/mock").dispatch().await;
+    let location = response.headers().get_one("Location").unwrap().to_string();
+    let param = |name: &str| {
+        let prefix = format!("{}=", name);
+        location
+            .split(|c| c == '?' || c == '&')
+            .find_map(|p| p.strip_prefix(&prefix).map(String::from))
+            .unwrap()
+    };
+    let (state, nonce) = (param("state"), param("nonce"));
+
+    let mut header = jsonwebtoken::Header::new(alg);
+    header.kid = kid.map(String::from);
+
+    let claims = json!({
+        "iss": issuer,
+        "aud": "mdj",
+        "sub": Uuid::new_v4().to_string(),
+        "exp": chrono::Utc::now().timestamp() + 300,
+        "nonce": nonce,
+        "email": email,
+        "email_verified": true,
+    });
+    *mock.id_token.lock().unwrap() = jsonwebtoken::encode(&header, &claims, &key).unwrap();
+
+    let uri = format!("/login/oidc/mock/callback?code=code&state={}", state);
+    let response = client.client.get(uri).dispatch().await;
+    (response.status(), client)
+}
+
+#[rocket::async_test]
+async fn oidc_login_picks_the_key_by_kid() {
+    let key = EncodingKey::from_rsa_pem(OIDC_PRIVATE_KEY.as_bytes()).unwrap();
+    let (status, client) = oidc_login(Algorithm::RS256, key, Some("real"), &random_email()).await;
+
+    assert_eq!(status, Status::SeeOther);
+    assert!(client
+        .client
+        .cookies()
+        .get_private(COOKIE_SESSION_NAME)
+        .is_some());
+}
+
+#[rocket::async_test]
+async fn oidc_login_rejects_other_algorithms() {
+    // HS256 keyed with the public key, which would pass if the header algorithm was trusted
+    let key = EncodingKey::from_secret(OIDC_PUBLIC_KEY.as_bytes());
+    let (status, _) = oidc_login(Algorithm::HS256, key, Some("real"), &random_email()).await;
+    assert_eq!(status, Status::Unauthorized);
+
+    // Without a kid, the key is ambiguous among those of the provider
+    let key = EncodingKey::from_rsa_pem(OIDC_PRIVATE_KEY.as_bytes()).unwrap();
+    let (status, _) = oidc_login(Algorithm::RS256, key, None, &random_email()).await;
+    assert_eq!(status, Status::Unauthorized);
+}
+
+#[rocket::async_test]
+async fn oidc_login_takes_over_unverified_accounts() {
+    migrate();
+    let mut db = connect();
+    let email = random_email();
+
+    let squatter = {
+        use schema::accounts::dsl;
+
+        diesel::insert_into(dsl::accounts)
+            .values((
+                dsl::email.eq(&email),
+                dsl::password.eq(bcrypt::hash("squatter's password", 4).unwrap()),
+            ))
+            .returning(dsl::id)
+            .get_result::<Uuid>(&mut db)
+            .unwrap()
+    };
+    let client_info = ClientInfo {
+        user_agent: None,
+        ip: None,
+    };
+    session::create(&mut db, squatter, client_info).unwrap();
+
+    let key = EncodingKey::from_rsa_pem(OIDC_PRIVATE_KEY.as_bytes()).unwrap();
+    let (status, _) = oidc_login(Algorithm::RS256, key, Some("real"), &email.to_uppercase()).await;
+    assert_eq!(status, Status::SeeOther);
+
+    let (password, verified) = {
+        use schema::accounts::dsl;
+
+        dsl::accounts
+            .find(squatter)
+            .select((dsl::password, dsl::email_verified))
+            .first::<(Option<String>, bool)>(&mut db)
+            .unwrap()
+    };
+    assert_eq!(password, None);
+    assert!(verified);
+
+    // Only the session opened by the provider login is left
+    let sessions = {
+        use schema::sessions::dsl;
+
+        dsl::sessions
+            .filter(dsl::account.eq(squatter))
+            .count()
+            .get_result::<i64>(&mut db)
+            .unwrap()
+    };
+    assert_eq!(sessions, 1);
+}
+
+/// SMTP sink listening on a free port, which accepts every email and keeps their recipients
+struct SmtpSink {
+    port: u16,
+    recipients: Arc<Mutex<Vec<String>>>,
+}
+
+impl SmtpSink {
+    fn start() -> Self {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let recipients = Arc::new(Mutex::new(Vec::new()));
+
+        let received = recipients.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut in_data = false;
+
+                stream.write_all(b"220 sink\r\n").unwrap();
+
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                    let command = line.trim_end().to_ascii_uppercase();
+
+                    let reply: &[u8] = match command.as_str() {
+                        "." if in_data => {
+                            in_data = false;
+                            b"250 queued\r\n"
+                        }
+                        _ if in_data => b"",
+                        "DATA" => {
+                            in_data = true;
+                            b"354 go on\r\n"
+                        }
+                        "QUIT" => b"221 bye\r\n",
+                        c if c.starts_with("RCPT TO:") => {
+                            let to = line.trim_end()[8..].trim_matches(|c| c == '<' || c == '>');
+                            received.lock().unwrap().push(to.to_string());
+                            b"250 ok\r\n"
+                        }
+                        _ => b"250 ok\r\n",
+                    };
+
+                    stream.write_all(reply).unwrap();
+                    line.clear();
+                }
+            }
+        });
+
+        SmtpSink { port, recipients }
+    }
+}
+
+/// Held by the digest tests, as the scheduler of one could otherwise send the digests of the other
+static DIGESTS: Mutex<()> = Mutex::new(());
+
+/// Client whose emails go to `port`, along with its database connection
+async fn digest_client(port: u16) -> (TestClient, crate::DbConn, String) {
+    let client = TestClient::with_config(|figment| {
+        figment
+            .merge(("smtp.host", "127.0.0.1"))
+            .merge(("smtp.port", port))
+            .merge(("smtp.from", "mdj@example.com"))
+            .merge(("smtp.tls", false))
+    })
+    .await;
+
+    let conn = crate::DbConn::get_one(client.client.rocket())
+        .await
+        .unwrap();
+
+    let settings = json!({ "enabled": true, "time": "00:00:00", "utc_offset": 0 });
+    let request = client.client.put("/api/account/digest");
+    let (status, _) = client.send(request, settings).await;
+    assert_eq!(status, Status::Ok);
+
+    let course = json!({
+        "name": "Digest",
+        "description": "",
+        "j_0": chrono::Utc::today().naive_utc().format("%Y-%m-%d").to_string(),
+        "j_end": "2099-12-31",
+        "recurrence": "0,1,3,7",
+    });
+    let (status, _) = client
+        .send(client.client.post("/api/courses"), course)
+        .await;
+    assert_eq!(status, Status::Ok);
+
+    let email = {
+        use schema::accounts::dsl;
+
+        dsl::accounts
+            .find(client.account)
+            .select(dsl::email)
+            .first::<String>(&mut connect())
+            .unwrap()
+    };
+
+    (client, conn, email)
+}
+
+/// Digest tokens and last sending date of an account
+fn digest_state(account: Uuid) -> (i64, Option<chrono::NaiveDate>) {
+    let mut db = connect();
+
+    let tokens = {
+        use schema::digest_tokens::dsl;
+
+        dsl::digest_tokens
+            .filter(dsl::account.eq(account))
+            .count()
+            .get_result::<i64>(&mut db)
+            .unwrap()
+    };
+
+    let last_sent = {
+        use schema::accounts::dsl;
+
+        dsl::accounts
+            .find(account)
+            .select(dsl::digest_last_sent)
+            .first::<Option<chrono::NaiveDate>>(&mut db)
+            .unwrap()
+    };
+
+    (tokens, last_sent)
+}
+
+#[rocket::async_test]
+async fn digest_is_sent_once_a_day() {
+    let _digests = DIGESTS.lock().unwrap_or_else(|e| e.into_inner());
+    let sink = SmtpSink::start();
+    let (client, conn, email) = digest_client(sink.port).await;
+    let config = client.client.rocket().state::<crate::Config>().unwrap();
+    let smtp = config.smtp.clone().unwrap();
+
+    let mut failures = std::collections::HashMap::new();
+    for _ in 0..2 {
+        crate::digest::send_due_digests(&conn, &smtp, &config.public_url, &mut failures)
+            .await
+            .unwrap();
+    }
+
+    let sent = sink
+        .recipients
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|to| **to == email)
+        .count();
+    assert_eq!(sent, 1);
+    assert_eq!(
+        digest_state(client.account),
+        (1, Some(chrono::Utc::today().naive_utc()))
+    );
+}
+
+#[rocket::async_test]
+async fn failed_digests_are_retried_without_tokens() {
+    let _digests = DIGESTS.lock().unwrap_or_else(|e| e.into_inner());
+
+    // Nothing listens on the port once the listener is dropped
+    let (client, conn, _) = digest_client(free_port()).await;
+    let config = client.client.rocket().state::<crate::Config>().unwrap();
+    let smtp = config.smtp.clone().unwrap();
+
+    let mut failures = std::collections::HashMap::new();
+    crate::digest::send_due_digests(&conn, &smtp, &config.public_url, &mut failures)
+        .await
+        .unwrap();
+
+    assert_eq!(digest_state(client.account), (0, None));
+    assert_eq!(failures.get(&client.account).map(|(_, f)| *f), Some(1));
+}
+
+/// Endpoints the mock push service received messages for
+struct MockPushService(Mutex<Vec<String>>);
+
+/// Accepts messages, except for endpoints starting with `gone`, which have expired
+#[post("/push/<endpoint>")]
+fn mock_push(endpoint: &str, mock: &State<Arc<MockPushService>>) -> Status {
+    mock.0.lock().unwrap().push(endpoint.to_string());
+
+    match endpoint.starts_with("gone") {
+        true => Status::Gone,
+        false => Status::Created,
+    }
+}
+
+#[rocket::async_test]
+async fn push_notifications_are_sent_and_expired_endpoints_removed() {
+    use p256::elliptic_curve::sec1::ToEncodedPoint;
+
+    let port = free_port();
+    let mock = Arc::new(MockPushService(Mutex::new(Vec::new())));
+    launch_mock(port, routes![mock_push], mock.clone()).await;
+
+    let client = TestClient::new().await;
+    let today = chrono::Utc::today().naive_utc();
+    let course = json!({
+        "name": "Push",
+        "description": "",
+        "j_0": today.format("%Y-%m-%d").to_string(),
+        "j_end": "2099-12-31",
+        "recurrence": "0,1,3,7",
+    });
+    let (status, _) = client
+        .send(client.client.post("/api/courses"), course)
+        .await;
+    assert_eq!(status, Status::Ok);
+
+    let settings = json!({ "enabled": false, "time": "00:00:00", "utc_offset": 0 });
+    let request = client.client.put("/api/account/digest");
+    assert_eq!(client.send(request, settings).await.0, Status::Ok);
+
+    let (ok, gone) = (Uuid::new_v4(), format!("gone-{}", Uuid::new_v4()));
+    for endpoint in [ok.to_string(), gone.clone()] {
+        let key = p256::SecretKey::random(&mut rand::rngs::OsRng);
+        let p256dh = key.public_key().to_encoded_point(false);
+        let subscription = json!({
+            "endpoint": format!("http://127.0.0.1:{}/push/{}", port, endpoint),
+            "keys": {
+                "p256dh": base64::encode_config(p256dh.as_bytes(), base64::URL_SAFE_NO_PAD),
+                "auth": base64::encode_config(Uuid::new_v4().as_bytes(), base64::URL_SAFE_NO_PAD),
+            },
+        });
+        let request = client.client.post("/api/push/subscriptions");
+        assert_eq!(client.send(request, subscription).await.0, Status::Ok);
+    }
+
+    // The scheduler of any test client can send them first, either way they are sent by the time
+    // this returns
+    let conn = crate::DbConn::get_one(client.client.rocket())
+        .await
+        .unwrap();
+    let push_client = web_push::IsahcWebPushClient::new().unwrap();
+    crate::push::send_due_notifications(&conn, &push_client, "https://mdj.example.com")
+        .await
+        .unwrap();
+
+    let received = mock.0.lock().unwrap().clone();
+    assert!(received.contains(&ok.to_string()));
+    assert!(received.contains(&gone));
+
+    let mut db = connect();
+    let endpoints = {
+        use schema::push_subscriptions::dsl;
+
+        dsl::push_subscriptions
+            .filter(dsl::account.eq(client.account))
+            .select(dsl::endpoint)
+            .load::<String>(&mut db)
+            .unwrap()
+    };
+    assert_eq!(endpoints.len(), 1);
+    assert!(endpoints[0].ends_with(&ok.to_string()));
+
+    let last_sent = {
+        use schema::accounts::dsl;
+
+        dsl::accounts
+            .find(client.account)
+            .select(dsl::push_last_sent)
+            .first::<Option<chrono::NaiveDate>>(&mut db)
+            .unwrap()
+    };
+    assert_eq!(last_sent, Some(today));
+}
+
+#[rocket::async_test]
+async fn export_redacts_push_endpoints_and_webhook_urls() {
+    let client = TestClient::new().await;
+
+    let subscription = json!({
+        "endpoint": format!("https://push.example.com/send/{}", Uuid::new_v4()),
+        "keys": { "p256dh": "key", "auth": "auth" },
+    });
+    let request = client.client.post("/api/push/subscriptions");
+    assert_eq!(client.send(request, subscription).await.0, Status::Ok);
+
+    let (status, export) = client.get("/api/export").await;
+    assert_eq!(status, Status::Ok);
+    assert_eq!(
+        export["push_subscriptions"][0]["push_service"],
+        "https://push.example.com"
+    );
+    assert!(!export.to_string().contains("/send/"));
+}
@@ -0,0 +1,207 @@
+use crate::api_result::ApiResult;
+use crate::import::{default_recurrence, import, ImportReport, ImportedCourse};
+use crate::model::NewEvent;
+use crate::{Account, DbConn};
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use regex::Regex;
+use rocket::data::{Data, ToByteUnit};
+use rocket::http::Status;
+
+/// The few VEVENT properties a timetable import cares about
+#[derive(Default)]
+struct TimetableEvent {
+    uid: Option<String>,
+    summary: Option<String>,
+    description: Option<String>,
+    start: Option<NaiveDate>,
+}
+
+/// Joins folded content lines back together (RFC 5545, section 3.1)
+pub fn unfold(ics: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+
+    for line in ics.split('\n').map(|l| l.strip_suffix('\r').unwrap_or(l)) {
+        match (
+            line.strip_prefix(|c: char| c == ' ' || c == '\t'),
+            lines.last_mut(),
+        ) {
+            (Some(continuation), Some(last)) => last.push_str(continuation),
+            _ => lines.push(line.to_string()),
+        }
+    }
+
+    lines
+}
+
+fn unescape_text(value: &str) -> String {
+    let mut text = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') | Some('N') => text.push('\n'),
+                Some(escaped) => text.push(escaped),
+                None => {}
+            },
+            c => text.push(c),
+        }
+    }
+
+    text
+}
+
+/// Splits a content line into its name (without parameters) and value
+pub fn split_property(line: &str) -> Option<(&str, &str)> {
+    let mut quoted = false;
+    let colon = line.char_indices().find_map(|(i, c)| match c {
+        '"' => {
+            quoted = !quoted;
+            None
+        }
+        ':' if !quoted => Some(i),
+        _ => None,
+    })?;
+
+    let (name, value) = (&line[..colon], &line[colon + 1..]);
+    let name = name.split(';').next().unwrap_or(name);
+    Some((name, value))
+}
+
+/// Date of a `DTSTART` value, in the user's local time for UTC times (`…T230000Z`)
+///
+/// Dates, floating times and times with a `TZID` are taken as they are: the date is that of the
+/// lecture where it takes place, which is the user's in practice.
+pub fn start_date(value: &str, utc_offset: Duration) -> Option<NaiveDate> {
+    if let Some(utc) = value.strip_suffix(|c| c == 'Z' || c == 'z') {
+        let time = NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S").ok()?;
+        return Some((time + utc_offset).date());
+    }
+
+    value
+        .get(..8)
+        .and_then(|date| NaiveDate::parse_from_str(date, "%Y%m%d").ok())
+}
+
+/// Extracts top-level VEVENTs from an iCalendar file
+///
+/// `RRULE`s are ignored: timetables published by universities generally list every lecture as its
+/// own event.
+fn parse_events(ics: &str, utc_offset: Duration) -> Vec<TimetableEvent> {
+    let mut events = Vec::new();
+    let mut current: Option<TimetableEvent> = None;
+    let mut depth = 0;
+
+    for line in unfold(ics) {
+        let (name, value) = match split_property(&line) {
+            Some(property) => property,
+            None => continue,
+        };
+
+        let name = name.to_ascii_uppercase();
+
+        let event = match current.as_mut() {
+            Some(event) => event,
+            None => {
+                if name == "BEGIN" && value.eq_ignore_ascii_case("VEVENT") {
+                    current = Some(TimetableEvent::default());
+                }
+                continue;
+            }
+        };
+
+        match name.as_str() {
+            "BEGIN" => depth += 1,
+            "END" if depth > 0 => depth -= 1,
+            "END" => events.extend(current.take()),
+            _ if depth > 0 => {}
+            "UID" => event.uid = Some(value.to_string()),
+            "SUMMARY" => event.summary = Some(unescape_text(value)),
+            "DESCRIPTION" => event.description = Some(unescape_text(value)),
+            "DTSTART" => event.start = start_date(value.trim(), utc_offset),
+            _ => {}
+        }
+    }
+
+    events
+}
+
+/// Creates one course per lecture of an uploaded `.ics` timetable
+///
+/// Every course uses the default recurrence preset, starting on the lecture date. Lectures can be
+/// selected with a `summary` regex and/or a list of `uid`s, and `dedup` (enabled by default) skips
+/// lectures for which a course with the same name and J0 already exists.
+///
+/// `utc_offset` is the offset of the user's local time to UTC in minutes, used to find the date of
+/// lectures given in UTC.
+#[post(
+    "/api/import/ical?<summary>&<uid>&<dedup>&<dry_run>&<utc_offset>",
+    format = "text/calendar",
+    data = "<data>"
+)]
+pub async fn import_ical(
+    db: DbConn,
+    a: Account,
+    summary: Option<String>,
+    uid: Vec<String>,
+    dedup: Option<bool>,
+    dry_run: Option<bool>,
+    utc_offset: Option<i32>,
+    data: Data<'_>,
+) -> ApiResult<ImportReport> {
+    let utc_offset = match utc_offset.unwrap_or(0) {
+        offset if offset.abs() <= 14 * 60 => Duration::minutes(offset as i64),
+        _ => return ApiResult::Error(Status::BadRequest, "invalid_utc_offset"),
+    };
+
+    let summary = match summary.as_deref().map(Regex::new).transpose() {
+        Ok(summary) => summary,
+        Err(_) => return ApiResult::Error(Status::BadRequest, "invalid_regex"),
+    };
+
+    let ics = match data.open(2.mebibytes()).into_string().await {
+        Ok(ics) if ics.is_complete() => ics.into_inner(),
+        Ok(_) => return ApiResult::Error(Status::PayloadTooLarge, "payload_too_large"),
+        Err(_) => return ApiResult::Error(Status::BadRequest, "invalid_encoding"),
+    };
+
+    let recurrence = default_recurrence();
+    let last_offset = NewEvent::parse_recurrence(&recurrence)
+        .last()
+        .copied()
+        .unwrap_or(0);
+
+    let courses = parse_events(&ics, utc_offset)
+        .into_iter()
+        .filter(|e| uid.is_empty() || e.uid.as_ref().map_or(false, |u| uid.contains(u)))
+        .filter_map(|e| {
+            let name = e.summary.unwrap_or_default();
+
+            if let Some(summary) = &summary {
+                if !summary.is_match(&name) {
+                    return None;
+                }
+            }
+
+            Some(match e.start {
+                Some(j_0) => Ok(ImportedCourse {
+                    name,
+                    description: e.description,
+                    j_0,
+                    j_end: j_0 + Duration::days(last_offset as _),
+                    recurrence: recurrence.clone(),
+                    tags: Vec::new(),
+                    archived: false,
+                    markings: Vec::new(),
+                }),
+                None => Err((name, String::from("missing or invalid DTSTART"))),
+            })
+        })
+        .collect();
+
+    let report = with_db!(db => {
+        import(db, a.id, courses, dry_run.unwrap_or(false), dedup.unwrap_or(true))
+    }?);
+
+    ApiResult::Ok(report)
+}
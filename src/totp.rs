@@ -0,0 +1,394 @@
+use crate::api_result::ApiResult;
+use crate::api_token::hash_token;
+use crate::csrf::SameOrigin;
+use crate::rate_limit::{LoginLimiter, RateLimited};
+use crate::session::{self, ClientInfo};
+use crate::{random_token, schema, Config, CookieAccount, DbConn, LoginError};
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::PgConnection;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rocket::form::{Form, FromForm};
+use rocket::http::{Cookie, CookieJar, RawStr, SameSite, Status};
+use rocket::response::content::RawJson;
+use rocket::response::Redirect;
+use rocket::serde::json::Json;
+use rocket::State;
+use sha1::Sha1;
+use uuid::Uuid;
+
+const ISSUER: &str = "Méthode des J";
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+const RECOVERY_CODES: usize = 10;
+
+/// Private cookie holding the token of a login waiting for its second factor
+pub const COOKIE_PENDING_LOGIN_NAME: &str = "mdj:pending_login";
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+
+    encoded
+}
+
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+
+    for c in encoded.bytes().filter(|c| *c != b'=') {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(bytes)
+}
+
+/// RFC 6238 code of a time step, with the default SHA-1 and 6 digits
+fn code_at(secret: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let truncated = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    truncated % 10u32.pow(DIGITS)
+}
+
+/// Returns the time step `code` belongs to, accepting one step of clock drift either way
+fn verify_code(secret: &str, code: &str) -> Option<i64> {
+    let secret = base32_decode(secret)?;
+    let code = code.trim().parse::<u32>().ok()?;
+    let current = Utc::now().timestamp() / STEP_SECONDS;
+
+    (current - 1..=current + 1).find(|step| code_at(&secret, *step) == code)
+}
+
+/// Checks a TOTP or recovery code of `account`, consuming it if it is valid
+fn check_second_factor(db: &mut PgConnection, account: Uuid, code: &str) -> QueryResult<bool> {
+    use schema::accounts::dsl as a_dsl;
+    use schema::totp_recovery_codes::dsl as r_dsl;
+
+    let secret = a_dsl::accounts
+        .find(account)
+        .select(a_dsl::totp_secret)
+        .first::<Option<String>>(db)?;
+
+    if let Some(step) = secret.and_then(|secret| verify_code(&secret, code)) {
+        // Checked by the update itself, so that concurrent logins can't both use the same code
+        let accepted = diesel::update(a_dsl::accounts.find(account))
+            .filter(
+                a_dsl::totp_last_step
+                    .is_null()
+                    .or(a_dsl::totp_last_step.lt(step)),
+            )
+            .set(a_dsl::totp_last_step.eq(step))
+            .execute(db)?;
+
+        if accepted > 0 {
+            return Ok(true);
+        }
+    }
+
+    let code_hash = hash_token(&code.trim().to_lowercase());
+    let used = diesel::delete(r_dsl::totp_recovery_codes.find((account, code_hash))).execute(db)?;
+
+    Ok(used > 0)
+}
+
+#[derive(serde::Serialize)]
+pub struct TotpEnrolment {
+    secret: String,
+    /// `otpauth://` URI, usually shown as a QR code
+    uri: String,
+}
+
+/// Starts enabling 2FA by generating a new secret, which is only used once confirmed with
+/// `/api/account/totp/verify`
+#[post("/api/account/totp")]
+pub async fn totp_enrol(db: DbConn, a: CookieAccount) -> ApiResult<TotpEnrolment> {
+    let CookieAccount(a) = a;
+    let mut secret = [0; 20];
+    rand::thread_rng().fill_bytes(&mut secret);
+    let secret = base32_encode(&secret);
+
+    let stored_secret = secret.clone();
+    let enrolling = with_db!(db => {
+        use schema::accounts::dsl;
+
+        diesel::update(dsl::accounts.find(a.id).filter(dsl::totp_enabled.eq(false)))
+            .set(dsl::totp_secret.eq(stored_secret))
+            .execute(db)
+    }?);
+
+    if enrolling == 0 {
+        return ApiResult::Error(Status::Conflict, "totp_already_enabled");
+    }
+
+    let label = format!("{}:{}", ISSUER, a.email);
+    let uri = format!(
+        "otpauth://totp/{}?secret={}&issuer={}",
+        RawStr::new(&label).percent_encode(),
+        secret,
+        RawStr::new(ISSUER).percent_encode(),
+    );
+
+    ApiResult::Ok(TotpEnrolment { secret, uri })
+}
+
+#[derive(serde::Deserialize)]
+pub struct TotpCode {
+    code: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct RecoveryCodes {
+    /// Single-use codes that replace a TOTP code, only shown once
+    recovery_codes: Vec<String>,
+}
+
+/// Enables 2FA once the user proves their authenticator works, and returns recovery codes
+#[post("/api/account/totp/verify", data = "<json>")]
+pub async fn totp_verify(
+    db: DbConn,
+    a: CookieAccount,
+    json: Json<TotpCode>,
+) -> ApiResult<RecoveryCodes> {
+    let CookieAccount(a) = a;
+    let code = json.into_inner().code;
+    let recovery_codes = (0..RECOVERY_CODES)
+        .map(|_| random_token(10).to_lowercase())
+        .collect::<Vec<_>>();
+    let hashes = recovery_codes
+        .iter()
+        .map(|c| hash_token(c))
+        .collect::<Vec<_>>();
+
+    let enabled = with_db!(db => {
+        use schema::accounts::dsl as a_dsl;
+        use schema::totp_recovery_codes::dsl as r_dsl;
+
+        db.transaction::<_, diesel::result::Error, _>(|db| {
+            let (secret, enabled) = a_dsl::accounts
+                .find(a.id)
+                .select((a_dsl::totp_secret, a_dsl::totp_enabled))
+                .first::<(Option<String>, bool)>(db)?;
+
+            let step = match secret.filter(|_| !enabled) {
+                Some(secret) => verify_code(&secret, &code),
+                None => None,
+            };
+
+            let step = match step {
+                Some(step) => step,
+                None => return Ok(false),
+            };
+
+            diesel::update(a_dsl::accounts.find(a.id))
+                .set((a_dsl::totp_enabled.eq(true), a_dsl::totp_last_step.eq(step)))
+                .execute(db)?;
+
+            diesel::delete(r_dsl::totp_recovery_codes.filter(r_dsl::account.eq(a.id)))
+                .execute(db)?;
+
+            let rows = hashes
+                .into_iter()
+                .map(|hash| (r_dsl::account.eq(a.id), r_dsl::code_hash.eq(hash)))
+                .collect::<Vec<_>>();
+
+            diesel::insert_into(r_dsl::totp_recovery_codes)
+                .values(rows)
+                .execute(db)?;
+
+            Ok(true)
+        })
+    }?);
+
+    match enabled {
+        true => ApiResult::Ok(RecoveryCodes { recovery_codes }),
+        false => ApiResult::Error(Status::BadRequest, "invalid_code"),
+    }
+}
+
+/// Disables 2FA, with a TOTP or recovery code
+#[delete("/api/account/totp", data = "<json>")]
+pub async fn totp_disable(db: DbConn, a: CookieAccount, json: Json<TotpCode>) -> ApiResult {
+    let CookieAccount(a) = a;
+    let code = json.into_inner().code;
+
+    let disabled = with_db!(db => {
+        use schema::accounts::dsl as a_dsl;
+        use schema::totp_recovery_codes::dsl as r_dsl;
+
+        db.transaction::<_, diesel::result::Error, _>(|db| {
+            if !check_second_factor(db, a.id, &code)? {
+                return Ok(false);
+            }
+
+            diesel::update(a_dsl::accounts.find(a.id))
+                .set((
+                    a_dsl::totp_enabled.eq(false),
+                    a_dsl::totp_secret.eq(None::<String>),
+                    a_dsl::totp_last_step.eq(None::<i64>),
+                ))
+                .execute(db)?;
+
+            diesel::delete(r_dsl::totp_recovery_codes.filter(r_dsl::account.eq(a.id)))
+                .execute(db)?;
+
+            Ok(true)
+        })
+    }?);
+
+    match disabled {
+        true => ApiResult::success(),
+        false => ApiResult::Error(Status::BadRequest, "invalid_code"),
+    }
+}
+
+/// Starts a login that still needs its second factor, to be completed with [`login_totp`]
+pub fn start_pending_login(
+    db: &mut PgConnection,
+    account: Uuid,
+    client: ClientInfo,
+) -> QueryResult<String> {
+    use schema::pending_logins::dsl;
+
+    let token = random_token(64);
+
+    diesel::insert_into(dsl::pending_logins)
+        .values((
+            dsl::token_hash.eq(hash_token(&token)),
+            dsl::account.eq(account),
+            dsl::user_agent.eq(client.user_agent),
+            dsl::ip.eq(client.ip),
+        ))
+        .execute(db)?;
+
+    Ok(token)
+}
+
+pub fn set_pending_login_cookie(cookies: &CookieJar<'_>, config: &Config, token: String) {
+    cookies.add_private(
+        Cookie::build(COOKIE_PENDING_LOGIN_NAME, token)
+            .same_site(SameSite::Strict)
+            .secure(config.secure_cookies())
+            .max_age(rocket::time::Duration::minutes(5))
+            .finish(),
+    );
+}
+
+#[derive(FromForm)]
+pub struct TotpForm {
+    code: String,
+}
+
+/// Second step of `login` for accounts with 2FA
+#[post("/login/totp", data = "<form>")]
+pub async fn login_totp(
+    db: DbConn,
+    form: Form<TotpForm>,
+    cookies: &CookieJar<'_>,
+    config: &State<Config>,
+    limiter: &State<LoginLimiter>,
+    _same_origin: SameOrigin,
+) -> Result<Redirect, LoginError> {
+    let database_error = |_| {
+        (
+            Status::InternalServerError,
+            RawJson(r#"{"error_kind":"database"}"#),
+        )
+    };
+
+    let unauthorized = |body| LoginError::from((Status::Unauthorized, RawJson(body)));
+
+    let token = match cookies.get_private(COOKIE_PENDING_LOGIN_NAME) {
+        Some(cookie) => cookie.value().to_string(),
+        None => return Err(unauthorized(r#"{"error_kind":"no_pending_login"}"#)),
+    };
+
+    let token_hash = hash_token(&token);
+    let lookup_hash = token_hash.clone();
+
+    let pending = with_db!(db => {
+        use schema::pending_logins::dsl;
+
+        dsl::pending_logins
+            .find(lookup_hash)
+            .filter(dsl::expires_at.gt(diesel::dsl::now))
+            .select((dsl::account, dsl::user_agent, dsl::ip))
+            .first::<(Uuid, Option<String>, Option<String>)>(db)
+            .optional()
+    })
+    .map_err(database_error)?;
+
+    let (account, user_agent, ip) = match pending {
+        Some(pending) => pending,
+        None => {
+            cookies.remove_private(Cookie::named(COOKIE_PENDING_LOGIN_NAME));
+            return Err(unauthorized(r#"{"error_kind":"no_pending_login"}"#));
+        }
+    };
+
+    // Codes only have a million possible values
+    if let Err(retry_after) = limiter.take(&[format!("totp:{}", account)]) {
+        return Err(LoginError::RateLimited(RateLimited::new(retry_after)));
+    }
+
+    let code = form.into_inner().code;
+
+    let session = with_db!(db => {
+        use schema::pending_logins::dsl;
+
+        db.transaction::<_, diesel::result::Error, _>(|db| {
+            if !check_second_factor(db, account, &code)? {
+                return Ok(None);
+            }
+
+            diesel::delete(dsl::pending_logins.find(token_hash)).execute(db)?;
+
+            session::create(db, account, ClientInfo { user_agent, ip }).map(Some)
+        })
+    })
+    .map_err(database_error)?;
+
+    match session {
+        Some((token, expires)) => {
+            cookies.remove_private(Cookie::named(COOKIE_PENDING_LOGIN_NAME));
+            session::set_cookie(cookies, config, token, expires);
+            Ok(Redirect::to("/"))
+        }
+        None => Err(unauthorized(r#"{"error_kind":"invalid_code"}"#)),
+    }
+}
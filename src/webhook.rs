@@ -0,0 +1,572 @@
+use crate::api_result::ApiResult;
+use crate::{random_token, schema, Account, DbConn};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use hmac::{Hmac, Mac};
+use isahc::config::{Configurable, Dialer};
+use isahc::http::Uri;
+use isahc::{AsyncReadResponseExt, HttpClient};
+use rocket::fairing::AdHoc;
+use rocket::futures::stream::{self, StreamExt};
+use rocket::http::Status;
+use rocket::serde::json::{self, Json};
+use serde::Serialize;
+use sha2::Sha256;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Kinds of changes webhooks can subscribe to
+#[derive(Clone, Copy, serde::Serialize)]
+pub enum WebhookEvent {
+    #[serde(rename = "course.created")]
+    CourseCreated,
+    #[serde(rename = "course.updated")]
+    CourseUpdated,
+    #[serde(rename = "course.archived")]
+    CourseArchived,
+    #[serde(rename = "course.deleted")]
+    CourseDeleted,
+    #[serde(rename = "event.marked")]
+    EventMarked,
+}
+
+impl WebhookEvent {
+    const ALL: [Self; 5] = [
+        Self::CourseCreated,
+        Self::CourseUpdated,
+        Self::CourseArchived,
+        Self::CourseDeleted,
+        Self::EventMarked,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::CourseCreated => "course.created",
+            Self::CourseUpdated => "course.updated",
+            Self::CourseArchived => "course.archived",
+            Self::CourseDeleted => "course.deleted",
+            Self::EventMarked => "event.marked",
+        }
+    }
+}
+
+/// Body of every webhook request
+#[derive(serde::Serialize)]
+struct Payload<'a, T> {
+    /// Identical across retries, so that receivers can ignore duplicates
+    id: Uuid,
+    #[serde(rename = "type")]
+    event_type: WebhookEvent,
+    created_at: NaiveDateTime,
+    data: &'a T,
+}
+
+/// `data` of `course.deleted` events
+#[derive(serde::Serialize)]
+pub struct Deleted {
+    pub id: Uuid,
+}
+
+/// `data` of `event.marked` events
+#[derive(serde::Serialize)]
+pub struct Marked {
+    pub course: Uuid,
+    pub j: i64,
+    pub marking: Option<String>,
+}
+
+/// Queues `event` for every webhook of `account` that subscribed to it
+///
+/// It should be called in the same transaction as the change itself, so that nothing is sent for
+/// changes that are rolled back.
+pub fn enqueue<T: Serialize>(
+    db: &mut PgConnection,
+    account: Uuid,
+    event: WebhookEvent,
+    data: &T,
+) -> QueryResult<()> {
+    use schema::webhook_outbox::dsl as o_dsl;
+    use schema::webhooks::dsl as w_dsl;
+
+    let webhooks = w_dsl::webhooks
+        .filter(w_dsl::account.eq(account))
+        .filter(w_dsl::event_types.contains(vec![event.as_str()]))
+        .select(w_dsl::id)
+        .load::<Uuid>(db)?;
+
+    if webhooks.is_empty() {
+        return Ok(());
+    }
+
+    let now = Utc::now().naive_utc();
+
+    let payload = json::to_string(&Payload {
+        id: Uuid::new_v4(),
+        event_type: event,
+        created_at: now,
+        data,
+    })
+    .unwrap();
+
+    let rows = webhooks
+        .into_iter()
+        .map(|webhook| {
+            (
+                o_dsl::webhook.eq(webhook),
+                o_dsl::event_type.eq(event.as_str()),
+                o_dsl::payload.eq(payload.clone()),
+                o_dsl::next_attempt_at.eq(now),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    diesel::insert_into(o_dsl::webhook_outbox)
+        .values(rows)
+        .execute(db)?;
+
+    Ok(())
+}
+
+/// Reasons for refusing to send requests to a webhook URL, also recorded as delivery errors
+#[derive(Clone, Copy, Debug)]
+enum TargetError {
+    InvalidUrl,
+    /// Plain HTTP is only accepted in debug builds, for local testing
+    InsecureUrl,
+    UnresolvableHost,
+    /// The host resolves to an address of the server's own network, loopback being only accepted
+    /// in debug builds, for local testing
+    ForbiddenAddress,
+}
+
+impl TargetError {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::InvalidUrl => "invalid_url",
+            Self::InsecureUrl => "insecure_url",
+            Self::UnresolvableHost => "unresolvable_host",
+            Self::ForbiddenAddress => "forbidden_address",
+        }
+    }
+}
+
+/// Whether `ip` is reachable on the public internet, as opposed to loopback, private, link-local
+/// (including cloud metadata endpoints) or unique-local addresses
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // 0.0.0.0/8 and the 100.64.0.0/10 shared address space
+                || a == 0
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+
+            let first = ip.segments()[0];
+
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // fc00::/7 unique-local and fe80::/10 link-local
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// Resolves the host of a webhook URL, refusing the ones that point to the server's own network
+///
+/// This runs both when the webhook is registered and before each delivery, which is then sent to
+/// the returned address so that the host can't be re-resolved to another one in between.
+async fn resolve_target(url: &str) -> Result<SocketAddr, TargetError> {
+    let uri = url.parse::<Uri>().map_err(|_| TargetError::InvalidUrl)?;
+
+    let default_port = match uri.scheme_str() {
+        Some("https") => 443,
+        Some("http") if cfg!(debug_assertions) => 80,
+        Some("http") => return Err(TargetError::InsecureUrl),
+        _ => return Err(TargetError::InvalidUrl),
+    };
+
+    let host = uri.host().ok_or(TargetError::InvalidUrl)?;
+    // IPv6 literals are bracketed in URLs
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = uri.port_u16().unwrap_or(default_port);
+
+    let addresses = rocket::tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|_| TargetError::UnresolvableHost)?
+        .collect::<Vec<_>>();
+
+    let allowed = |ip: IpAddr| is_public(ip) || (cfg!(debug_assertions) && ip.is_loopback());
+
+    // Every address is checked, as any of them may be the one that ends up being used
+    if addresses.iter().any(|a| !allowed(a.ip())) {
+        return Err(TargetError::ForbiddenAddress);
+    }
+
+    addresses
+        .into_iter()
+        .next()
+        .ok_or(TargetError::UnresolvableHost)
+}
+
+#[derive(Queryable, serde::Serialize)]
+pub struct Webhook {
+    id: Uuid,
+    url: String,
+    event_types: Vec<String>,
+    created_at: NaiveDateTime,
+}
+
+#[get("/api/webhooks")]
+pub async fn webhooks(db: DbConn, a: Account) -> ApiResult<Vec<Webhook>> {
+    let webhooks = with_db!(db => {
+        use schema::webhooks::dsl;
+
+        dsl::webhooks
+            .filter(dsl::account.eq(a.id))
+            .order_by(dsl::created_at.asc())
+            .select((dsl::id, dsl::url, dsl::event_types, dsl::created_at))
+            .load::<Webhook>(db)
+    }?);
+
+    ApiResult::Ok(webhooks)
+}
+
+#[derive(serde::Deserialize)]
+pub struct NewWebhook {
+    url: String,
+    /// Key of the `X-Mdj-Signature` HMAC, generated if missing
+    secret: Option<String>,
+    event_types: Vec<String>,
+}
+
+/// The secret is only returned when the webhook is created
+#[derive(serde::Serialize)]
+pub struct CreatedWebhook {
+    #[serde(flatten)]
+    webhook: Webhook,
+    secret: String,
+}
+
+#[post("/api/webhooks", data = "<json>")]
+pub async fn webhooks_insert(
+    db: DbConn,
+    a: Account,
+    json: Json<NewWebhook>,
+) -> ApiResult<CreatedWebhook> {
+    let NewWebhook {
+        url,
+        secret,
+        event_types,
+    } = json.into_inner();
+
+    if let Err(e) = resolve_target(&url).await {
+        return ApiResult::Error(Status::BadRequest, e.as_str());
+    }
+
+    let known = |t: &String| WebhookEvent::ALL.iter().any(|e| e.as_str() == t);
+    if event_types.is_empty() || !event_types.iter().all(known) {
+        return ApiResult::Error(Status::BadRequest, "invalid_event_type");
+    }
+
+    let secret = secret
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| random_token(32));
+    let stored_secret = secret.clone();
+
+    let webhook = with_db!(db => {
+        use schema::webhooks::dsl;
+
+        diesel::insert_into(dsl::webhooks)
+            .values((
+                dsl::account.eq(a.id),
+                dsl::url.eq(url),
+                dsl::secret.eq(stored_secret),
+                dsl::event_types.eq(event_types),
+            ))
+            .returning((dsl::id, dsl::url, dsl::event_types, dsl::created_at))
+            .get_result::<Webhook>(db)
+    }?);
+
+    ApiResult::Ok(CreatedWebhook { webhook, secret })
+}
+
+#[delete("/api/webhooks/<id>")]
+pub async fn webhooks_delete(db: DbConn, a: Account, id: Uuid) -> ApiResult {
+    with_db!(db => {
+        use schema::webhooks::dsl;
+
+        diesel::delete(dsl::webhooks)
+            .filter(dsl::account.eq(a.id).and(dsl::id.eq(id)))
+            .execute(db)
+    }?);
+
+    ApiResult::success()
+}
+
+#[derive(Queryable, serde::Serialize)]
+pub struct Delivery {
+    id: i64,
+    outbox_id: i64,
+    event_type: String,
+    attempt: i32,
+    /// Missing if no response was received
+    status_code: Option<i32>,
+    /// Why no response was received, such as `timeout` or `connection_failed`
+    error: Option<String>,
+    delivered_at: NaiveDateTime,
+}
+
+/// Returns the 50 last delivery attempts of a webhook
+#[get("/api/webhooks/<id>/deliveries")]
+pub async fn webhooks_deliveries(db: DbConn, a: Account, id: Uuid) -> ApiResult<Vec<Delivery>> {
+    let deliveries = with_db!(db => {
+        use schema::webhook_deliveries::dsl as d_dsl;
+        use schema::webhooks::dsl as w_dsl;
+
+        w_dsl::webhooks
+            .filter(w_dsl::account.eq(a.id).and(w_dsl::id.eq(id)))
+            .select(w_dsl::id)
+            .first::<Uuid>(db)?;
+
+        d_dsl::webhook_deliveries
+            .filter(d_dsl::webhook.eq(id))
+            .order_by(d_dsl::delivered_at.desc())
+            .limit(50)
+            .select((
+                d_dsl::id,
+                d_dsl::outbox_id,
+                d_dsl::event_type,
+                d_dsl::attempt,
+                d_dsl::status_code,
+                d_dsl::error,
+                d_dsl::delivered_at,
+            ))
+            .load::<Delivery>(db)
+    }?);
+
+    ApiResult::Ok(deliveries)
+}
+
+/// Deliveries are given up on after this many attempts
+const MAX_ATTEMPTS: i32 = 10;
+
+/// Time outbox entries are claimed for by [`send_due_webhooks`], after which they are picked up
+/// again if the server stopped before recording their delivery
+const CLAIM_MINUTES: i64 = 5;
+
+/// Deliveries sent at the same time, so that a slow endpoint doesn't hold back the others
+const CONCURRENT_DELIVERIES: usize = 10;
+
+#[derive(Queryable)]
+struct PendingDelivery {
+    id: i64,
+    event_type: String,
+    payload: String,
+    attempts: i32,
+    webhook: Uuid,
+    url: String,
+    secret: String,
+}
+
+/// `sha256=` followed by the hex HMAC-SHA256 of `{timestamp}.{payload}`, keyed with the secret
+fn signature(secret: &str, timestamp: i64, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(payload.as_bytes());
+
+    let hex = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b));
+    format!("sha256={}", hex.collect::<String>())
+}
+
+/// Sends a delivery, returning the response status if one was received, or an error kind
+/// otherwise
+///
+/// Transport errors are only logged, as their details could reveal things about the network the
+/// server runs in.
+async fn deliver(client: &HttpClient, d: &PendingDelivery) -> (Option<u16>, Option<String>) {
+    let address = match resolve_target(&d.url).await {
+        Ok(address) => address,
+        Err(e) => return (None, Some(e.as_str().to_string())),
+    };
+
+    let timestamp = Utc::now().timestamp();
+
+    let request = isahc::Request::post(&d.url)
+        .header("Content-Type", "application/json")
+        .header("User-Agent", "mdj-webhooks")
+        .header("X-Mdj-Event", &d.event_type)
+        .header("X-Mdj-Delivery", d.id.to_string())
+        .header("X-Mdj-Timestamp", timestamp.to_string())
+        .header(
+            "X-Mdj-Signature",
+            signature(&d.secret, timestamp, &d.payload),
+        )
+        .dial(Dialer::ip_socket(address))
+        .timeout(Duration::from_secs(10))
+        .body(d.payload.clone());
+
+    let request = match request {
+        Ok(request) => request,
+        Err(_) => return (None, Some(TargetError::InvalidUrl.as_str().to_string())),
+    };
+
+    match client.send_async(request).await {
+        Ok(mut response) => {
+            // The body is ignored, but it has to be read for the connection to be reused
+            let _ = response.consume().await;
+            (Some(response.status().as_u16()), None)
+        }
+        Err(e) => {
+            eprintln!("couldn't deliver webhook {}: {}", d.webhook, e);
+
+            let kind = match e.kind() {
+                isahc::error::ErrorKind::Timeout => "timeout",
+                _ => "connection_failed",
+            };
+            (None, Some(kind.to_string()))
+        }
+    }
+}
+
+/// Claims the due outbox entries, skipping the ones another server is claiming at the same time,
+/// by pushing back their next attempt by [`CLAIM_MINUTES`]
+fn claim_due_deliveries(db: &mut PgConnection) -> QueryResult<Vec<PendingDelivery>> {
+    use schema::webhook_outbox::dsl as o_dsl;
+    use schema::webhooks::dsl as w_dsl;
+
+    db.transaction::<_, diesel::result::Error, _>(|db| {
+        let now = Utc::now().naive_utc();
+
+        let ids = o_dsl::webhook_outbox
+            .filter(o_dsl::next_attempt_at.le(now))
+            .order_by(o_dsl::id.asc())
+            .limit(100)
+            .select(o_dsl::id)
+            .for_update()
+            .skip_locked()
+            .load::<i64>(db)?;
+
+        diesel::update(o_dsl::webhook_outbox.filter(o_dsl::id.eq_any(&ids)))
+            .set(o_dsl::next_attempt_at.eq(now + chrono::Duration::minutes(CLAIM_MINUTES)))
+            .execute(db)?;
+
+        o_dsl::webhook_outbox
+            .inner_join(w_dsl::webhooks)
+            .filter(o_dsl::id.eq_any(&ids))
+            .order_by(o_dsl::id.asc())
+            .select((
+                o_dsl::id,
+                o_dsl::event_type,
+                o_dsl::payload,
+                o_dsl::attempts,
+                w_dsl::id,
+                w_dsl::url,
+                w_dsl::secret,
+            ))
+            .load::<PendingDelivery>(db)
+    })
+}
+
+/// Records the outcome of a delivery, rescheduling it with an exponential backoff if it failed
+fn record_delivery(
+    db: &mut PgConnection,
+    delivery: PendingDelivery,
+    status_code: Option<u16>,
+    error: Option<String>,
+) -> QueryResult<()> {
+    use schema::webhook_deliveries::dsl as d_dsl;
+    use schema::webhook_outbox::dsl as o_dsl;
+
+    let delivered = status_code.map_or(false, |s| (200..300).contains(&s));
+    let attempt = delivery.attempts + 1;
+
+    diesel::insert_into(d_dsl::webhook_deliveries)
+        .values((
+            d_dsl::webhook.eq(delivery.webhook),
+            d_dsl::outbox_id.eq(delivery.id),
+            d_dsl::event_type.eq(delivery.event_type),
+            d_dsl::attempt.eq(attempt),
+            d_dsl::status_code.eq(status_code.map(i32::from)),
+            d_dsl::error.eq(error),
+        ))
+        .execute(db)?;
+
+    if delivered || attempt >= MAX_ATTEMPTS {
+        diesel::delete(o_dsl::webhook_outbox.find(delivery.id)).execute(db)?;
+    } else {
+        // 30s, 1min, 2min, … up to about 2h between the two last attempts
+        let backoff = chrono::Duration::seconds(30 << (attempt - 1));
+
+        diesel::update(o_dsl::webhook_outbox.find(delivery.id))
+            .set((
+                o_dsl::attempts.eq(attempt),
+                o_dsl::next_attempt_at.eq(Utc::now().naive_utc() + backoff),
+            ))
+            .execute(db)?;
+    }
+
+    Ok(())
+}
+
+/// Delivers due outbox entries, [`CONCURRENT_DELIVERIES`] at a time
+async fn send_due_webhooks(db: &DbConn, client: &HttpClient) -> QueryResult<()> {
+    let pending = db.run(claim_due_deliveries).await?;
+
+    stream::iter(pending)
+        .map(|delivery| async move {
+            let (status_code, error) = deliver(client, &delivery).await;
+            db.run(move |db| record_delivery(db, delivery, status_code, error))
+                .await
+        })
+        .buffer_unordered(CONCURRENT_DELIVERIES)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect()
+}
+
+/// Checks the outbox every 10 seconds
+pub fn scheduler() -> AdHoc {
+    AdHoc::on_liftoff("webhook scheduler", |rocket| {
+        Box::pin(async move {
+            let conn = DbConn::get_one(rocket)
+                .await
+                .expect("no database available for sending webhooks");
+
+            let client = HttpClient::new().expect("couldn't create the webhook HTTP client");
+
+            rocket::tokio::spawn(async move {
+                let mut interval = rocket::tokio::time::interval(Duration::from_secs(10));
+
+                loop {
+                    interval.tick().await;
+
+                    if let Err(e) = send_due_webhooks(&conn, &client).await {
+                        eprintln!("couldn't send webhooks: {}", e);
+                    }
+                }
+            });
+        })
+    })
+}